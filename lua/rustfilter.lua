@@ -7,14 +7,44 @@ local filterer = package.loadlib(d .. "../rust/target/release/libfilter." .. son
 
 -- create matcher object
 local matcher = filterer.threaded_matcher()
-local timer = nil
-function handler(window, lines, query, callback)
-  local err = matcher:query(query, window.launched_from_name, 10, lines)
+-- record queries for building a `sylph --test-file` corpus
+if vim.g.sylph_record_file ~= nil then
+  local err = matcher:set_record_file(vim.g.sylph_record_file)
   if err ~= nil then
     sylph.print_err(err)
+  end
+end
+local timer = nil
+-- lists shorter than this are matched synchronously, skipping the worker thread
+local sync_threshold = 200
+
+local function to_matched_lines(lines, res)
+  local matched_lines = {}
+  for _, x in ipairs(res) do
+    local l = lines[x.index+1]
+    l.frequency_score = x.frequency_score
+    l.open_score = x.open_score
+    l.context_score = x.context_score
+    l.query_score = x.query_score
+    l.raw_query_score = x.raw_query_score
+    matched_lines[#matched_lines+1] = l
+  end
+  return matched_lines
+end
+
+function handler(window, lines, query, callback)
+  if #lines < sync_threshold then
+    local res, err = matcher:match_sync(query, window.launched_from_name, 10, lines)
+    if err ~= nil then
+      sylph.print_err(err)
+      return
+    end
+    callback(to_matched_lines(lines, res))
     return
   end
 
+  matcher:query(query, window.launched_from_name, 10, lines)
+
   -- poll matcher to see if it has completed
   local timer_callback
   timer_callback = function()
@@ -30,15 +60,7 @@ function handler(window, lines, query, callback)
     if res == nil then
       vim.defer_fn(timer_callback, 5)
     else
-      local matched_lines = {}
-      for _, x in ipairs(res) do
-        local l = lines[x.index+1]
-        l.frequency_score = x.frequency_score
-        l.context_score = x.context_score
-        l.query_score = x.query_score
-        matched_lines[#matched_lines+1] = l
-      end
-      callback(matched_lines)
+      callback(to_matched_lines(lines, res))
     end
   end
   timer_callback()
@@ -46,6 +68,10 @@ end
 
 function on_selected(line)
   matcher:update(line.location.path)
+  local err = matcher:record_selection(line)
+  if err ~= nil then
+    sylph.print_err(err)
+  end
 end
 
 sylph:register_filter("rust", {handler = handler, on_selected = on_selected})
@@ -1,8 +1,10 @@
-use filter::{Line, Matcher, Progress};
+use filter::{Line, MatchMode, Matcher, MatcherConfig, OwnedLine, Progress};
 use serde::Deserialize;
+use std::cell::RefCell;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
-use criterion::{BenchmarkId, criterion_group, criterion_main, Criterion};
+use std::time::{Duration, Instant};
+use criterion::{BatchSize, BenchmarkId, criterion_group, criterion_main, Criterion};
 
 #[derive(Deserialize)]
 struct Location {
@@ -35,8 +37,9 @@ struct Query {
 fn incremental(batch_size: usize, num_results: u64, items: &Vec<Query>) {
     let matcher = Matcher::new().unwrap();
     for json in items {
-        let mut inc_matcher =
-            matcher.incremental_match(&json.query, &json.launched_from, num_results, &json.lines);
+        let mut inc_matcher = matcher
+            .incremental_match(&json.query, &json.launched_from, num_results, &json.lines)
+            .unwrap();
         let mut progress = inc_matcher.process(batch_size).unwrap();
         while progress == Progress::Working {
             progress = inc_matcher.process(batch_size).unwrap();
@@ -44,11 +47,10 @@ fn incremental(batch_size: usize, num_results: u64, items: &Vec<Query>) {
     }
 }
 
-fn incremental_bench(c: &mut Criterion) {
+fn read_corpus() -> Vec<Query> {
     let file = File::open("tests/sylph.log").unwrap();
     let reader = BufReader::new(file);
     let mut items = Vec::new();
-    let mut total = 0;
     for line in reader.lines() {
         let l = line.unwrap();
         let sl = if &l[l.len() - 1..] == "\n" {
@@ -58,16 +60,277 @@ fn incremental_bench(c: &mut Criterion) {
         };
         match serde_json::from_str::<Query>(sl) {
             Err(err) => eprintln!("{:?}", err),
-            Ok(json) => {
-                total += json.lines.len();
-                items.push(json);
-            }
+            Ok(json) => items.push(json),
         }
     }
+    items
+}
+
+fn incremental_bench(c: &mut Criterion) {
+    let items = read_corpus();
+    let total = items.iter().map(|json| json.lines.len()).sum::<usize>();
     c.bench_with_input(BenchmarkId::new("incremental", format!("batch 100 results 10 lines {}", total)), &items, |b, itms| {
         b.iter(|| incremental(100, 5, itms));
     });
 }
 
-criterion_group!(benches, incremental_bench);
+/// The value below which `percent` of `samples` fall. `samples` must be sorted.
+fn percentile(samples: &[Duration], percent: usize) -> Duration {
+    samples[(samples.len() * percent / 100).min(samples.len() - 1)]
+}
+
+/// Criterion samples per latency benchmark.
+const LATENCY_SAMPLE_SIZE: usize = 100;
+
+/// Times individual `best_matches` calls, the delay a user sees after each keystroke, and prints
+/// their distribution. The corpus queries are empty, so each corpus entry is queried with the
+/// first `query_len` characters of one of its own file names, as if the user were typing it.
+fn latency_bench(c: &mut Criterion) {
+    let items = read_corpus();
+    let matcher = Matcher::new().unwrap();
+    let queries = items
+        .iter()
+        .flat_map(|json| {
+            json.lines.iter().map(move |l| {
+                let path = l.path();
+                let basename = path.rsplit_once('/').map_or(path, |(_, b)| b);
+                (json, basename.chars().collect::<Vec<_>>())
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut group = c.benchmark_group("keystroke latency");
+    group.sample_size(LATENCY_SAMPLE_SIZE);
+    for &query_len in &[1, 3, 6] {
+        let queries = queries
+            .iter()
+            .filter(|(_, chars)| chars.len() >= query_len)
+            .map(|(json, chars)| (*json, chars[..query_len].iter().collect::<String>()))
+            .collect::<Vec<_>>();
+        // One batch of call times per call of the routine
+        let batches = RefCell::new(Vec::new());
+        group.bench_with_input(
+            BenchmarkId::new("query length", query_len),
+            &queries,
+            |b, queries| {
+                b.iter_custom(|iters| {
+                    let mut batch = Vec::with_capacity(iters as usize);
+                    let mut total = Duration::from_secs(0);
+                    for i in 0..iters as usize {
+                        let (json, query) = &queries[i % queries.len()];
+                        let start = Instant::now();
+                        matcher
+                            .best_matches(query, &json.launched_from, 10, &json.lines)
+                            .unwrap();
+                        let elapsed = start.elapsed();
+                        batch.push(elapsed);
+                        total += elapsed;
+                    }
+                    batches.borrow_mut().push(batch);
+                    total
+                })
+            },
+        );
+        // Criterion warms up by calling the routine until its warm up time passes, then measures
+        // with exactly one call per sample, so only the last calls are measurements
+        let batches = batches.into_inner();
+        let mut samples = batches[batches.len().saturating_sub(LATENCY_SAMPLE_SIZE)..].concat();
+        if samples.is_empty() {
+            // Filtered out on the command line
+            continue;
+        }
+        samples.sort();
+        println!(
+            "query length {}: p50 {:?} p95 {:?} p99 {:?} over {} calls",
+            query_len,
+            percentile(&samples, 50),
+            percentile(&samples, 95),
+            percentile(&samples, 99),
+            samples.len()
+        );
+    }
+    group.finish();
+}
+
+fn synthetic_paths(n: usize) -> Vec<String> {
+    (0..n)
+        .map(|i| format!("src/module{}/sub{}/file{}.rs", i % 17, i % 5, i))
+        .collect()
+}
+
+fn pattern_bench(c: &mut Criterion) {
+    let paths = synthetic_paths(10000);
+    let matcher = Matcher::with_config(MatcherConfig {
+        match_mode: MatchMode::Glob,
+        ..MatcherConfig::default()
+    })
+    .unwrap();
+    let query = "src/**/sub3/*.rs";
+    let mut group = c.benchmark_group("glob pattern 10000 lines");
+    group.bench_function("compiled per line", |b| {
+        b.iter(|| {
+            paths
+                .iter()
+                .enumerate()
+                .filter_map(|(i, p)| matcher.score(query, "", i, p, p))
+                .count()
+        })
+    });
+    group.bench_function("compiled once", |b| {
+        b.iter(|| {
+            let pattern = matcher.pattern(query).unwrap();
+            paths
+                .iter()
+                .enumerate()
+                .filter_map(|(i, p)| matcher.score_pattern(&pattern, "", i, p, p))
+                .count()
+        })
+    });
+    group.finish();
+}
+
+fn poll_done_bench(c: &mut Criterion) {
+    let paths = synthetic_paths(10000);
+    let lines = paths
+        .iter()
+        .map(|p| OwnedLine {
+            path: p.clone(),
+            line: p.clone(),
+        })
+        .collect::<Vec<_>>();
+    let matcher = Matcher::new().unwrap();
+    let mut inc_matcher = matcher.incremental_match("file", "", 100, &lines).unwrap();
+    while inc_matcher.process(1000).unwrap() == Progress::Working {}
+    c.bench_function("poll done 100 results", |b| {
+        b.iter(|| inc_matcher.process(1000).unwrap())
+    });
+}
+
+fn empty_query_bench(c: &mut Criterion) {
+    let paths = synthetic_paths(100000);
+    let lines = paths
+        .iter()
+        .map(|p| OwnedLine {
+            path: p.clone(),
+            line: p.clone(),
+        })
+        .collect::<Vec<_>>();
+    let mut matcher = Matcher::new().unwrap();
+    for p in paths.iter().step_by(1000) {
+        matcher.update(p);
+    }
+    let mut group = c.benchmark_group("empty query 100000 lines");
+    group.bench_function("best_matches", |b| {
+        b.iter(|| matcher.best_matches("", "", 10, &lines).unwrap())
+    });
+    // For comparison, a query that matches every line
+    group.bench_function("best_matches one character", |b| {
+        b.iter(|| matcher.best_matches("f", "", 10, &lines).unwrap())
+    });
+    group.finish();
+}
+
+fn exact_query_bench(c: &mut Criterion) {
+    let lines = (0..100000)
+        .map(|i| {
+            let path = if i % 10 == 0 {
+                format!("src/module{}/config{}.rs", i % 17, i)
+            } else {
+                format!("src/module{}/sub{}/file{}.rs", i % 17, i % 5, i)
+            };
+            OwnedLine {
+                path: path.clone(),
+                line: path,
+            }
+        })
+        .collect::<Vec<_>>();
+    let matcher = Matcher::new().unwrap();
+    let mut group = c.benchmark_group("config 100000 lines");
+    group.bench_function("fuzzy", |b| {
+        b.iter(|| matcher.best_matches("config", "", 10, &lines).unwrap())
+    });
+    group.bench_function("exact", |b| {
+        b.iter(|| matcher.best_matches("'config", "", 10, &lines).unwrap())
+    });
+    group.finish();
+}
+
+/// A one character query matches nearly every line, which is the most work for the results heap.
+/// When boosts lift the results far above any fuzzy score, lines are skipped without being
+/// fuzzy matched once the results are full.
+fn all_lines_match_bench(c: &mut Criterion) {
+    let paths = synthetic_paths(100000);
+    let lines = paths
+        .iter()
+        .map(|p| OwnedLine {
+            path: p.clone(),
+            line: p.clone(),
+        })
+        .collect::<Vec<_>>();
+    let mut group = c.benchmark_group("query e 100000 lines");
+    let matcher = Matcher::new().unwrap();
+    group.bench_function("default", |b| {
+        b.iter(|| matcher.best_matches("e", "", 10, &lines).unwrap())
+    });
+    let mut boosted = Matcher::with_config(MatcherConfig {
+        open_boost: 1000.,
+        ..MatcherConfig::default()
+    })
+    .unwrap();
+    boosted.set_open_set(paths.iter().step_by(1000).cloned().collect());
+    group.bench_function("open files boosted", |b| {
+        b.iter(|| boosted.best_matches("e", "", 10, &lines).unwrap())
+    });
+    group.finish();
+}
+
+/// The worker keeps one `Matcher` and only builds a new `IncrementalMatcher` per query, so the
+/// skim matcher's scratch buffers are already grown by the time later queries run. A fresh
+/// matcher's first query shows what that saves.
+fn warm_matcher_bench(c: &mut Criterion) {
+    let lines = synthetic_paths(1000)
+        .into_iter()
+        .map(|p| OwnedLine {
+            path: p.clone(),
+            line: p,
+        })
+        .collect::<Vec<_>>();
+    let mut matcher = Matcher::new().unwrap();
+    let mut group = c.benchmark_group("query 1000 lines");
+    group.bench_function("first query", |b| {
+        b.iter_batched(
+            || Matcher::new().unwrap(),
+            |matcher| matcher.best_matches("file", "", 10, &lines).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("steady state", |b| {
+        b.iter(|| {
+            matcher
+                .incremental_match("file", "", 10, &lines)
+                .unwrap()
+                .process(lines.len())
+                .unwrap()
+        })
+    });
+    // Changing settings skim doesn't use keeps its buffers
+    group.bench_function("after set_config", |b| {
+        b.iter(|| {
+            matcher.set_config(MatcherConfig::default());
+            matcher.best_matches("file", "", 10, &lines).unwrap()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    incremental_bench,
+    pattern_bench,
+    poll_done_bench,
+    empty_query_bench,
+    exact_query_bench,
+    latency_bench,
+    warm_matcher_bench,
+    all_lines_match_bench
+);
 criterion_main!(benches);
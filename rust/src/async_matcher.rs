@@ -0,0 +1,67 @@
+//! `Matcher` for async Rust code, enabled by the `async` feature. Scoring runs on tokio's
+//! blocking thread pool so it doesn't stall the runtime.
+
+use anyhow::{anyhow, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+use super::matcher::*;
+
+pub struct AsyncMatcher {
+    matcher: Arc<RwLock<Matcher>>,
+}
+
+/// Sets its flag when dropped, cancelling the scoring of a query whose future was dropped.
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+impl AsyncMatcher {
+    pub fn new(matcher: Matcher) -> Self {
+        AsyncMatcher {
+            matcher: Arc::new(RwLock::new(matcher)),
+        }
+    }
+
+    /// `Matcher::best_matches` on the blocking thread pool. Dropping the future stops the
+    /// scoring within `CANCEL_CHECK_INTERVAL` lines. Must be called from within a tokio runtime.
+    pub async fn query(
+        &self,
+        query: String,
+        context: String,
+        num_results: u64,
+        lines: Vec<OwnedLine>,
+    ) -> Result<Vec<Match>> {
+        let cancel = CancelOnDrop(Arc::new(AtomicBool::new(false)));
+        let flag = cancel.0.clone();
+        let matcher = self.matcher.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            matcher
+                .read()
+                .map_err(|_| anyhow!("Matcher lock was poisoned"))?
+                .best_matches_cancellable(&query, &context, num_results, &lines, &flag)
+        })
+        .await?;
+        // Only a dropped future sets the flag, and then nothing is waiting for the result
+        result.map(Option::unwrap_or_default)
+    }
+
+    /// `Matcher::update`. Resolves once running queries have finished and the update is applied,
+    /// waiting for them on the blocking thread pool rather than stalling the runtime.
+    pub async fn update(&self, path: &str) -> Result<()> {
+        let matcher = self.matcher.clone();
+        let path = path.to_string();
+        tokio::task::spawn_blocking(move || {
+            matcher
+                .write()
+                .map_err(|_| anyhow!("Matcher lock was poisoned"))?
+                .update(&path);
+            Ok(())
+        })
+        .await?
+    }
+}
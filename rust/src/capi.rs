@@ -0,0 +1,147 @@
+//! C interface to `Matcher`. The header is generated into `target/bindings.h` by `build.rs`.
+//!
+//! The shared library is also the Lua module, so loading it needs the Lua C API, e.g. LuaJIT,
+//! linked into the host. Hosts without Lua should link the static library with
+//! `-Wl,--gc-sections` instead, which drops the Lua module and its Lua references.
+//!
+//! Strings passed in are borrowed for the duration of the call and never freed by the library.
+//! Matchers and match arrays returned by the library must be freed with `sylph_matcher_free` and
+//! `sylph_matches_free` respectively.
+
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::ptr;
+
+use super::matcher::*;
+
+/// A candidate line. Both strings must be nul terminated UTF-8.
+#[repr(C)]
+pub struct SylphLine {
+    pub path: *const c_char,
+    pub line: *const c_char,
+}
+
+struct BorrowedLine<'a> {
+    path: &'a str,
+    line: &'a str,
+}
+
+impl<'a> Line for BorrowedLine<'a> {
+    fn path(&self) -> &str {
+        self.path
+    }
+
+    fn line(&self) -> &str {
+        self.line
+    }
+}
+
+unsafe fn to_str<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        None
+    } else {
+        CStr::from_ptr(s).to_str().ok()
+    }
+}
+
+/// Create a matcher with the default config. Returns NULL on failure.
+#[no_mangle]
+pub extern "C" fn sylph_matcher_new() -> *mut Matcher {
+    match Matcher::new() {
+        Ok(matcher) => Box::into_raw(Box::new(matcher)),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Free a matcher created by `sylph_matcher_new`. Passing NULL is a no-op.
+///
+/// # Safety
+/// `matcher` must be NULL or a pointer returned by `sylph_matcher_new` that has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn sylph_matcher_free(matcher: *mut Matcher) {
+    if !matcher.is_null() {
+        drop(Box::from_raw(matcher));
+    }
+}
+
+/// Record that `path` was selected by the user.
+///
+/// # Safety
+/// `matcher` must be a live matcher and `path` a nul terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn sylph_matcher_update(matcher: *mut Matcher, path: *const c_char) {
+    if let (Some(matcher), Some(path)) = (matcher.as_mut(), to_str(path)) {
+        matcher.update(path);
+    }
+}
+
+/// Find the best `num_results` matches for `query` among `num_lines` entries of `lines`. The
+/// number of matches is written to `out_len` and the matches are returned best first. Each
+/// match's `index` is an index into `lines`.
+///
+/// Returns NULL and sets `out_len` to 0 if any argument is NULL or not valid UTF-8, or if
+/// matching fails. A non-NULL result must be freed with `sylph_matches_free`.
+///
+/// # Safety
+/// `matcher` must be a live matcher, `query` and `context` nul terminated strings, `lines` an
+/// array of at least `num_lines` entries, and `out_len` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn sylph_best_matches(
+    matcher: *const Matcher,
+    query: *const c_char,
+    context: *const c_char,
+    num_results: u64,
+    lines: *const SylphLine,
+    num_lines: usize,
+    out_len: *mut usize,
+) -> *mut Match {
+    if out_len.is_null() {
+        return ptr::null_mut();
+    }
+    *out_len = 0;
+    let (matcher, query, context) = match (matcher.as_ref(), to_str(query), to_str(context)) {
+        (Some(m), Some(q), Some(c)) => (m, q, c),
+        _ => return ptr::null_mut(),
+    };
+    if lines.is_null() && num_lines > 0 {
+        return ptr::null_mut();
+    }
+    let c_lines = if num_lines > 0 {
+        std::slice::from_raw_parts(lines, num_lines)
+    } else {
+        &[]
+    };
+    let borrowed = c_lines
+        .iter()
+        .map(|l| {
+            Some(BorrowedLine {
+                path: to_str(l.path)?,
+                line: to_str(l.line)?,
+            })
+        })
+        .collect::<Option<Vec<_>>>();
+    let borrowed = match borrowed {
+        Some(b) => b,
+        None => return ptr::null_mut(),
+    };
+    match matcher.best_matches(query, context, num_results, &borrowed) {
+        Ok(mtchs) => {
+            let mtchs = mtchs.into_boxed_slice();
+            *out_len = mtchs.len();
+            Box::into_raw(mtchs) as *mut Match
+        }
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Free matches returned by `sylph_best_matches`. `len` must be the length it returned. Passing
+/// NULL is a no-op.
+///
+/// # Safety
+/// `matches` must be NULL or a pointer returned by `sylph_best_matches` that has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn sylph_matches_free(matches: *mut Match, len: usize) {
+    if !matches.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(matches, len)));
+    }
+}
@@ -1,10 +1,18 @@
 use anyhow::{anyhow, Result};
-use crossbeam_channel::{unbounded, Receiver, Sender, TryRecvError};
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender, TryRecvError};
+use globset::GlobSet;
 use mlua::prelude::*;
-use mlua::{UserData, Value};
+use mlua::{LuaSerdeExt, UserData, Value};
+use std::collections::{HashSet, VecDeque};
+use std::path::Path;
 use std::thread;
+use std::time::Duration;
 
 use super::matcher::*;
+use super::record::*;
+
+/// Default for `ThreadedMatcher::set_max_queued_results`.
+pub const DEFAULT_MAX_QUEUED_RESULTS: usize = 8;
 
 #[derive(Debug)]
 enum Command {
@@ -15,7 +23,266 @@ enum Command {
         lines: Vec<OwnedLine>,
         id: usize,
     },
+    /// Like `Query`, but scored in a single `best_matches` call rather than in chunks that check
+    /// for new commands in between. Cheaper for lists too short to be worth interrupting.
+    QueryAll {
+        query: String,
+        context: String,
+        num_results: usize,
+        lines: Vec<OwnedLine>,
+        id: usize,
+    },
+    /// Lines to match ahead of the next `Query` or `QueryAll`'s own lines.
+    Feed(Vec<OwnedLine>),
+    /// Rerun query `query_id` with `additional` more results.
+    QueryMore {
+        query_id: usize,
+        additional: usize,
+        id: usize,
+    },
     Update(String),
+    Demote(String),
+    SetConfig(MatcherConfig),
+    SetOpenSet(HashSet<String>),
+    SetCurrentFile(Option<String>),
+    LoadFrequency(Vec<FrequencyEntry>),
+    SetExcludes(GlobSet),
+    /// See `ThreadedMatcher::set_max_queued_results`.
+    SetMaxQueuedResults(usize),
+    /// Reply with the `IncrementalMatcher::stats` of the running query.
+    Progress(Sender<(usize, usize)>),
+    /// Stop the running query without sending results and drop the lines kept for `QueryMore`.
+    Reset,
+}
+
+#[cfg(feature = "tracing")]
+impl Command {
+    fn name(&self) -> &'static str {
+        match self {
+            Command::Query { .. } => "Query",
+            Command::QueryAll { .. } => "QueryAll",
+            Command::Feed(_) => "Feed",
+            Command::QueryMore { .. } => "QueryMore",
+            Command::Update(_) => "Update",
+            Command::Demote(_) => "Demote",
+            Command::SetConfig(_) => "SetConfig",
+            Command::SetOpenSet(_) => "SetOpenSet",
+            Command::SetCurrentFile(_) => "SetCurrentFile",
+            Command::LoadFrequency(_) => "LoadFrequency",
+            Command::SetExcludes(_) => "SetExcludes",
+            Command::SetMaxQueuedResults(_) => "SetMaxQueuedResults",
+            Command::Progress(_) => "Progress",
+            Command::Reset => "Reset",
+        }
+    }
+}
+
+/// The worker's most recent query, kept so `QueryMore` can rerun it without the lines being sent
+/// again.
+struct LastQuery {
+    id: usize,
+    query: String,
+    context: String,
+    num_results: usize,
+    lines: Vec<OwnedLine>,
+}
+
+/// State of the thread running a `ThreadedMatcher`'s queries.
+struct Worker {
+    matcher: Matcher,
+    command_recv: Receiver<Command>,
+    result_send: Sender<(usize, Result<Vec<Match>>)>,
+    /// The other end of `result_send`, to drop the oldest unread results when too many are
+    /// queued.
+    unread_results: Receiver<(usize, Result<Vec<Match>>)>,
+    max_queued_results: usize,
+    last: Option<LastQuery>,
+    /// Lines fed since the last query started, for the next one.
+    fed: Vec<OwnedLine>,
+    /// Commands that arrived while a query was running, to handle once it stops.
+    pending: VecDeque<Command>,
+    /// Lines processed and total lines of the current or most recent query.
+    stats: (usize, usize),
+}
+
+impl Worker {
+    fn run(mut self) {
+        loop {
+            let command = match self.pending.pop_front() {
+                Some(command) => command,
+                None => match self.command_recv.recv() {
+                    Ok(command) => command,
+                    // The ThreadedMatcher was dropped
+                    Err(_) => return,
+                },
+            };
+            self.handle(command);
+        }
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "command", skip_all, fields(command = command.name()))
+    )]
+    fn handle(&mut self, command: Command) {
+        match command {
+            Command::Query {
+                query,
+                context,
+                num_results,
+                lines,
+                id,
+            } => {
+                let lines = self.with_fed(lines);
+                self.last = self.run_query(LastQuery {
+                    id,
+                    query,
+                    context,
+                    num_results,
+                    lines,
+                });
+            }
+            Command::QueryAll {
+                query,
+                context,
+                num_results,
+                lines,
+                id,
+            } => {
+                let lines = self.with_fed(lines);
+                self.stats = (lines.len(), lines.len());
+                let results =
+                    self.matcher
+                        .best_matches(&query, &context, num_results as u64, &lines);
+                let failed = results.is_err();
+                self.send_result(id, results);
+                self.last = (!failed).then_some(LastQuery {
+                    id,
+                    query,
+                    context,
+                    num_results,
+                    lines,
+                });
+            }
+            Command::QueryMore {
+                query_id,
+                additional,
+                id,
+            } => match self.last.take() {
+                Some(prev) if prev.id == query_id => {
+                    self.last = self.run_query(LastQuery {
+                        id,
+                        num_results: prev.num_results.saturating_add(additional),
+                        ..prev
+                    });
+                }
+                prev => {
+                    self.last = prev;
+                    self.send_result(
+                        id,
+                        Err(anyhow!("Query {} is not the most recent query", query_id)),
+                    );
+                }
+            },
+            Command::Feed(lines) => self.fed.extend(lines),
+            Command::Update(path) => self.matcher.update(&path),
+            Command::Demote(path) => self.matcher.demote(&path),
+            Command::SetConfig(config) => self.matcher.set_config(config),
+            Command::SetOpenSet(paths) => self.matcher.set_open_set(paths),
+            Command::SetCurrentFile(path) => self.matcher.set_current_file(path),
+            Command::LoadFrequency(entries) => self.matcher.load_frequency(&entries),
+            Command::SetExcludes(excludes) => self.matcher.set_exclude_set(excludes),
+            Command::SetMaxQueuedResults(max) => self.max_queued_results = max,
+            Command::Progress(reply) => {
+                // The caller may have given up waiting
+                let _ = reply.send(self.stats);
+            }
+            Command::Reset => {
+                self.last = None;
+                self.fed.clear();
+                self.stats = (0, 0);
+            }
+        }
+    }
+
+    /// Send a result, first dropping the oldest unread ones if `max_queued_results` are already
+    /// queued. Those are for older commands, which `get_result` would skip anyway.
+    fn send_result(&self, id: usize, result: Result<Vec<Match>>) {
+        while self.result_send.len() >= self.max_queued_results.max(1) {
+            // The caller may have read them in the meantime
+            if self.unread_results.try_recv().is_err() {
+                break;
+            }
+        }
+        self.result_send.send((id, result)).unwrap();
+    }
+
+    /// A query's `lines`, preceded by any fed before it.
+    fn with_fed(&mut self, lines: Vec<OwnedLine>) -> Vec<OwnedLine> {
+        if self.fed.is_empty() {
+            return lines;
+        }
+        let mut fed = std::mem::take(&mut self.fed);
+        fed.extend(lines);
+        fed
+    }
+
+    /// Run `q` until it finishes or a new query replaces it, sending the results if it finished.
+    /// `Progress` commands are answered without stopping. Other commands change the matcher,
+    /// which the running query borrows, so they are deferred until it is done. Returns the query
+    /// to keep for `QueryMore`, or `None` if it failed.
+    fn run_query(&mut self, q: LastQuery) -> Option<LastQuery> {
+        let LastQuery {
+            id,
+            query,
+            context,
+            num_results,
+            lines,
+        } = q;
+        self.stats = (0, lines.len());
+        let r: Result<Vec<OwnedLine>> = try {
+            let mut inc_matcher =
+                self.matcher
+                    .incremental_match(&query, &context, num_results as u64, lines)?;
+            let mut progress = Progress::Working;
+            while progress == Progress::Working {
+                match self.command_recv.try_recv() {
+                    Ok(Command::Progress(reply)) => {
+                        let _ = reply.send(inc_matcher.stats());
+                    }
+                    Ok(command @ Command::Query { .. })
+                    | Ok(command @ Command::QueryAll { .. })
+                    | Ok(command @ Command::QueryMore { .. })
+                    | Ok(command @ Command::Reset) => {
+                        self.pending.push_back(command);
+                        break;
+                    }
+                    Ok(command) => self.pending.push_back(command),
+                    Err(_) => {
+                        progress = inc_matcher.process_adaptive(Duration::from_millis(2))?;
+                    }
+                }
+            }
+            self.stats = inc_matcher.stats();
+            if let Progress::Done(results) = progress {
+                self.send_result(id, Ok(results));
+            }
+            inc_matcher.into_lines()
+        };
+        match r {
+            Ok(lines) => Some(LastQuery {
+                id,
+                query,
+                context,
+                num_results,
+                lines,
+            }),
+            Err(err) => {
+                self.send_result(id, Err(err));
+                None
+            }
+        }
+    }
 }
 
 /// Object holding a matcher running in a separate thread
@@ -23,14 +290,31 @@ pub struct ThreadedMatcher {
     command_ch: Sender<Command>,
     result_ch: Receiver<(usize, Result<Vec<Match>>)>,
     command_num: usize,
+    /// The config most recently sent to the worker.
+    config: MatcherConfig,
+    /// Used by `match_sync`. Kept in step with the worker's matcher by sending it the same
+    /// updates, so both share selection history and config. `None` if it failed to initialize.
+    sync_matcher: Option<Matcher>,
+    /// Why the worker failed to start, if its error was read off `result_ch` while discarding
+    /// stale results.
+    init_error: Option<String>,
+    /// Where queries are recorded, see `set_record_file`.
+    recorder: Option<Recorder>,
+}
+
+impl Default for ThreadedMatcher {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ThreadedMatcher {
-    fn new() -> Self {
+    pub fn new() -> Self {
         let (command_send, command_recv) = unbounded();
         let (result_send, result_recv) = unbounded::<(usize, Result<Vec<Match>>)>();
+        let unread_results = result_recv.clone();
         thread::spawn(move || {
-            let mut matcher = match Matcher::new() {
+            let matcher = match Matcher::new() {
                 Ok(matcher) => matcher,
                 Err(err) => {
                     eprintln!("{}", err);
@@ -38,63 +322,181 @@ impl ThreadedMatcher {
                     return;
                 }
             };
-            loop {
-                match command_recv.recv().unwrap() {
-                    Command::Query {
-                        query,
-                        context,
-                        num_results,
-                        lines,
-                        id,
-                    } => {
-                        let r: Result<()> = try {
-                            let mut inc_matcher = matcher.incremental_match(
-                                &query,
-                                &context,
-                                num_results as u64,
-                                lines.as_slice(),
-                            );
-                            let mut progress = Progress::Working;
-                            while command_recv.len() == 0 && progress == Progress::Working {
-                                progress = inc_matcher.process(100)?;
-                            }
-                            if let Progress::Done(results) = progress {
-                                result_send.send((id, Ok(results))).unwrap();
-                            }
-                        };
-                        if let Err(err) = r {
-                            result_send.send((id, Err(err))).unwrap();
-                        }
-                    }
-                    Command::Update(path) => matcher.update(&path),
-                }
+            matcher.warmup();
+            Worker {
+                matcher,
+                command_recv,
+                result_send,
+                unread_results,
+                max_queued_results: DEFAULT_MAX_QUEUED_RESULTS,
+                last: None,
+                fed: Vec::new(),
+                pending: VecDeque::new(),
+                stats: (0, 0),
             }
+            .run();
         });
         ThreadedMatcher {
             command_ch: command_send,
             result_ch: result_recv,
             command_num: 0,
+            config: MatcherConfig::default(),
+            sync_matcher: Matcher::new().ok(),
+            init_error: None,
+            recorder: None,
         }
     }
 
-    fn query<L: Line>(&mut self, query: &str, context: &str, num_results: usize, lines: &[L]) {
-        self.command_num += 1;
-        self.command_ch.send(Command::Query {
-            query: query.to_string(),
-            context: context.to_string(),
+    /// Start matching `lines` against `query`, cancelling any running query. Returns the query's
+    /// id, for use with `query_more`.
+    pub fn query<L: Line>(
+        &mut self,
+        query: &str,
+        context: &str,
+        num_results: usize,
+        lines: &[L],
+    ) -> usize {
+        self.query_owned(
+            query,
+            context,
             num_results,
-            lines: lines
+            lines
                 .iter()
                 .map(|l| OwnedLine {
                     path: l.path().to_string(),
                     line: l.line().to_string(),
                 })
                 .collect(),
-            id: self.command_num,
-        });
+        )
+    }
+
+    /// Like `query`, but takes ownership of `lines` instead of copying them.
+    pub fn query_owned(
+        &mut self,
+        query: &str,
+        context: &str,
+        num_results: usize,
+        lines: Vec<OwnedLine>,
+    ) -> usize {
+        self.send_query(query, context, num_results, lines, false)
+    }
+
+    /// Like `query_owned`, but the worker scores all of `lines` at once instead of in chunks
+    /// between which it checks for newer commands. For short lists that saves the overhead of
+    /// chunking, while the result still comes back through `get_result`.
+    pub fn match_now(
+        &mut self,
+        query: &str,
+        context: &str,
+        num_results: usize,
+        lines: Vec<OwnedLine>,
+    ) -> usize {
+        self.send_query(query, context, num_results, lines, true)
+    }
+
+    fn send_query(
+        &mut self,
+        query: &str,
+        context: &str,
+        num_results: usize,
+        lines: Vec<OwnedLine>,
+        all_at_once: bool,
+    ) -> usize {
+        if let Some(recorder) = &self.recorder {
+            recorder.set_query(query, context, &lines);
+        }
+        self.discard_stale_results();
+        self.command_num += 1;
+        let (query, context, id) = (query.to_string(), context.to_string(), self.command_num);
+        self.command_ch
+            .send(if all_at_once {
+                Command::QueryAll {
+                    query,
+                    context,
+                    num_results,
+                    lines,
+                    id,
+                }
+            } else {
+                Command::Query {
+                    query,
+                    context,
+                    num_results,
+                    lines,
+                    id,
+                }
+            })
+            .unwrap();
+        self.command_num
+    }
+
+    /// Send `lines` to the worker ahead of the next query, e.g. while a provider is still
+    /// producing candidates and the user hasn't typed anything. The next `query` or `match_now`
+    /// matches them before its own lines, so result indices count the fed lines first. `reset`
+    /// drops them.
+    pub fn feed(&mut self, lines: Vec<OwnedLine>) {
+        self.command_ch.send(Command::Feed(lines)).unwrap();
+    }
+
+    /// Rerun query `query_id` asking for `additional` more results, reusing the lines it was
+    /// given. Only the most recent query can be extended. Returns the new query's id.
+    ///
+    /// The results heap only has room for the original `num_results`, so lines that didn't make
+    /// the cut are not kept. The lines are matched again from scratch with room for the extra
+    /// results, which takes as long as the original query.
+    pub fn query_more(&mut self, query_id: usize, additional: usize) -> usize {
+        self.discard_stale_results();
+        self.command_num += 1;
+        self.command_ch
+            .send(Command::QueryMore {
+                query_id,
+                additional,
+                id: self.command_num,
+            })
+            .unwrap();
+        self.command_num
+    }
+
+    /// Abandon the current query: stop it if it is still running, discard its results and free
+    /// the lines the worker keeps for `query_more`. Without this they are only freed by the next
+    /// query.
+    pub fn reset(&mut self) {
+        self.discard_stale_results();
+        // Results already in flight belong to an older command and will be skipped
+        self.command_num += 1;
+        self.command_ch.send(Command::Reset).unwrap();
+    }
+
+    /// Drop results the caller never read. A new query supersedes all of them, and without this
+    /// a caller that stops polling would have them pile up in the channel.
+    fn discard_stale_results(&mut self) {
+        while let Ok((id, result)) = self.result_ch.try_recv() {
+            if let (0, Err(err)) = (id, result) {
+                self.init_error = Some(err.to_string());
+            }
+        }
+    }
+
+    /// Number of results sent by the worker that `get_result` hasn't read yet, including ones
+    /// for superseded queries.
+    pub fn queued_results(&self) -> usize {
+        self.result_ch.len()
+    }
+
+    /// Cap the number of unread results, `DEFAULT_MAX_QUEUED_RESULTS` by default. New queries
+    /// drop unread results when they are sent, but commands sent in quick succession can still
+    /// queue one result each after the last of them. Past the cap the worker drops the oldest.
+    /// At least one result is always kept.
+    pub fn set_max_queued_results(&mut self, max: usize) {
+        self.command_ch
+            .send(Command::SetMaxQueuedResults(max))
+            .unwrap();
     }
 
-    fn get_result(&self) -> Option<Result<Vec<Match>>> {
+    pub fn get_result(&self) -> Option<Result<Vec<Match>>> {
+        if let Some(err) = &self.init_error {
+            return Some(Err(anyhow!("{}", err)));
+        }
         match self.result_ch.try_recv() {
             Ok((id, result)) => match id {
                 0 => Some(result),
@@ -107,11 +509,180 @@ impl ThreadedMatcher {
         }
     }
 
-    fn update(&self, path: &str) {
+    /// Match `lines` on the calling thread and return the results immediately. Matching takes
+    /// around a microsecond per line, so for lists of up to a few hundred lines this is faster
+    /// than `query`, which pays for a channel round trip and waking the worker, and whose results
+    /// are only seen at the caller's next poll. Longer lists block the caller for longer, so use
+    /// `query` for them.
+    pub fn match_sync<L: Line>(
+        &self,
+        query: &str,
+        context: &str,
+        num_results: usize,
+        lines: &[L],
+    ) -> Result<Vec<Match>> {
+        if let Some(recorder) = &self.recorder {
+            recorder.set_query(query, context, lines);
+        }
+        self.sync_matcher
+            .as_ref()
+            .ok_or(anyhow!("Matcher failed to initialize"))?
+            .best_matches(query, context, num_results as u64, lines)
+    }
+
+    /// `Matcher::telescope_score`, computed on the calling thread like `match_sync`.
+    pub fn telescope_score<L: Line>(&self, query: &str, context: &str, line: &L) -> Result<f64> {
+        Ok(self
+            .sync_matcher
+            .as_ref()
+            .ok_or(anyhow!("Matcher failed to initialize"))?
+            .telescope_score(query, context, line))
+    }
+
+    /// `Matcher::matched_ranges`, computed on the calling thread like `match_sync`.
+    pub fn matched_ranges(&self, query: &str, text: &str) -> Result<Option<Vec<(u32, u32)>>> {
+        Ok(self
+            .sync_matcher
+            .as_ref()
+            .ok_or(anyhow!("Matcher failed to initialize"))?
+            .matched_ranges(query, text))
+    }
+
+    /// Number of lines processed so far and the total number of lines in the running query, or
+    /// the most recent one if none is running. Waits for the worker to finish its current chunk.
+    pub fn progress(&self) -> Result<(usize, usize)> {
+        let (reply_send, reply_recv) = bounded(1);
+        self.command_ch
+            .send(Command::Progress(reply_send))
+            .map_err(|_| anyhow!("Processing thread has died"))?;
+        reply_recv
+            .recv()
+            .map_err(|_| anyhow!("Processing thread has died"))
+    }
+
+    pub fn update(&mut self, path: &str) {
+        if let Some(matcher) = &mut self.sync_matcher {
+            matcher.update(path);
+        }
         self.command_ch
             .send(Command::Update(path.to_string()))
             .unwrap();
     }
+
+    /// Seed the selection history, e.g. from `dump_frequency` in an earlier session. See
+    /// `Matcher::load_frequency`.
+    pub fn load_frequency(&mut self, entries: Vec<FrequencyEntry>) {
+        if let Some(matcher) = &mut self.sync_matcher {
+            matcher.load_frequency(&entries);
+        }
+        self.command_ch
+            .send(Command::LoadFrequency(entries))
+            .unwrap();
+    }
+
+    /// The selection history, see `Matcher::frequency_entries`. Read from the matcher used by
+    /// `match_sync`, which gets the same updates as the worker's.
+    pub fn dump_frequency(&self) -> Result<Vec<FrequencyEntry>> {
+        Ok(self
+            .sync_matcher
+            .as_ref()
+            .ok_or(anyhow!("Matcher failed to initialize"))?
+            .frequency_entries())
+    }
+
+    /// `Matcher::frequent_paths`, read from the matcher used by `match_sync` like
+    /// `dump_frequency`.
+    pub fn frequent_paths(&self) -> Result<Vec<(String, f64)>> {
+        Ok(self
+            .sync_matcher
+            .as_ref()
+            .ok_or(anyhow!("Matcher failed to initialize"))?
+            .frequent_paths())
+    }
+
+    /// See `Matcher::demote`.
+    pub fn demote(&mut self, path: &str) {
+        if let Some(matcher) = &mut self.sync_matcher {
+            matcher.demote(path);
+        }
+        self.command_ch
+            .send(Command::Demote(path.to_string()))
+            .unwrap();
+    }
+
+    /// The config most recently passed to `set_config`, which the worker uses for queries sent
+    /// after it.
+    pub fn config(&self) -> &MatcherConfig {
+        &self.config
+    }
+
+    /// Replace the worker's config. A query that is already running finishes with the old
+    /// config, later queries use the new one.
+    pub fn set_config(&mut self, config: MatcherConfig) {
+        self.config = config.clone();
+        if let Some(matcher) = &mut self.sync_matcher {
+            matcher.set_config(config.clone());
+        }
+        self.command_ch.send(Command::SetConfig(config)).unwrap();
+    }
+
+    /// Replace the set of paths open in the editor. See `Matcher::set_open_set`.
+    pub fn set_open_set(&mut self, paths: HashSet<String>) {
+        if let Some(matcher) = &mut self.sync_matcher {
+            matcher.set_open_set(paths.clone());
+        }
+        self.command_ch.send(Command::SetOpenSet(paths)).unwrap();
+    }
+
+    /// Set the file being edited. See `Matcher::set_current_file`.
+    pub fn set_current_file(&mut self, path: Option<String>) {
+        if let Some(matcher) = &mut self.sync_matcher {
+            matcher.set_current_file(path.clone());
+        }
+        self.command_ch.send(Command::SetCurrentFile(path)).unwrap();
+    }
+
+    /// Start recording queries to `path` in the `sylph --test-file` format, or stop with `None`.
+    /// Each query is written once `record_selection` is called for it.
+    pub fn set_record_file(&mut self, path: Option<&Path>) -> Result<()> {
+        self.recorder = path.map(Recorder::open).transpose()?;
+        Ok(())
+    }
+
+    /// Record the last query with `selected` as the line the user picked, if recording.
+    pub fn record_selection<L: Line>(&self, selected: &L) -> Result<()> {
+        match &self.recorder {
+            Some(recorder) => recorder.record_selection(selected),
+            None => Ok(()),
+        }
+    }
+
+    /// Drop lines whose path matches any of `globs`. See `Matcher::set_exclude_globs`.
+    pub fn set_exclude_globs(&mut self, globs: &[&str]) -> Result<()> {
+        let excludes = exclude_set(globs)?;
+        if let Some(matcher) = &mut self.sync_matcher {
+            matcher.set_exclude_set(excludes.clone());
+        }
+        self.command_ch
+            .send(Command::SetExcludes(excludes))
+            .map_err(|_| anyhow!("Processing thread has died"))
+    }
+}
+
+/// Zip parallel arrays of paths and line texts into lines.
+pub fn lines_from_columns(paths: Vec<String>, lines: Vec<String>) -> Result<Vec<OwnedLine>> {
+    if paths.len() != lines.len() {
+        return Err(anyhow!(
+            "Got {} paths but {} lines",
+            paths.len(),
+            lines.len()
+        ));
+    }
+    Ok(paths
+        .into_iter()
+        .zip(lines)
+        .map(|(path, line)| OwnedLine { path, line })
+        .collect())
 }
 
 impl<'lua> FromLua<'lua> for OwnedLine {
@@ -145,19 +716,97 @@ impl<'lua> ToLua<'lua> for Match {
             ("score", self.score.to_lua(lua)?),
             ("context_score", self.context_score.to_lua(lua)?),
             ("query_score", self.query_score.to_lua(lua)?),
+            ("raw_query_score", self.raw_query_score.to_lua(lua)?),
             ("frequency_score", self.frequency_score.to_lua(lua)?),
+            ("open_score", self.open_score.to_lua(lua)?),
+            ("stable_key", self.stable_key.to_lua(lua)?),
+            ("matched_basename", self.matched_basename.to_lua(lua)?),
         ];
         lua.create_table_from(x.into_iter())
             .map(|x| Value::Table(x))
     }
 }
 
+/// Converts matches to one table of parallel arrays, `{index = {...}, score = {...}, ...}`, with
+/// the same fields as a `Match` table. Building eight arrays instead of a table per match
+/// creates much less garbage when results are fetched on every keystroke.
+fn matches_to_columns<'lua>(lua: &'lua Lua, mtchs: &[Match]) -> mlua::Result<LuaTable<'lua>> {
+    let columns = vec![
+        (
+            "index",
+            lua.create_sequence_from(mtchs.iter().map(|m| m.index))?,
+        ),
+        (
+            "score",
+            lua.create_sequence_from(mtchs.iter().map(|m| m.score))?,
+        ),
+        (
+            "context_score",
+            lua.create_sequence_from(mtchs.iter().map(|m| m.context_score))?,
+        ),
+        (
+            "query_score",
+            lua.create_sequence_from(mtchs.iter().map(|m| m.query_score))?,
+        ),
+        (
+            "raw_query_score",
+            lua.create_sequence_from(mtchs.iter().map(|m| m.raw_query_score))?,
+        ),
+        (
+            "frequency_score",
+            lua.create_sequence_from(mtchs.iter().map(|m| m.frequency_score))?,
+        ),
+        (
+            "open_score",
+            lua.create_sequence_from(mtchs.iter().map(|m| m.open_score))?,
+        ),
+        (
+            "stable_key",
+            lua.create_sequence_from(mtchs.iter().map(|m| m.stable_key))?,
+        ),
+        (
+            "matched_basename",
+            lua.create_sequence_from(mtchs.iter().map(|m| m.matched_basename))?,
+        ),
+    ];
+    lua.create_table_from(columns)
+}
+
 impl UserData for ThreadedMatcher {
     fn add_methods<'lua, M: LuaUserDataMethods<'lua, Self>>(methods: &mut M) {
         methods.add_method_mut("query", |_, this, vals| {
             let (query, context, num_results, lines): (String, String, usize, Vec<OwnedLine>) =
                 vals;
-            this.query(&query, &context, num_results, &lines);
+            Ok(this.query_owned(&query, &context, num_results, lines))
+        });
+        // Like `query`, but with the lines as two arrays of paths and texts. This avoids looking
+        // up fields in a table per line, which dominates converting large lists.
+        methods.add_method_mut("query_raw", |_, this, vals| {
+            let (query, context, num_results, paths, lines): (
+                String,
+                String,
+                usize,
+                Vec<String>,
+                Vec<String>,
+            ) = vals;
+            let lines = lines_from_columns(paths, lines).map_err(LuaError::external)?;
+            Ok(this.query_owned(&query, &context, num_results, lines))
+        });
+        methods.add_method_mut("match_now", |_, this, vals| {
+            let (query, context, num_results, lines): (String, String, usize, Vec<OwnedLine>) =
+                vals;
+            Ok(this.match_now(&query, &context, num_results, lines))
+        });
+        methods.add_method_mut("feed", |_, this, lines: Vec<OwnedLine>| {
+            this.feed(lines);
+            Ok(())
+        });
+        methods.add_method_mut("query_more", |_, this, vals| {
+            let (query_id, additional): (usize, usize) = vals;
+            Ok(this.query_more(query_id, additional))
+        });
+        methods.add_method_mut("reset", |_, this, _: ()| {
+            this.reset();
             Ok(())
         });
         methods.add_method("get_result", |lua, this, _: ()| match this.get_result() {
@@ -165,11 +814,145 @@ impl UserData for ThreadedMatcher {
             Some(Ok(mtchs)) => Ok((mtchs.to_lua(lua)?, Value::Nil)),
             Some(Err(err)) => Ok((Value::Nil, err.to_string().to_lua(lua)?)),
         });
-        methods.add_method("update", |_, this, s| {
+        // Like `get_result`, but returns the matches as parallel arrays.
+        methods.add_method("get_result_columnar", |lua, this, _: ()| {
+            match this.get_result() {
+                None => Ok((Value::Nil, Value::Nil)),
+                Some(Ok(mtchs)) => Ok((Value::Table(matches_to_columns(lua, &mtchs)?), Value::Nil)),
+                Some(Err(err)) => Ok((Value::Nil, err.to_string().to_lua(lua)?)),
+            }
+        });
+        methods.add_method("match_sync", |lua, this, vals| {
+            let (query, context, num_results, lines): (String, String, usize, Vec<OwnedLine>) =
+                vals;
+            match this.match_sync(&query, &context, num_results, &lines) {
+                Ok(mtchs) => Ok((mtchs.to_lua(lua)?, Value::Nil)),
+                Err(err) => Ok((Value::Nil, err.to_string().to_lua(lua)?)),
+            }
+        });
+        methods.add_method("telescope_score", |lua, this, vals| {
+            let (query, context, line): (String, String, OwnedLine) = vals;
+            match this.telescope_score(&query, &context, &line) {
+                Ok(score) => Ok((score.to_lua(lua)?, Value::Nil)),
+                Err(err) => Ok((Value::Nil, err.to_string().to_lua(lua)?)),
+            }
+        });
+        // The ranges are `{start, end}` arrays of 0-based character positions, end exclusive.
+        // `nil` if the query doesn't match.
+        methods.add_method("matched_ranges", |lua, this, vals| {
+            let (query, text): (String, String) = vals;
+            match this.matched_ranges(&query, &text) {
+                Ok(ranges) => Ok((
+                    ranges
+                        .map(|r| {
+                            r.into_iter()
+                                .map(|(start, end)| vec![start, end])
+                                .collect::<Vec<_>>()
+                        })
+                        .to_lua(lua)?,
+                    Value::Nil,
+                )),
+                Err(err) => Ok((Value::Nil, err.to_string().to_lua(lua)?)),
+            }
+        });
+        methods.add_method("progress", |lua, this, _: ()| match this.progress() {
+            Ok((processed, total)) => {
+                let progress = lua.create_table()?;
+                progress.set("processed", processed)?;
+                progress.set("total", total)?;
+                Ok((Value::Table(progress), Value::Nil))
+            }
+            Err(err) => Ok((Value::Nil, err.to_string().to_lua(lua)?)),
+        });
+        methods.add_method_mut("update", |_, this, s| {
             let s: String = s;
             this.update(&s);
             Ok(())
         });
+        methods.add_method_mut(
+            "set_record_file",
+            |lua, this, path: Option<String>| match this
+                .set_record_file(path.as_deref().map(Path::new))
+            {
+                Ok(()) => Ok(Value::Nil),
+                Err(err) => err.to_string().to_lua(lua),
+            },
+        );
+        methods.add_method("record_selection", |lua, this, line: OwnedLine| match this
+            .record_selection(&line)
+        {
+            Ok(()) => Ok(Value::Nil),
+            Err(err) => err.to_string().to_lua(lua),
+        });
+        methods.add_method_mut("demote", |_, this, s: String| {
+            this.demote(&s);
+            Ok(())
+        });
+        methods.add_method_mut("set_excludes", |lua, this, globs: Vec<String>| {
+            let globs = globs.iter().map(String::as_str).collect::<Vec<_>>();
+            match this.set_exclude_globs(&globs) {
+                Ok(()) => Ok(Value::Nil),
+                Err(err) => err.to_string().to_lua(lua),
+            }
+        });
+        methods.add_method_mut("set_max_queued_results", |_, this, max: usize| {
+            this.set_max_queued_results(max);
+            Ok(())
+        });
+        methods.add_method_mut("set_open_set", |_, this, paths: Vec<String>| {
+            this.set_open_set(paths.into_iter().collect());
+            Ok(())
+        });
+        // Takes a list of `{path = ..., count = ...}` tables
+        methods.add_method_mut("load_frequency", |lua, this, entries: Value| {
+            this.load_frequency(lua.from_value(entries)?);
+            Ok(())
+        });
+        methods.add_method("dump_frequency", |lua, this, _: ()| {
+            match this.dump_frequency() {
+                Ok(entries) => Ok((lua.to_value(&entries)?, Value::Nil)),
+                Err(err) => Ok((Value::Nil, err.to_string().to_lua(lua)?)),
+            }
+        });
+        // An array of `{path = ..., score = ...}`, most recent first.
+        methods.add_method("frequent_paths", |lua, this, _: ()| {
+            match this.frequent_paths() {
+                Ok(paths) => {
+                    let entries = paths
+                        .into_iter()
+                        .map(|(path, score)| {
+                            lua.create_table_from(vec![
+                                ("path", path.to_lua(lua)?),
+                                ("score", score.to_lua(lua)?),
+                            ])
+                        })
+                        .collect::<mlua::Result<Vec<_>>>()?;
+                    Ok((entries.to_lua(lua)?, Value::Nil))
+                }
+                Err(err) => Ok((Value::Nil, err.to_string().to_lua(lua)?)),
+            }
+        });
+        methods.add_method_mut("set_current_file", |_, this, path: Option<String>| {
+            this.set_current_file(path);
+            Ok(())
+        });
+        // The config as a table with the same keys `set_config` takes
+        methods.add_method("get_config", |lua, this, _: ()| lua.to_value(this.config()));
+        // Keys in the table override the current config, other settings are left as is.
+        methods.add_method_mut("set_config", |lua, this, tbl: LuaTable| {
+            let config = match lua.to_value(&this.config)? {
+                Value::Table(current) => {
+                    for pair in tbl.pairs::<Value, Value>() {
+                        let (k, v) = pair?;
+                        current.set(k, v)?;
+                    }
+                    lua.from_value(Value::Table(current))?
+                }
+                _ => unreachable!(),
+            };
+            this.set_config(config);
+            Ok(())
+        });
     }
 }
 
@@ -177,9 +960,37 @@ fn threaded_matcher(_: &Lua, _: ()) -> LuaResult<ThreadedMatcher> {
     Ok(ThreadedMatcher::new())
 }
 
+/// `Match::diff` of two result arrays from `get_result` or `match_sync`. Returns the added,
+/// removed and moved `index`es as three arrays.
+fn diff_results(
+    _: &Lua,
+    (old, new): (Vec<LuaTable>, Vec<LuaTable>),
+) -> LuaResult<(Vec<usize>, Vec<usize>, Vec<usize>)> {
+    let indexed = |tbls: Vec<LuaTable>| -> LuaResult<Vec<Match>> {
+        tbls.into_iter()
+            .map(|tbl| {
+                Ok(Match {
+                    index: tbl.get("index")?,
+                    score: 0.,
+                    context_score: 0.,
+                    query_score: 0.,
+                    raw_query_score: 0.,
+                    frequency_score: 0.,
+                    open_score: 0.,
+                    stable_key: 0,
+                    matched_basename: false,
+                })
+            })
+            .collect()
+    };
+    let diff = Match::diff(&indexed(old)?, &indexed(new)?);
+    Ok((diff.added, diff.removed, diff.moved))
+}
+
 #[lua_module]
 fn filter(lua: &Lua) -> LuaResult<LuaTable> {
     let exports = lua.create_table()?;
     exports.set("threaded_matcher", lua.create_function(threaded_matcher)?)?;
+    exports.set("diff_results", lua.create_function(diff_results)?)?;
     Ok(exports)
 }
@@ -0,0 +1,89 @@
+//! Stand-ins for the parts of `fuzzy_matcher` the matcher uses, for builds without the
+//! `fuzzy-matcher` feature. Matching is an in-order subsequence check like
+//! `MatchMode::Subsequence`, so every mode still works, but ranking is much cruder.
+
+/// Scores a subsequence match `score_match` per query character, scaled down by how much longer
+/// the text is than the query. That keeps scores within what the real matcher could give, which
+/// `Matcher::query_score_bound` relies on.
+#[derive(Default)]
+pub struct SkimMatcherV2 {
+    score_config: SkimScoreConfig,
+}
+
+impl SkimMatcherV2 {
+    pub fn use_cache(self, _: bool) -> Self {
+        self
+    }
+
+    /// The check is always smart case: case is ignored unless the query has uppercase letters.
+    pub fn smart_case(self) -> Self {
+        self
+    }
+
+    pub fn score_config(self, score_config: SkimScoreConfig) -> Self {
+        SkimMatcherV2 { score_config }
+    }
+}
+
+/// Only `score_match` affects scores, the rest are kept so configs build the same way.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+pub struct SkimScoreConfig {
+    pub score_match: i32,
+    pub gap_start: i32,
+    pub gap_extension: i32,
+    pub bonus_first_char_multiplier: i32,
+    pub bonus_head: i32,
+    pub bonus_break: i32,
+    pub bonus_camel: i32,
+    pub bonus_consecutive: i32,
+    pub penalty_case_mismatch: i32,
+}
+
+impl Default for SkimScoreConfig {
+    /// The same values as `fuzzy_matcher`'s.
+    fn default() -> Self {
+        SkimScoreConfig {
+            score_match: 16,
+            gap_start: -3,
+            gap_extension: -1,
+            bonus_first_char_multiplier: 2,
+            bonus_head: 8,
+            bonus_break: 7,
+            bonus_camel: 6,
+            bonus_consecutive: 4,
+            penalty_case_mismatch: -2,
+        }
+    }
+}
+
+pub trait FuzzyMatcher {
+    fn fuzzy_indices(&self, choice: &str, pattern: &str) -> Option<(i64, Vec<usize>)>;
+
+    fn fuzzy_match(&self, choice: &str, pattern: &str) -> Option<i64> {
+        self.fuzzy_indices(choice, pattern).map(|(score, _)| score)
+    }
+}
+
+impl FuzzyMatcher for SkimMatcherV2 {
+    fn fuzzy_indices(&self, choice: &str, pattern: &str) -> Option<(i64, Vec<usize>)> {
+        let case_sensitive = pattern.chars().any(char::is_uppercase);
+        let same = |q: char, t: char| {
+            if case_sensitive {
+                q == t
+            } else {
+                t.to_lowercase().eq(q.to_lowercase())
+            }
+        };
+        let mut text_chars = choice.chars().enumerate();
+        let indices = pattern
+            .chars()
+            .map(|q| text_chars.find(|&(_, t)| same(q, t)).map(|(i, _)| i))
+            .collect::<Option<Vec<_>>>()?;
+        let text_len = choice.chars().count().max(1);
+        let score =
+            self.score_config.score_match as i64 * indices.len() as i64 * indices.len() as i64
+                / text_len as i64;
+        Some((score, indices))
+    }
+}
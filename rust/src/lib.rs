@@ -1,18 +1,48 @@
+//! Scoring and ranking of candidate lines for sylph.
+//!
+//! With the `tracing` feature, matching emits [tracing](https://docs.rs/tracing) spans:
+//! `best_matches` and `IncrementalMatcher::process` with their line counts and chunk sizes, and
+//! one span per command handled by the `ThreadedMatcher` worker. A Rust host sees them by
+//! installing a subscriber, for example `tracing_subscriber::fmt()
+//! .with_span_events(FmtSpan::CLOSE).init()` logs how long each span took. Without the feature
+//! none of this is compiled in.
 #![feature(try_blocks)]
 
 extern crate anyhow;
 extern crate binary_heap_plus;
+extern crate bincode;
 extern crate crossbeam_channel;
+#[cfg(feature = "fuzzy-matcher")]
 extern crate fuzzy_matcher;
+extern crate globset;
 extern crate itertools;
 extern crate serde;
+extern crate serde_json;
 extern crate sublime_fuzzy;
 #[macro_use]
 extern crate mlua_derive;
+#[cfg(feature = "async")]
+extern crate futures_core;
 extern crate lru;
 extern crate strsim;
+#[cfg(feature = "async")]
+extern crate tokio;
+#[cfg(feature = "tracing")]
+extern crate tracing;
 
+#[cfg(feature = "async")]
+mod async_matcher;
+mod capi;
 mod ffi;
+#[cfg(not(feature = "fuzzy-matcher"))]
+mod fuzzy_fallback;
+mod live_query;
 mod matcher;
+mod record;
+#[cfg(feature = "async")]
+pub use crate::async_matcher::*;
+pub use crate::capi::*;
 pub use crate::ffi::*;
+pub use crate::live_query::*;
 pub use crate::matcher::*;
+pub use crate::record::*;
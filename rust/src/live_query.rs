@@ -0,0 +1,67 @@
+//! A query whose lines arrive over time, for Rust hosts that want the Lua side's polling without
+//! running a worker thread.
+
+use anyhow::Result;
+use std::time::{Duration, Instant};
+
+use super::matcher::*;
+
+/// How long `LiveQuery::poll` works before returning, unless set with `LiveQuery::set_budget`.
+pub const DEFAULT_POLL_BUDGET: Duration = Duration::from_millis(2);
+
+/// Matches lines as they are fed, a chunk at a time. Wraps an `IncrementalMatcher`, choosing
+/// chunk sizes so that each `poll` takes about the budget.
+pub struct LiveQuery<'b, 'c, L: Line> {
+    inc_matcher: IncrementalMatcher<'b, 'c, L>,
+    budget: Duration,
+}
+
+impl<'b, 'c, L: Line> LiveQuery<'b, 'c, L> {
+    /// Start matching `query` against lines that will be fed later. Returns an error if
+    /// `num_results` is zero.
+    pub fn new(
+        matcher: &'b Matcher,
+        query: &'c str,
+        context: &'c str,
+        num_results: u64,
+    ) -> Result<Self> {
+        Ok(LiveQuery {
+            inc_matcher: matcher.incremental_match(query, context, num_results, Vec::new())?,
+            budget: DEFAULT_POLL_BUDGET,
+        })
+    }
+
+    /// Set roughly how long each `poll` may take.
+    pub fn set_budget(&mut self, budget: Duration) {
+        self.budget = budget;
+    }
+
+    /// Add more lines. Returns how many were dropped for exceeding `MatcherConfig::max_lines`.
+    pub fn feed(&mut self, lines: impl IntoIterator<Item = L>) -> usize {
+        self.inc_matcher.feed_lines(lines)
+    }
+
+    /// Process lines for up to the budget. Returns the results so far once every line fed has
+    /// been processed, or `None` if there is still work left. Feeding more lines makes the
+    /// next poll do more work, and its results replace earlier ones.
+    pub fn poll(&mut self) -> Result<Option<Vec<Match>>> {
+        let start = Instant::now();
+        loop {
+            match self.inc_matcher.process_adaptive(self.budget)? {
+                Progress::Done(results) => return Ok(Some(results)),
+                Progress::Working if start.elapsed() >= self.budget => return Ok(None),
+                Progress::Working => (),
+            }
+        }
+    }
+
+    /// Process every remaining line and return the final results.
+    pub fn finish(mut self) -> Result<Vec<Match>> {
+        loop {
+            let (processed, total) = self.inc_matcher.stats();
+            if let Progress::Done(results) = self.inc_matcher.process(total - processed)? {
+                return Ok(results);
+            }
+        }
+    }
+}
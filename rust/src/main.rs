@@ -6,12 +6,13 @@ extern crate serde_json;
 extern crate structopt;
 
 use anyhow::{anyhow, Result};
-use filter::{lookup, Line, Match, Matcher};
+use filter::{lookup, Line, Match, Matcher, MatcherConfig};
+use itertools::Itertools;
 use neovim_lib::{Neovim, RequestHandler, Session, Value};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::fs::File;
 use std::io::{BufRead, BufReader};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
@@ -19,6 +20,20 @@ fn to_value(m: Match) -> Value {
     Value::Map(vec![(Value::from("index"), Value::from(m.index))])
 }
 
+/// Some msgpack encoders send whole numbers computed in Lua as floats, so accept those too.
+fn num_matches(val: &Value) -> Result<u64> {
+    if let Some(n) = val.as_u64() {
+        return Ok(n);
+    }
+    match val.as_f64() {
+        Some(n) if n.is_finite() && n.round() >= 0. => Ok(n.round() as u64),
+        _ => Err(anyhow!(
+            "num_matches argument {} is not a nonnegative number",
+            val
+        )),
+    }
+}
+
 struct EventHandler {
     matcher: Matcher,
 }
@@ -40,22 +55,19 @@ impl RequestHandler for EventHandler {
         let result: Result<Value> = try {
             match name {
                 "match" => {
-                    let query = lookup(&args[0], "query")?
+                    let arg = args.first().ok_or(anyhow!("match requires an argument"))?;
+                    let query = lookup(arg, "query")?
                         .as_str()
                         .ok_or(anyhow!("query argument is not a string"))?;
-                    let context = lookup(&args[0], "context")?
+                    let context = lookup(arg, "context")?
                         .as_str()
                         .ok_or(anyhow!("context argument is not a string"))?;
-                    let num_matches = lookup(&args[0], "num_matches")?
-                        .as_u64()
-                        .ok_or(anyhow!("num_matches argument is not an integer"))?;
+                    let num_matches = num_matches(lookup(arg, "num_matches")?)?;
+                    let lines_value = lookup(arg, "lines")?;
                     let lines = itertools::process_results(
-                        lookup(&args[0], "lines")?
+                        lines_value
                             .as_array()
-                            .ok_or(anyhow!(
-                                "lines argument {} is not an array",
-                                lookup(&args[0], "lines").unwrap()
-                            ))?
+                            .ok_or(anyhow!("lines argument {} is not an array", lines_value))?
                             .iter()
                             .map(JSONLine::from_value),
                         |iter| iter.collect::<Vec<_>>(),
@@ -71,7 +83,10 @@ impl RequestHandler for EventHandler {
                     )
                 }
                 "selected" => {
-                    let selected = JSONLine::from_value(&args[0])?;
+                    let selected = JSONLine::from_value(
+                        args.first()
+                            .ok_or(anyhow!("selected requires an argument"))?,
+                    )?;
                     self.matcher.update(&selected.path);
                     Value::from(true)
                 }
@@ -87,6 +102,16 @@ impl RequestHandler for EventHandler {
 struct Opts {
     #[structopt(long = "test-file", parse(from_os_str))]
     test_file: Option<PathBuf>,
+    /// Print test file results as one JSON object per query instead of a table.
+    #[structopt(long = "json")]
+    json: bool,
+    /// Compare test file results against the `--json` output of a previous run.
+    #[structopt(long = "baseline", parse(from_os_str))]
+    baseline: Option<PathBuf>,
+    /// Search for the `MatcherConfig` weights that maximize the test file's total score and
+    /// print the best found.
+    #[structopt(long = "tune")]
+    tune: bool,
 }
 
 #[derive(Deserialize)]
@@ -129,14 +154,291 @@ struct Query {
     selected: JSONLine,
 }
 
+#[derive(Serialize)]
+struct JSONMatch<'a> {
+    index: usize,
+    path: &'a str,
+    score: f64,
+    context_score: f64,
+    query_score: f64,
+    raw_query_score: f64,
+    frequency_score: f64,
+    open_score: f64,
+}
+
+impl<'a> JSONMatch<'a> {
+    fn new(m: &Match, lines: &'a [JSONLine]) -> Self {
+        JSONMatch {
+            index: m.index,
+            path: &lines[m.index].path,
+            score: m.score,
+            context_score: m.context_score,
+            query_score: m.query_score,
+            raw_query_score: m.raw_query_score,
+            frequency_score: m.frequency_score,
+            open_score: m.open_score,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JSONResult<'a> {
+    query: &'a str,
+    context: &'a str,
+    num_lines: usize,
+    elapsed_ns: u128,
+    correct_position: Option<usize>,
+    matches: Vec<JSONMatch<'a>>,
+}
+
+/// The subset of a `JSONResult` needed to compare against a baseline run.
+#[derive(Deserialize)]
+struct BaselineResult {
+    query: String,
+    correct_position: Option<usize>,
+}
+
+fn load_baseline(path: &Path) -> Result<Vec<BaselineResult>> {
+    let reader = BufReader::new(File::open(path)?);
+    itertools::process_results(
+        reader
+            .lines()
+            .filter(|l| l.as_ref().map_or(true, |l| !l.trim().is_empty()))
+            .map(|l| -> Result<BaselineResult> { Ok(serde_json::from_str(&l?)?) }),
+        |iter| iter.collect(),
+    )
+}
+
+/// Score for finding the correct match at `position`. Finding it first gives 1, and the score
+/// decays towards 0.5 the further down the list it is. Not finding it gives 0.
+fn position_score(position: Option<usize>) -> f64 {
+    position.map_or(0., |x| 0.5 * (x as f64 * -0.2).exp() + 0.5)
+}
+
+fn reciprocal_rank(position: Option<usize>) -> f64 {
+    position.map_or(0., |x| 1. / (x as f64 + 1.))
+}
+
+/// Mean `reciprocal_rank` of the correct match positions in `results`, 0 if there are none.
+fn mean_reciprocal_rank(results: &[(String, Option<usize>)]) -> f64 {
+    results
+        .iter()
+        .map(|(_, position)| reciprocal_rank(*position))
+        .sum::<f64>()
+        / results.len().max(1) as f64
+}
+
+/// Summarize how each query's correct match position changed relative to `baseline`.
+fn compare_to_baseline(
+    baseline: &[BaselineResult],
+    results: &[(String, Option<usize>)],
+) -> Result<String> {
+    if baseline.len() != results.len() {
+        return Err(anyhow!(
+            "Baseline has {} queries but test file has {}",
+            baseline.len(),
+            results.len()
+        ));
+    }
+    let mut improved = Vec::new();
+    let mut regressed = Vec::new();
+    let mut unchanged = 0;
+    let mut delta = 0.;
+    for (old, (query, new_position)) in baseline.iter().zip(results) {
+        if &old.query != query {
+            return Err(anyhow!(
+                "Baseline query {:?} does not match test file query {:?}",
+                old.query,
+                query
+            ));
+        }
+        let old_score = position_score(old.correct_position);
+        let new_score = position_score(*new_position);
+        delta += new_score - old_score;
+        let show = |x: Option<usize>| x.map_or(-1, |x| x as isize);
+        let line = format!(
+            "  {:?}: {} -> {}",
+            query,
+            show(old.correct_position),
+            show(*new_position)
+        );
+        if new_score > old_score {
+            improved.push(line);
+        } else if new_score < old_score {
+            regressed.push(line);
+        } else {
+            unchanged += 1;
+        }
+    }
+    Ok(format!(
+        "improved: {}\n{}regressed: {}\n{}unchanged: {}\nnet score delta: {:+.3}",
+        improved.len(),
+        improved.iter().map(|l| format!("{}\n", l)).join(""),
+        regressed.len(),
+        regressed.iter().map(|l| format!("{}\n", l)).join(""),
+        unchanged,
+        delta
+    ))
+}
+
+fn load_queries(path: &Path) -> Result<Vec<Query>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut queries = Vec::new();
+    for line in reader.lines() {
+        match serde_json::from_str::<Query>(line?.trim_end()) {
+            Err(err) => eprintln!("{:?}", err),
+            Ok(json) => queries.push(json),
+        }
+    }
+    Ok(queries)
+}
+
+/// A `MatcherConfig` weight searched over by `--tune`.
+struct Weight {
+    name: &'static str,
+    step: f64,
+    min: f64,
+    get: fn(&MatcherConfig) -> f64,
+    set: fn(&mut MatcherConfig, f64),
+}
+
+/// The weights `--tune` searches over. The test file harness starts each query with a fresh
+/// matcher, so weights for frequency and open files have no effect and are left out.
+fn tuned_weights() -> Vec<Weight> {
+    vec![
+        Weight {
+            name: "path_length_penalty",
+            step: 0.05,
+            min: 0.,
+            get: |c| c.path_length_penalty,
+            set: |c, x| c.path_length_penalty = x,
+        },
+        Weight {
+            name: "bonus_head",
+            step: 1.,
+            min: 0.,
+            get: |c| c.bonus_head as f64,
+            set: |c, x| c.bonus_head = x as i32,
+        },
+        Weight {
+            name: "bonus_break",
+            step: 1.,
+            min: 0.,
+            get: |c| c.bonus_break as f64,
+            set: |c, x| c.bonus_break = x as i32,
+        },
+        Weight {
+            name: "bonus_camel",
+            step: 1.,
+            min: 0.,
+            get: |c| c.bonus_camel as f64,
+            set: |c, x| c.bonus_camel = x as i32,
+        },
+        Weight {
+            name: "bonus_consecutive",
+            step: 1.,
+            min: 0.,
+            get: |c| c.bonus_consecutive as f64,
+            set: |c, x| c.bonus_consecutive = x as i32,
+        },
+        Weight {
+            name: "bonus_first_char_multiplier",
+            step: 1.,
+            min: 1.,
+            get: |c| c.bonus_first_char_multiplier as f64,
+            set: |c, x| c.bonus_first_char_multiplier = x as i32,
+        },
+    ]
+}
+
+/// Most passes `tune` makes over the weights before giving up on converging.
+const TUNE_ROUNDS: usize = 10;
+
+/// Total `position_score` over `queries` with `config`, each query matched by a fresh matcher
+/// like the test file harness does.
+fn evaluate(config: &MatcherConfig, queries: &[Query]) -> Result<f64> {
+    let mut total = 0.;
+    for json in queries {
+        let matches = Matcher::with_config(config.clone())?.best_matches(
+            &json.query,
+            &json.launched_from,
+            10,
+            &json.lines,
+        )?;
+        total += position_score(
+            matches
+                .iter()
+                .position(|m| json.lines[m.index].line == json.selected.line),
+        );
+    }
+    Ok(total)
+}
+
+/// Coordinate descent over `tuned_weights`, starting from the default config: step each weight
+/// up or down while that strictly improves the total score, until a full pass changes nothing.
+/// Returns the best config and its score. Deterministic, so the same corpus always gives the
+/// same result.
+fn tune(queries: &[Query]) -> Result<(MatcherConfig, f64)> {
+    let weights = tuned_weights();
+    let mut best = MatcherConfig::default();
+    let mut best_score = evaluate(&best, queries)?;
+    for _ in 0..TUNE_ROUNDS {
+        let mut improved = false;
+        for weight in &weights {
+            for &direction in &[1., -1.] {
+                loop {
+                    let value = (weight.get)(&best) + direction * weight.step;
+                    if value < weight.min {
+                        break;
+                    }
+                    let mut candidate = best.clone();
+                    (weight.set)(&mut candidate, value);
+                    let score = evaluate(&candidate, queries)?;
+                    if score <= best_score {
+                        break;
+                    }
+                    eprintln!("{} = {}: {:.3}", weight.name, value, score);
+                    best = candidate;
+                    best_score = score;
+                    improved = true;
+                }
+            }
+        }
+        if !improved {
+            break;
+        }
+    }
+    Ok((best, best_score))
+}
+
 fn main() -> Result<()> {
     let opt = Opts::from_args();
+    if opt.tune {
+        let path = opt
+            .test_file
+            .ok_or(anyhow!("--tune requires --test-file"))?;
+        let queries = load_queries(&path)?;
+        let default_score = evaluate(&MatcherConfig::default(), &queries)?;
+        let (config, score) = tune(&queries)?;
+        for weight in tuned_weights() {
+            println!("{}: {}", weight.name, (weight.get)(&config));
+        }
+        println!(
+            "total score: {:.3}/{} (default config {:.3})",
+            score,
+            queries.len(),
+            default_score
+        );
+        return Ok(());
+    }
     match opt.test_file {
         Some(path) => {
             let file = File::open(path).unwrap();
             let reader = BufReader::new(file);
+            let baseline = opt.baseline.as_deref().map(load_baseline).transpose()?;
 
             let mut total_score = 0.;
+            let mut results = Vec::new();
             let mut count = 0;
             let mut total_time = Duration::from_secs(0);
             for line in reader.lines() {
@@ -157,11 +459,27 @@ fn main() -> Result<()> {
                         let match_position = matches
                             .iter()
                             .position(|m| json.lines[m.index].line == json.selected.line);
-                        total_score +=
-                            match_position.map_or(0., |x| 0.5 * (x as f64 * -0.2).exp() + 0.5);
+                        total_score += position_score(match_position);
+                        results.push((json.query.clone(), match_position));
                         count += 1;
                         total_time += elapsed;
 
+                        if opt.json {
+                            let result = JSONResult {
+                                query: &json.query,
+                                context: &json.launched_from,
+                                num_lines: json.lines.len(),
+                                elapsed_ns: elapsed.as_nanos(),
+                                correct_position: match_position,
+                                matches: matches
+                                    .iter()
+                                    .map(|m| JSONMatch::new(m, &json.lines))
+                                    .collect(),
+                            };
+                            println!("{}", serde_json::to_string(&result)?);
+                            continue;
+                        }
+
                         println!(
                             "query {} from {} ({} lines)",
                             json.query,
@@ -193,8 +511,26 @@ fn main() -> Result<()> {
                 }
             }
 
-            println!("\ntotal score: {:.3}/{}", total_score, count);
-            println!("total time: {:?}", total_time);
+            let mut summary = format!(
+                "total score: {:.3}/{}\nmean reciprocal rank: {:.3}\ntotal time: {:?}",
+                total_score,
+                count,
+                mean_reciprocal_rank(&results),
+                total_time
+            );
+            if let Some(baseline) = baseline {
+                summary = format!(
+                    "{}\n\n{}",
+                    summary,
+                    compare_to_baseline(&baseline, &results)?
+                );
+            }
+            // Keep stdout valid JSON lines when emitting JSON
+            if opt.json {
+                eprintln!("{}", summary);
+            } else {
+                println!("\n{}", summary);
+            }
         }
         None => {
             let session = Session::new_parent().unwrap();
@@ -207,3 +543,193 @@ fn main() -> Result<()> {
     };
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn match_args(lines: Value) -> Vec<Value> {
+        match_args_with_num_matches(lines, Value::from(10))
+    }
+
+    fn match_args_with_num_matches(lines: Value, num_matches: Value) -> Vec<Value> {
+        vec![Value::Map(vec![
+            (Value::from("query"), Value::from("mat")),
+            (Value::from("context"), Value::from("")),
+            (Value::from("num_matches"), num_matches),
+            (Value::from("lines"), lines),
+        ])]
+    }
+
+    #[test]
+    fn match_with_non_array_lines_is_an_error() {
+        let mut handler = EventHandler::new().unwrap();
+        let err = handler
+            .handle_request("match", match_args(Value::from("src/matcher.rs")))
+            .unwrap_err();
+        assert!(err.as_str().unwrap().contains("is not an array"));
+    }
+
+    #[test]
+    fn match_without_arguments_is_an_error() {
+        let mut handler = EventHandler::new().unwrap();
+        assert!(handler.handle_request("match", vec![]).is_err());
+        assert!(handler.handle_request("selected", vec![]).is_err());
+    }
+
+    #[test]
+    fn recorded_queries_parse_as_test_file() {
+        let lines = vec![
+            filter::OwnedLine {
+                path: "src/main.rs".to_string(),
+                line: "fn main() {".to_string(),
+            },
+            filter::OwnedLine {
+                path: "src/matcher.rs".to_string(),
+                line: "pub struct \"Matcher\"".to_string(),
+            },
+        ];
+        let mut out = Vec::new();
+        filter::write_test_record(&mut out, "mat", "src/lib.rs", &lines, &lines[1]).unwrap();
+        filter::write_test_record(&mut out, "", "", &lines, &lines[0]).unwrap();
+        let queries = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str::<Query>(l).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(queries.len(), 2);
+        assert_eq!(queries[0].query, "mat");
+        assert_eq!(queries[0].launched_from, "src/lib.rs");
+        assert_eq!(queries[0].lines.len(), 2);
+        assert_eq!(queries[0].lines[1].line, lines[1].line);
+        assert_eq!(queries[0].lines[1].path, lines[1].path);
+        assert_eq!(queries[0].selected.line, lines[1].line);
+        assert_eq!(queries[1].selected.path, "src/main.rs");
+    }
+
+    #[test]
+    fn json_matches_include_every_score() {
+        let lines = ["src/main.rs", "src/matcher.rs"]
+            .iter()
+            .map(|p| JSONLine {
+                line: p.to_string(),
+                path: p.to_string(),
+            })
+            .collect::<Vec<_>>();
+        let mut matcher = Matcher::with_config(MatcherConfig {
+            query_weight: 2.,
+            open_boost: 3.,
+            ..MatcherConfig::default()
+        })
+        .unwrap();
+        matcher.set_open_set(std::iter::once("src/matcher.rs".to_string()).collect());
+        let matches = matcher.best_matches("mat", "", 10, &lines).unwrap();
+        let json = serde_json::to_value(JSONMatch::new(&matches[0], &lines)).unwrap();
+        assert_eq!(json["path"], "src/matcher.rs");
+        assert_eq!(json["query_score"], matches[0].query_score);
+        assert_eq!(json["raw_query_score"], matches[0].raw_query_score);
+        assert_eq!(
+            json["query_score"].as_f64().unwrap(),
+            2. * json["raw_query_score"].as_f64().unwrap()
+        );
+        assert_eq!(json["open_score"], 3.);
+    }
+
+    #[test]
+    fn baseline_comparison_reports_each_query() {
+        let path =
+            std::env::temp_dir().join(format!("sylph-baseline-{}.jsonl", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{"query": "a", "correct_position": 0, "matches": []}
+{"query": "b", "correct_position": 2, "matches": []}
+{"query": "c", "correct_position": null, "matches": []}
+"#,
+        )
+        .unwrap();
+        let baseline = load_baseline(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let results = vec![
+            ("a".to_string(), Some(1)),
+            ("b".to_string(), Some(0)),
+            ("c".to_string(), None),
+        ];
+        // (1/2 + 1 + 0) / 3
+        assert_eq!(mean_reciprocal_rank(&results), 0.5);
+        assert_eq!(mean_reciprocal_rank(&[]), 0.);
+        // position_score(0) - position_score(2) + position_score(1) - position_score(0)
+        assert_eq!(
+            compare_to_baseline(&baseline, &results).unwrap(),
+            "improved: 1\n  \"b\": 2 -> 0\nregressed: 1\n  \"a\": 0 -> 1\nunchanged: 1\n\
+             net score delta: +0.074"
+        );
+
+        assert!(compare_to_baseline(&baseline, &results[..2]).is_err());
+        let renamed = vec![
+            ("a".to_string(), Some(1)),
+            ("x".to_string(), Some(0)),
+            ("c".to_string(), None),
+        ];
+        assert!(compare_to_baseline(&baseline, &renamed).is_err());
+    }
+
+    #[test]
+    fn tune_does_not_lose_to_default_config() {
+        let lines = [
+            "src/matcher.rs",
+            "src/ffi.rs",
+            "tests/unit.rs",
+            "rust/src/main.rs",
+        ]
+        .iter()
+        .map(|p| format!(r#"{{"name": "{0}", "path": "{0}"}}"#, p))
+        .join(",");
+        let queries = [("mat", "src/main.rs"), ("unit", ""), ("rsm", "")]
+            .iter()
+            .map(|(query, selected)| {
+                serde_json::from_str::<Query>(&format!(
+                    r#"{{"query": "{}", "launched_from": "", "lines": [{}],
+                        "selected": {{"name": "{2}", "path": "{2}"}}}}"#,
+                    query, lines, selected
+                ))
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let default_score = evaluate(&MatcherConfig::default(), &queries).unwrap();
+        let (config, score) = tune(&queries).unwrap();
+        assert!(score >= default_score);
+        assert_eq!(evaluate(&config, &queries).unwrap(), score);
+        assert_eq!(tune(&queries).unwrap(), (config, score));
+    }
+
+    #[test]
+    fn match_accepts_float_num_matches() {
+        let lines = Value::from(
+            (0..12)
+                .map(|i| {
+                    let path = format!("src/matcher{}.rs", i);
+                    Value::Map(vec![
+                        (Value::from("path"), Value::from(path.as_str())),
+                        (Value::from("name"), Value::from(path.as_str())),
+                    ])
+                })
+                .collect::<Vec<_>>(),
+        );
+        let mut handler = EventHandler::new().unwrap();
+        let result = handler
+            .handle_request(
+                "match",
+                match_args_with_num_matches(lines.clone(), Value::from(10.0)),
+            )
+            .unwrap();
+        assert_eq!(result.as_array().unwrap().len(), 10);
+
+        let err = handler
+            .handle_request(
+                "match",
+                match_args_with_num_matches(lines, Value::from(-1.0)),
+            )
+            .unwrap_err();
+        assert!(err.as_str().unwrap().contains("not a nonnegative number"));
+    }
+}
@@ -1,12 +1,28 @@
+#[cfg(not(feature = "fuzzy-matcher"))]
+use crate::fuzzy_fallback::{FuzzyMatcher, SkimMatcherV2, SkimScoreConfig};
 use anyhow::{anyhow, Result};
 use binary_heap_plus::*;
+use crossbeam_channel::Sender;
+#[cfg(feature = "fuzzy-matcher")]
 use fuzzy_matcher::skim::{SkimMatcherV2, SkimScoreConfig};
+#[cfg(feature = "fuzzy-matcher")]
 use fuzzy_matcher::FuzzyMatcher;
-use itertools::process_results;
+use globset::{Glob, GlobMatcher, GlobSet, GlobSetBuilder};
 use itertools::Itertools;
 use lru::LruCache;
 use neovim_lib::Value;
-use strsim::normalized_levenshtein;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::cmp::Reverse;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use strsim::{normalized_levenshtein, osa_distance};
 
 pub fn lookup<'a>(val: &'a Value, key: &str) -> Result<&'a Value> {
     let map: &Vec<(Value, Value)> =
@@ -28,193 +44,2539 @@ pub fn lookup<'a>(val: &'a Value, key: &str) -> Result<&'a Value> {
 pub trait Line {
     fn path(&self) -> &str;
     fn line(&self) -> &str;
+
+    /// The line with the matcher's preprocess hook already applied, for providers that reuse
+    /// the same lines across many queries and want to do that work once. When this returns
+    /// `Some`, the matcher uses it in place of running the hook on `line`, so it must be exactly
+    /// what the hook would return for `line` (or `line` itself if no hook is set), otherwise
+    /// scores will differ from the uncached path.
+    fn normalized(&self) -> Option<&str> {
+        None
+    }
+
+    /// A score term the matcher can't compute itself, like how recently the file was changed in
+    /// git. It is multiplied by `MatcherConfig::extra_score_weight` and added to the score of
+    /// lines that match. Called once per line for every query, so it should be cheap.
+    fn extra_score(&self) -> f64 {
+        0.
+    }
+}
+
+impl<T: Line + ?Sized> Line for &T {
+    fn path(&self) -> &str {
+        (**self).path()
+    }
+
+    fn line(&self) -> &str {
+        (**self).line()
+    }
+
+    fn normalized(&self) -> Option<&str> {
+        (**self).normalized()
+    }
+
+    fn extra_score(&self) -> f64 {
+        (**self).extra_score()
+    }
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub struct OwnedLine {
-    pub path: String,
-    pub line: String,
-}
+#[derive(Debug, Clone, PartialEq)]
+pub struct OwnedLine {
+    pub path: String,
+    pub line: String,
+}
+
+impl Line for OwnedLine {
+    fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn line(&self) -> &str {
+        &self.line
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Match {
+    /// Position of the line in the lines being matched. In incremental mode this changes when
+    /// lines before it are removed with `remove_lines`.
+    pub index: usize,
+    pub score: f64,
+    pub context_score: f64,
+    /// `raw_query_score` times `MatcherConfig::query_weight`.
+    pub query_score: f64,
+    /// How well the query matched, before `MatcherConfig::query_weight`: the fuzzy score per
+    /// query byte, plus the basename's when it matched too, and any query related bonuses and
+    /// penalties.
+    pub raw_query_score: f64,
+    pub frequency_score: f64,
+    /// `MatcherConfig::open_boost` if the line's path is open, plus
+    /// `MatcherConfig::current_file_sibling_boost` if it is next to the current file.
+    pub open_score: f64,
+    /// Hash of the line's path and text, for ordering matches with equal scores. Unlike
+    /// `index`, it is the same for a line across queries, `feed_lines` and `remove_lines`. It
+    /// fits in 53 bits so it survives conversion to a Lua or JSON number.
+    pub stable_key: u64,
+    /// Whether the query also matched the part of the text after its last `/`, which adds to
+    /// the score. Highlights are then best placed in the basename rather than wherever the match
+    /// against the whole text landed. The basename keeps its leading `/`, so a query with a slash
+    /// only matches it if the slash is the query's first character.
+    pub matched_basename: bool,
+}
+
+/// See `Match::stable_key`. This is FxHash rather than `DefaultHasher`, whose algorithm may
+/// change between Rust releases, so keys saved by a caller stay valid.
+pub fn stable_key(path: &str, line: &str) -> u64 {
+    // 0xff never occurs in UTF-8, so it separates the path from the line
+    let hash = path
+        .bytes()
+        .chain(std::iter::once(0xff))
+        .chain(line.bytes())
+        .fold(0u64, |hash, byte| {
+            (hash.rotate_left(5) ^ u64::from(byte)).wrapping_mul(0x51_7c_c1_b7_27_22_0a_95)
+        });
+    // The multiplication mixes into the high bits
+    hash >> 11
+}
+
+impl Match {
+    /// Compare two result lists, e.g. before and after a keystroke, so a UI can animate the
+    /// change instead of redrawing. Matches are identified by `index`, so both lists must be for
+    /// the same lines.
+    pub fn diff(old: &[Match], new: &[Match]) -> ResultDiff {
+        let old_positions = old
+            .iter()
+            .enumerate()
+            .map(|(i, m)| (m.index, i))
+            .collect::<HashMap<_, _>>();
+        let new_indices = new.iter().map(|m| m.index).collect::<HashSet<_>>();
+        let mut diff = ResultDiff::default();
+        for (position, m) in new.iter().enumerate() {
+            match old_positions.get(&m.index) {
+                None => diff.added.push(m.index),
+                Some(&old_position) if old_position != position => diff.moved.push(m.index),
+                Some(_) => (),
+            }
+        }
+        diff.removed = old
+            .iter()
+            .map(|m| m.index)
+            .filter(|i| !new_indices.contains(i))
+            .collect();
+        diff
+    }
+}
+
+/// The result of `Match::diff`, as `Match::index`es.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResultDiff {
+    /// In the new results but not the old ones, in their new order.
+    pub added: Vec<usize>,
+    /// In the old results but not the new ones, in their old order.
+    pub removed: Vec<usize>,
+    /// In both, but at a different position, in their new order. A result that keeps its place
+    /// relative to the others still moves if something above it was added or removed.
+    pub moved: Vec<usize>,
+}
+
+impl Eq for Match {}
+
+/// Hashes only `index`, as the scores are floats. Equal matches have equal indices, so this
+/// agrees with `PartialEq`, and matches for different lines of the same query hash apart.
+impl Hash for Match {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.index.hash(state);
+    }
+}
+
+impl PartialOrd for Match {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// A total order, even for NaN scores, so that which matches are kept and how they are sorted
+/// never depends on the order they were produced in, e.g. from iterating a `HashMap`.
+impl Ord for Match {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.score
+            .total_cmp(&other.score)
+            .then(self.index.cmp(&other.index))
+    }
+}
+
+/// Which part of a candidate the query is fuzzy matched against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchTarget {
+    /// Match against the line's text only.
+    Line,
+    /// Match against the line's path only.
+    Path,
+    /// Match against both the line and the path and keep the better of the two query scores. The
+    /// scores are not summed, so a candidate matching in both places is not favoured over one
+    /// that matches equally well in only one of them.
+    Both,
+    /// Match against the path and the line joined by `MatcherConfig::join_separator`, so a
+    /// single query can span both, e.g. `src login` for `fn login` in `src/auth.rs`.
+    Joined,
+}
+
+/// How the query is interpreted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchMode {
+    /// Fuzzy match the query. A query starting with `'`, like `'config`, instead matches lines
+    /// containing the rest of it exactly, ignoring case unless it has uppercase letters. These
+    /// all get the same query score, so they are ranked by frequency and context.
+    ///
+    /// A query starting with `^`, like `^mat`, only matches from the start of the text or of a
+    /// `/` separated component of it, so `^m` matches `src/matcher.rs` and `src/main.rs` but not
+    /// `src/format.rs`. The rest of the query is fuzzy matched from there.
+    ///
+    /// If any word of the query starts with `path:` or `line:`, like `path:src login`, each word
+    /// is matched separately. Prefixed words must match the line's path or text, unprefixed ones
+    /// whichever of the two they match better, and `MatcherConfig::match_target` is ignored.
+    /// Every word has to match, and their scores are summed. Queries without such a word are
+    /// matched as a whole against `match_target`.
+    Fuzzy,
+    /// Treat the query as a glob like `src/**/*.rs`. Matching lines all get the same query score,
+    /// so they are ranked by frequency and context.
+    Glob,
+    /// Split the query on `/` and fuzzy match the segments against the text's `/` separated
+    /// components in order, each starting at the start of a component. The last segment must
+    /// match the last component, earlier segments match earlier components with any number
+    /// skipped in between. `r/s/mat` matches
+    /// `rust/src/matcher.rs` but not `src/rust/matcher.rs`. The segment scores are summed.
+    Components,
+    /// Match lines containing the query's characters in order, ignoring case unless the query
+    /// has uppercase letters, without running the fuzzy matcher. The score is the query's
+    /// length over the text's, so ranking is much cruder than `Fuzzy`, but matching is cheaper.
+    Subsequence,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MatcherConfig {
+    /// Multiplier for the frequency score, which is at most 1 before weighting.
+    pub frequency_weight: f64,
+    /// Multiplier for the query score. See `Match::raw_query_score`.
+    pub query_weight: f64,
+    /// Upper bound on the weighted frequency score, including the directory's share. Frequency
+    /// only counts for lines that match the query at all, and this caps how far it can lift a
+    /// weak match over a strong one. The default of infinity doesn't limit it.
+    pub max_frequency_contribution: f64,
+    pub match_target: MatchTarget,
+    pub match_mode: MatchMode,
+    /// Queries with fewer characters than this only match lines whose text or basename starts
+    /// with the query, instead of being fuzzy matched. The default of 0 always fuzzy matches.
+    pub min_fuzzy_len: usize,
+    /// Subtracted from the score for every byte in a line's path, so that all else being equal
+    /// shallower paths rank higher.
+    pub path_length_penalty: f64,
+    /// Only return the best match for each path.
+    pub group_by_path: bool,
+    /// Placed between the path and the line with `MatchTarget::Joined`. A space lets a space in
+    /// the query step from the path to the line.
+    pub join_separator: String,
+    /// When set, a line the query doesn't fuzzy match still matches if part of it is within this
+    /// many edits of the query. Its query score is at most 1, which is usually, but not always,
+    /// below a fuzzy match's.
+    pub typo_tolerance: Option<u8>,
+    /// Added to the score of lines whose path is in the set given to `Matcher::set_open_set`.
+    /// The default of 0 leaves open files unboosted.
+    pub open_boost: f64,
+    /// Added to the score of lines in the same directory as the file given to
+    /// `Matcher::set_current_file`, other than that file itself.
+    pub current_file_sibling_boost: f64,
+    /// The following are passed through to the fuzzy matcher's scoring, see
+    /// `fuzzy_matcher::skim::SkimScoreConfig`. Bonus for each matched character that starts a
+    /// word after a space or at the start of the line.
+    pub bonus_head: i32,
+    /// Bonus for each matched character that starts a word after a delimiter like `/` or `_`.
+    pub bonus_break: i32,
+    /// Bonus for each matched character that starts a word in camelCase.
+    pub bonus_camel: i32,
+    /// Minimum bonus for each matched character following another matched character.
+    pub bonus_consecutive: i32,
+    /// The bonus for matching the first character of a line is multiplied by this, favouring
+    /// lines that start with the query. With 1 the start of the line is like any other word
+    /// start.
+    pub bonus_first_char_multiplier: i32,
+    /// Matches scoring below this are dropped, even if that leaves fewer than `num_results`.
+    /// The default keeps every match.
+    pub min_score: f64,
+    /// Most lines an `IncrementalMatcher` will hold. Lines fed beyond this are dropped.
+    pub max_lines: Option<usize>,
+    /// Return results worst first instead of best first, for UIs that list them bottom to top.
+    pub ascending: bool,
+    /// Added to the score of the line set with `Matcher::set_previous_top`, so the top result
+    /// only changes between keystrokes when another line beats it by more than this.
+    pub sticky_margin: f64,
+    /// Multiplier for `Line::extra_score`.
+    pub extra_score_weight: f64,
+    /// Selecting a path also counts as selecting its directory. A path's frequency score then
+    /// includes its directory's score times this, so files next to recently selected ones rank
+    /// higher even if they were never selected themselves. The default of 0 turns this off.
+    pub directory_frequency_weight: f64,
+    /// Only match the query against the part of the text after its last `/`, never against the
+    /// directories. Then `src` matches nothing unless a file name contains it.
+    pub basename_only: bool,
+    /// Among results with exactly the same score, alternate between paths instead of listing
+    /// them by index, so a file with many identical lines doesn't crowd out the others.
+    pub diversify: bool,
+    /// Subtracted from a fuzzy match's query score for each character before the first one the
+    /// query matched, so `name` ranks `name_of_thing` above `thing_name`. The default of 0 skips
+    /// finding the matched characters, which is slower than scoring alone.
+    pub position_penalty: f64,
+    /// Added to the query score when the query, if it has no uppercase letters and at most
+    /// `MAX_INITIALISM_LEN` characters, appears in the initials of the text's words, split at
+    /// camelCase humps and after `_`, `-` and `/`. Then `gfm` favours `getFooMeta` over `gofmt`.
+    pub initialism_bonus: f64,
+    /// How many `best_matches` results to keep, so rerunning a query on the same lines, like
+    /// after deleting and retyping a character, returns without scoring anything. The lines
+    /// still have to be hashed to look a query up. Selecting or demoting a path, or any other
+    /// change to the matcher, clears the cache. The default of 0 disables it.
+    pub result_cache_size: usize,
+    /// Most parts a query may be split into when each part is matched on its own: words of a
+    /// query using `path:` or `line:`, or `/` separated segments with `MatchMode::Components`.
+    /// Each part costs a fuzzy match per line, so a longer query, most likely pasted by
+    /// accident, is rejected with an error rather than truncated, which would silently match
+    /// something other than what was typed. Other queries are matched whole and never split.
+    pub max_atoms: usize,
+    /// Ignore diacritics in both the query and the text, so `cafe` matches `café.txt` and the
+    /// other way around. Accented Latin letters match their base letter and combining marks are
+    /// dropped.
+    pub fold_diacritics: bool,
+    /// Added to a fuzzy match's query score when all the characters it matched are in the same
+    /// `/` separated component of the text, so `mat` favours `src/matcher.rs` over
+    /// `src/main/test.rs`. The default of 0 skips finding the matched characters.
+    pub component_alignment_bonus: f64,
+    /// Replace control characters in lines with spaces before scoring, for providers that emit
+    /// raw bytes. These are the characters `char::is_control` is true for: U+0000 to U+001F,
+    /// which includes NUL, tab and newline, and U+007F to U+009F. Each is replaced by one space,
+    /// so highlight positions still line up with the original line, and match indices still
+    /// refer to it. Lines with `Line::normalized` set are used as they are.
+    pub replace_control_chars: bool,
+    /// Added to a fuzzy match's query score when the query appears in the text with exactly its
+    /// case, so with both `Makefile` and `makefile` present, typing `makefile` puts the second
+    /// first. Matching itself stays smart case.
+    pub exact_case_bonus: f64,
+    /// Round each match's `score` to the nearest multiple of this, so scores that differ only by
+    /// floating point rounding, e.g. from summing the same parts in another order, compare
+    /// equal and are ordered by `Match::stable_key` instead. The parts of the score are left
+    /// unrounded. `None` keeps the exact score.
+    pub score_quantum: Option<f64>,
+    /// Remove leading and trailing whitespace from lines before scoring, for providers that pad
+    /// them for alignment. Highlight positions from `Matcher::matched_ranges` are given in the
+    /// untrimmed text, so they line up with the padded display. Like `replace_control_chars`,
+    /// this doesn't apply to lines with `Line::normalized` set.
+    pub trim_candidates: bool,
+}
+
+impl Default for MatcherConfig {
+    fn default() -> Self {
+        MatcherConfig {
+            frequency_weight: 10.,
+            query_weight: 1.,
+            match_target: MatchTarget::Line,
+            match_mode: MatchMode::Fuzzy,
+            min_fuzzy_len: 0,
+            path_length_penalty: 0.,
+            group_by_path: false,
+            join_separator: " ".to_string(),
+            typo_tolerance: None,
+            open_boost: 0.,
+            current_file_sibling_boost: 1.,
+            bonus_head: 8,
+            bonus_break: 7,
+            bonus_camel: 6,
+            bonus_consecutive: 4,
+            bonus_first_char_multiplier: 2,
+            min_score: f64::NEG_INFINITY,
+            max_frequency_contribution: f64::INFINITY,
+            max_lines: None,
+            ascending: false,
+            sticky_margin: 0.,
+            extra_score_weight: 1.,
+            directory_frequency_weight: 0.,
+            basename_only: false,
+            diversify: false,
+            position_penalty: 0.,
+            initialism_bonus: 0.,
+            result_cache_size: 0,
+            max_atoms: 32,
+            fold_diacritics: false,
+            component_alignment_bonus: 0.,
+            replace_control_chars: false,
+            exact_case_bonus: 0.,
+            score_quantum: None,
+            trim_candidates: false,
+        }
+    }
+}
+
+/// A path in the selection history, see `Matcher::frequency_entries`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FrequencyEntry {
+    pub path: String,
+    /// When the path was last selected. Larger is more recent.
+    pub count: usize,
+}
+
+/// A query compiled once and shared across all the lines it is scored against. Build one with
+/// `Matcher::pattern`.
+pub struct Pattern<'q> {
+    query: &'q str,
+    glob: Option<Arc<GlobMatcher>>,
+    /// Whether `query` must appear in the text as is, see `MatchMode::Fuzzy`.
+    exact: bool,
+    /// Whether `query` must match from the start of a `/` separated component, see
+    /// `MatchMode::Fuzzy`.
+    component_start: bool,
+    /// The words of a query using `path:` or `line:`, otherwise empty. See `MatchMode::Fuzzy`.
+    fields: Vec<(Option<Field>, &'q str)>,
+}
+
+/// Which part of a line a word prefixed with `path:` or `line:` is matched against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Path,
+    Line,
+}
+
+/// What scoring needs to know about a line besides its text.
+struct Candidate<'a> {
+    index: usize,
+    path: &'a str,
+    /// The line's text before preprocessing, for `Match::stable_key`. The key is only hashed
+    /// for lines that match.
+    line: &'a str,
+    /// `Line::extra_score`, before weighting.
+    extra_score: f64,
+}
+
+/// Transforms a candidate line before it is scored. See `Matcher::set_preprocess`.
+pub type Preprocess = Box<dyn for<'a> Fn(&'a str) -> Cow<'a, str> + Send + Sync>;
+
+/// What a custom scorer gets to rank a line with. See `Matcher::set_scorer`.
+pub struct ScoreInputs<'a> {
+    /// The query with leading and trailing whitespace removed. Empty when the finder was just
+    /// opened.
+    pub query: &'a str,
+    pub context: &'a str,
+    /// Position of the line in the lines being matched, to put in the returned `Match`.
+    pub index: usize,
+    /// The line as given, without the preprocess hook applied.
+    pub line: &'a str,
+    pub path: &'a str,
+    /// The weighted selection history score the built-in scorer would use.
+    pub frequency_score: f64,
+}
+
+/// Replaces the built-in scoring of a line. Returns `None` if the line doesn't match. See
+/// `Matcher::set_scorer`.
+pub type Scorer = Box<dyn Fn(&ScoreInputs) -> Option<Match> + Send + Sync>;
+
+pub struct Matcher {
+    config: MatcherConfig,
+    preprocess: Option<Preprocess>,
+    scorer: Option<Scorer>,
+    frequency: FrequencyCounter,
+    /// Selection history of the directories containing selected paths.
+    directory_frequency: FrequencyCounter,
+    /// Paths currently open in the editor.
+    open_set: HashSet<String>,
+    /// The file being edited, see `set_current_file`.
+    current_file: Option<String>,
+    /// Paths matching these never match, see `set_exclude_globs`.
+    excludes: GlobSet,
+    skim_matcher: SkimMatcherV2,
+    /// `Match::stable_key` of the previous query's top result, see `set_previous_top`.
+    previous_top: Option<u64>,
+    /// Compiled globs by query, so retyping a query doesn't recompile it.
+    glob_cache: Mutex<LruCache<String, Arc<GlobMatcher>>>,
+    /// Results of recent `best_matches` calls, see `MatcherConfig::result_cache_size`.
+    result_cache: Mutex<LruCache<ResultKey, Vec<Match>>>,
+    /// Time to score one query character against one line character, see `estimate_cost`.
+    nanos_per_cell: f64,
+}
+
+/// A `best_matches` call's query, context, number of results and `lines_hash` of its lines.
+type ResultKey = (String, String, u64, u64);
+
+impl Matcher {
+    pub fn new() -> Result<Self> {
+        Self::with_config(MatcherConfig::default())
+    }
+
+    pub fn with_config(config: MatcherConfig) -> Result<Self> {
+        Ok(Matcher {
+            skim_matcher: skim_matcher(&config),
+            preprocess: None,
+            scorer: None,
+            frequency: FrequencyCounter::new()?,
+            directory_frequency: FrequencyCounter::new()?,
+            open_set: HashSet::new(),
+            current_file: None,
+            excludes: GlobSet::empty(),
+            previous_top: None,
+            glob_cache: Mutex::new(LruCache::new(32)),
+            result_cache: Mutex::new(LruCache::new(config.result_cache_size.max(1))),
+            nanos_per_cell: DEFAULT_NANOS_PER_CELL,
+            config,
+        })
+    }
+
+    pub fn update(&mut self, entry: &str) {
+        self.frequency.update(entry);
+        self.directory_frequency.update(directory(entry));
+        self.clear_result_cache();
+    }
+
+    /// Rank `entry` below paths that were never selected, e.g. because the user dismissed it.
+    /// Demotion doesn't decay, it lasts until `entry` is selected with `update` again, and is
+    /// saved by `to_bytes`. Only the most recent `MAX_DEMOTED` demotions are kept.
+    pub fn demote(&mut self, entry: &str) {
+        self.frequency.demote(entry);
+        self.clear_result_cache();
+    }
+
+    /// Forget cached results after a change that affects scores.
+    fn clear_result_cache(&self) {
+        self.result_cache.lock().unwrap().clear();
+    }
+
+    /// The config in use, as last set by `with_config` or `set_config`.
+    pub fn config(&self) -> &MatcherConfig {
+        &self.config
+    }
+
+    /// Replace the config. Takes effect on the next call to `score` or `best_matches`.
+    pub fn set_config(&mut self, config: MatcherConfig) {
+        // The skim matcher keeps scratch buffers between calls, which a new one would have to
+        // grow again, so only replace it if its scoring changed
+        if skim_score_config(&config) != skim_score_config(&self.config) {
+            self.skim_matcher = skim_matcher(&config);
+        }
+        *self.result_cache.lock().unwrap() = LruCache::new(config.result_cache_size.max(1));
+        self.config = config;
+        self.glob_cache.lock().unwrap().clear();
+    }
+
+    /// Replace the set of paths open in the editor, which get `MatcherConfig::open_boost`. Unlike
+    /// the selection history this is not saved by `to_bytes`.
+    pub fn set_open_set(&mut self, paths: HashSet<String>) {
+        self.open_set = paths;
+        self.clear_result_cache();
+    }
+
+    /// Set the file being edited, whose siblings get `MatcherConfig::current_file_sibling_boost`.
+    /// This is separate from the context passed with each query, which is where the finder was
+    /// launched from and is scored by path similarity rather than by sharing a directory. Like
+    /// the open set, it is not saved by `to_bytes`.
+    pub fn set_current_file(&mut self, path: Option<String>) {
+        self.current_file = path;
+        self.clear_result_cache();
+    }
+
+    /// Drop lines whose path matches any of `globs`, like `*.min.js` or `node_modules/**`,
+    /// whatever the query. The globs are matched against the whole path, and `*` also matches
+    /// `/`. Replaces any earlier exclusions, an empty list excludes nothing. Fails without
+    /// changing anything if a glob is invalid.
+    pub fn set_exclude_globs(&mut self, globs: &[&str]) -> Result<()> {
+        self.set_exclude_set(exclude_set(globs)?);
+        Ok(())
+    }
+
+    pub(crate) fn set_exclude_set(&mut self, excludes: GlobSet) {
+        self.excludes = excludes;
+        self.clear_result_cache();
+    }
+
+    fn excluded(&self, path: &str) -> bool {
+        !self.excludes.is_empty() && self.excludes.is_match(path)
+    }
+
+    /// Remember the `Match::stable_key` of the top result shown for the previous query. While it
+    /// keeps matching, that line gets `MatcherConfig::sticky_margin` added to its score, so the
+    /// top result doesn't flicker between lines with nearly equal scores as the user types. Pass
+    /// `None` when starting a new search.
+    pub fn set_previous_top(&mut self, stable_key: Option<u64>) {
+        self.previous_top = stable_key;
+        self.clear_result_cache();
+    }
+
+    /// Score a throwaway line so the fuzzy matcher allocates its buffers now instead of on the
+    /// first real query. This is purely a latency optimization and doesn't change any results.
+    /// The buffers are per thread, so call this on the thread that will do the matching.
+    pub fn warmup(&self) {
+        let line = "src/warmup/matcher_warmup.rs";
+        self.score("warmup", "", 0, line, line);
+    }
+
+    /// Roughly how long `best_matches` will take for a query of `query_len` characters against
+    /// `line_count` lines averaging `avg_line_len` characters, e.g. to debounce harder when
+    /// queries are slow. Fuzzy matching takes time proportional to the query length times the
+    /// line length, and the time per character pair is a built in estimate until `calibrate`
+    /// measures it on this machine. This ignores how many lines actually match, so treat it as
+    /// an order of magnitude.
+    pub fn estimate_cost(
+        &self,
+        query_len: usize,
+        line_count: usize,
+        avg_line_len: usize,
+    ) -> Duration {
+        let cells = query_len.max(1) as f64 * line_count as f64 * avg_line_len as f64;
+        Duration::from_secs_f64(cells * self.nanos_per_cell / 1e9)
+    }
+
+    /// Measure how fast this machine scores lines, for `estimate_cost`. Takes a few
+    /// milliseconds, so call it once at startup, on a thread that isn't busy with anything else.
+    pub fn calibrate(&mut self) {
+        let lines = (0..CALIBRATION_LINES)
+            .map(|i| format!("src/module{}/sub{}/file{}.rs", i % 17, i % 5, i))
+            .collect::<Vec<_>>();
+        let query = "mdfile";
+        let pattern = match self.pattern(query) {
+            Ok(pattern) => pattern,
+            Err(_) => return,
+        };
+        let cells = (query.len() * lines.iter().map(|l| l.len()).sum::<usize>()) as f64;
+        // The fastest of a few runs is the least disturbed by other work. Lines are scored one
+        // by one rather than with `best_matches` so the result cache can't answer.
+        let fastest = (0..3)
+            .map(|_| {
+                let start = Instant::now();
+                for (i, line) in lines.iter().enumerate() {
+                    self.score_pattern(&pattern, "", i, line, line);
+                }
+                start.elapsed()
+            })
+            .min()
+            .unwrap();
+        self.nanos_per_cell = (fastest.as_nanos() as f64).max(1.) / cells;
+    }
+
+    /// Query characters times line characters scored per second, as used by `estimate_cost`.
+    pub fn scoring_throughput(&self) -> f64 {
+        1e9 / self.nanos_per_cell
+    }
+
+    /// Transform each candidate line before it is scored, e.g. to strip a `lineno:` prefix.
+    /// Returned match indices still refer to the original lines. The hook is called once per
+    /// line for every query, so it should be cheap and return `Cow::Borrowed` when it doesn't
+    /// change the line to avoid allocating.
+    pub fn set_preprocess(&mut self, preprocess: Option<Preprocess>) {
+        self.preprocess = preprocess;
+        self.clear_result_cache();
+    }
+
+    /// Replace the whole scoring formula, for experimenting with ranking without changing the
+    /// matcher. The scorer is called for every line that isn't excluded, instead of the fuzzy
+    /// matcher, context and boosts, so it has to decide itself whether the line matches the
+    /// query. `MatcherConfig::min_score`, grouping and sorting still apply to what it returns.
+    ///
+    /// This is a virtual call per line that can't be inlined, and the frequency score is looked
+    /// up for every line whether or not the scorer uses it, so expect it to be slower than the
+    /// built-in scoring even for a trivial formula. `None` goes back to the built-in scoring.
+    pub fn set_scorer(&mut self, scorer: Option<Scorer>) {
+        self.scorer = scorer;
+        self.clear_result_cache();
+    }
+
+    /// The paths in the selection history with their frequency scores before
+    /// `MatcherConfig::frequency_weight`, most recently selected first, e.g. for a recently
+    /// used menu. Demoted paths score -1.
+    pub fn frequent_paths(&self) -> Vec<(String, f64)> {
+        self.frequency.scored_paths()
+    }
+
+    /// The selection history as a list an editor plugin can save itself, least recently
+    /// selected first. Unlike `to_bytes` this leaves out demotions and the config.
+    pub fn frequency_entries(&self) -> Vec<FrequencyEntry> {
+        self.frequency
+            .snapshot()
+            .entries
+            .into_iter()
+            .map(|(path, count)| FrequencyEntry { path, count })
+            .collect()
+    }
+
+    /// Select each path in `entries` once, in increasing order of `count`, so the path with the
+    /// highest count ends up the most recently selected. The counts only order the entries, so
+    /// they can be anything from `frequency_entries` to how often each path was opened. Entries
+    /// are added on top of the existing history.
+    pub fn load_frequency(&mut self, entries: &[FrequencyEntry]) {
+        for entry in entries.iter().sorted_by_key(|e| e.count) {
+            self.update(&entry.path);
+        }
+    }
+
+    /// Serialize the config and frequency history so they can be restored with `from_bytes`.
+    /// The bytes start with `STATE_VERSION`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = STATE_VERSION.to_le_bytes().to_vec();
+        bincode::serialize_into(
+            &mut bytes,
+            &MatcherState {
+                config: self.config.clone(),
+                frequency: self.frequency.snapshot(),
+                directory_frequency: self.directory_frequency.snapshot(),
+            },
+        )
+        .expect("Matcher state is always serializable");
+        bytes
+    }
+
+    /// Restore a matcher saved with `to_bytes`. The fuzzy matcher itself is rebuilt from the
+    /// stored config. The preprocess hook is not saved. Fails if the bytes were saved by a
+    /// version with a different `STATE_VERSION`, since bincode can't tell what changed.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let version = bytes
+            .get(..4)
+            .map(|v| u32::from_le_bytes([v[0], v[1], v[2], v[3]]))
+            .ok_or(anyhow!("Matcher state is too short to have a version"))?;
+        if version != STATE_VERSION {
+            return Err(anyhow!(
+                "Matcher state has version {}, but this version of sylph reads version {}",
+                version,
+                STATE_VERSION
+            ));
+        }
+        let state: MatcherState = bincode::deserialize(&bytes[4..])?;
+        let mut matcher = Self::with_config(state.config)?;
+        matcher.frequency = FrequencyCounter::from_snapshot(state.frequency)?;
+        matcher.directory_frequency = FrequencyCounter::from_snapshot(state.directory_frequency)?;
+        Ok(matcher)
+    }
+
+    pub fn score(
+        &self,
+        query: &str,
+        context: &str,
+        index: usize,
+        line: &str,
+        path: &str,
+    ) -> Option<Match> {
+        let pattern = self.pattern(query).ok()?;
+        self.score_pattern(&pattern, context, index, line, path)
+    }
+
+    /// Compile `query` for use with `score_pattern`. Fails if the query is not valid for the
+    /// configured `MatchMode`. Leading and trailing whitespace is ignored, so an all whitespace
+    /// query behaves like an empty one. Whitespace between words is kept.
+    pub fn pattern<'q>(&self, query: &'q str) -> Result<Pattern<'q>> {
+        let query = query.trim();
+        if self.config.match_mode == MatchMode::Fuzzy {
+            if let Some(exact) = query.strip_prefix('\'').filter(|q| !q.is_empty()) {
+                return Ok(Pattern {
+                    query: exact,
+                    glob: None,
+                    exact: true,
+                    component_start: false,
+                    fields: Vec::new(),
+                });
+            }
+            if let Some(anchored) = query.strip_prefix('^').filter(|q| !q.is_empty()) {
+                return Ok(Pattern {
+                    query: anchored,
+                    glob: None,
+                    exact: false,
+                    component_start: true,
+                    fields: Vec::new(),
+                });
+            }
+            let fields = query
+                .split_whitespace()
+                .map(|word| {
+                    if let Some(word) = word.strip_prefix("path:") {
+                        (Some(Field::Path), word)
+                    } else if let Some(word) = word.strip_prefix("line:") {
+                        (Some(Field::Line), word)
+                    } else {
+                        (None, word)
+                    }
+                })
+                .filter(|(_, word)| !word.is_empty())
+                .collect::<Vec<_>>();
+            if fields.iter().any(|(field, _)| field.is_some()) {
+                self.check_atoms(fields.len())?;
+                return Ok(Pattern {
+                    query,
+                    glob: None,
+                    exact: false,
+                    component_start: false,
+                    fields,
+                });
+            }
+        }
+        if self.config.match_mode == MatchMode::Components {
+            self.check_atoms(query.split('/').filter(|s| !s.is_empty()).count())?;
+        }
+        let glob = match self.config.match_mode {
+            MatchMode::Glob if !query.is_empty() => {
+                let mut cache = self.glob_cache.lock().unwrap();
+                Some(match cache.get(&query.to_string()) {
+                    Some(glob) => glob.clone(),
+                    None => {
+                        let glob = Arc::new(Glob::new(query)?.compile_matcher());
+                        cache.put(query.to_string(), glob.clone());
+                        glob
+                    }
+                })
+            }
+            _ => None,
+        };
+        Ok(Pattern {
+            query,
+            glob,
+            exact: false,
+            component_start: false,
+            fields: Vec::new(),
+        })
+    }
+
+    /// Like `score`, but with a query already compiled by `pattern`. Use this when scoring many
+    /// lines against the same query.
+    pub fn score_pattern(
+        &self,
+        pattern: &Pattern,
+        context: &str,
+        index: usize,
+        line: &str,
+        path: &str,
+    ) -> Option<Match> {
+        if self.excluded(path) {
+            return None;
+        }
+        let candidate = Candidate {
+            index,
+            path,
+            line,
+            extra_score: 0.,
+        };
+        if let Some(scorer) = &self.scorer {
+            return self.above_min_score(self.custom_score(
+                scorer,
+                pattern.query,
+                context,
+                line,
+                &candidate,
+            ));
+        }
+        if pattern.query.is_empty() {
+            return self.above_min_score(self.empty_query_score(context, line, &candidate));
+        }
+        self.above_min_score(self.score_preprocessed(
+            pattern,
+            context,
+            &self.preprocessed(line),
+            &candidate,
+        ))
+    }
+
+    /// `score_pattern` for a nonempty query and a line the preprocess hook has already been
+    /// applied to.
+    fn score_preprocessed(
+        &self,
+        pattern: &Pattern,
+        context: &str,
+        line: &str,
+        candidate: &Candidate,
+    ) -> Option<Match> {
+        let context_score = context_score(pattern.query, line, context);
+        let text_score =
+            self.target_score(pattern, self.config.match_target, line, candidate.path)?;
+        let mut mtch = self.new_match(
+            candidate,
+            context_score,
+            text_score.score,
+            text_score.matched,
+        );
+        mtch.matched_basename = text_score.matched_basename;
+        Some(mtch)
+    }
+
+    /// Match `pattern` against the part of a line picked by `target`.
+    fn target_score(
+        &self,
+        pattern: &Pattern,
+        target: MatchTarget,
+        line: &str,
+        path: &str,
+    ) -> Option<TextScore> {
+        Some(match target {
+            _ if !pattern.fields.is_empty() => self.fields_score(&pattern.fields, line, path)?,
+            MatchTarget::Line => self.text_score(pattern, line)?,
+            MatchTarget::Path => self.text_score(pattern, path)?,
+            MatchTarget::Both => {
+                match (
+                    self.text_score(pattern, line),
+                    self.text_score(pattern, path),
+                ) {
+                    (Some(l), Some(p)) => {
+                        if l.score >= p.score {
+                            l
+                        } else {
+                            p
+                        }
+                    }
+                    (l, p) => l.or(p)?,
+                }
+            }
+            MatchTarget::Joined => self.text_score(
+                pattern,
+                &format!("{}{}{}", path, self.config.join_separator, line),
+            )?,
+        })
+    }
+
+    /// Fail if a query split into `atoms` parts that are each matched separately has more than
+    /// `MatcherConfig::max_atoms`.
+    fn check_atoms(&self, atoms: usize) -> Result<()> {
+        if atoms > self.config.max_atoms {
+            Err(anyhow!(
+                "Query has {} parts, more than the maximum of {}",
+                atoms,
+                self.config.max_atoms
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Score the words of a query using `path:` or `line:`, see `MatchMode::Fuzzy`.
+    fn fields_score(
+        &self,
+        fields: &[(Option<Field>, &str)],
+        line: &str,
+        path: &str,
+    ) -> Option<TextScore> {
+        let mut total = TextScore::whole(0.);
+        for &(field, word) in fields {
+            let pattern = Pattern {
+                query: word,
+                glob: None,
+                exact: false,
+                component_start: false,
+                fields: Vec::new(),
+            };
+            let score = match field {
+                Some(Field::Path) => self.text_score(&pattern, path)?,
+                Some(Field::Line) => self.text_score(&pattern, line)?,
+                None => match (
+                    self.text_score(&pattern, line),
+                    self.text_score(&pattern, path),
+                ) {
+                    (Some(l), Some(p)) if l.score >= p.score => l,
+                    (Some(_), Some(p)) => p,
+                    (l, p) => l.or(p)?,
+                },
+            };
+            total.score += score.score;
+            total.matched_basename |= score.matched_basename;
+            total.matched |= score.matched;
+        }
+        Some(total)
+    }
+
+    /// `score_pattern` for the empty query, where only frequency and context matter. Nothing is
+    /// matched against the query, and the preprocess hook only runs if there is a context.
+    fn empty_query_score(&self, context: &str, line: &str, candidate: &Candidate) -> Option<Match> {
+        let context_score = if context.is_empty() {
+            0.
+        } else {
+            context_score("", &self.preprocessed(line), context)
+        };
+        Some(self.new_match(candidate, context_score, 0., true))
+    }
+
+    fn custom_score(
+        &self,
+        scorer: &Scorer,
+        query: &str,
+        context: &str,
+        line: &str,
+        candidate: &Candidate,
+    ) -> Option<Match> {
+        scorer(&ScoreInputs {
+            query,
+            context,
+            index: candidate.index,
+            line,
+            path: candidate.path,
+            frequency_score: self.frequency_score(candidate.path),
+        })
+    }
+
+    fn preprocessed<'a>(&self, line: &'a str) -> Cow<'a, str> {
+        let line = match &self.preprocess {
+            Some(preprocess) => preprocess(line),
+            None => Cow::Borrowed(line),
+        };
+        let line = if self.config.replace_control_chars && line.chars().any(char::is_control) {
+            Cow::Owned(
+                line.chars()
+                    .map(|c| if c.is_control() { ' ' } else { c })
+                    .collect(),
+            )
+        } else {
+            line
+        };
+        match line {
+            _ if !self.config.trim_candidates => line,
+            Cow::Borrowed(line) => Cow::Borrowed(line.trim()),
+            Cow::Owned(line) if line.trim().len() == line.len() => Cow::Owned(line),
+            Cow::Owned(line) => Cow::Owned(line.trim().to_string()),
+        }
+    }
+
+    /// The weighted selection history score of `path`, including its directory's.
+    fn frequency_score(&self, path: &str) -> f64 {
+        let directory_score = if self.config.directory_frequency_weight == 0. {
+            0.
+        } else {
+            self.directory_frequency.score(directory(path)) * self.config.directory_frequency_weight
+        };
+        ((self.frequency.score(path) + directory_score) * self.config.frequency_weight)
+            .min(self.config.max_frequency_contribution)
+    }
+
+    /// Combine the scores for a line. `matched` is whether the line actually matched the query,
+    /// only then do the frequency, open set, previous top and `Line::extra_score` boost it.
+    /// Otherwise a frequently selected or open file would show up for unrelated queries.
+    fn new_match(
+        &self,
+        candidate: &Candidate,
+        context_score: f64,
+        raw_query_score: f64,
+        matched: bool,
+    ) -> Match {
+        let query_score = raw_query_score * self.config.query_weight;
+        let Candidate {
+            index,
+            path,
+            line,
+            extra_score,
+        } = *candidate;
+        let stable_key = stable_key(path, line);
+        let (frequency_score, open_score, sticky_score, extra_score) = if matched {
+            (
+                self.frequency_score(path),
+                self.open_score(path),
+                if self.previous_top == Some(stable_key) {
+                    self.config.sticky_margin
+                } else {
+                    0.
+                },
+                extra_score * self.config.extra_score_weight,
+            )
+        } else {
+            (0., 0., 0., 0.)
+        };
+        let score =
+            frequency_score + context_score + query_score + open_score + sticky_score + extra_score
+                - self.config.path_length_penalty * path.len() as f64;
+        let score = match self.config.score_quantum {
+            Some(quantum) if quantum > 0. => (score / quantum).round() * quantum,
+            _ => score,
+        };
+        Match {
+            index,
+            score,
+            context_score,
+            frequency_score,
+            open_score,
+            query_score,
+            raw_query_score,
+            stable_key,
+            matched_basename: false,
+        }
+    }
+
+    /// `MatcherConfig::open_boost` if `path` is open, plus its `sibling_score`.
+    fn open_score(&self, path: &str) -> f64 {
+        let open = if self.open_set.contains(path) {
+            self.config.open_boost
+        } else {
+            0.
+        };
+        open + self.sibling_score(path)
+    }
+
+    /// An upper bound on the score of any line for `pattern` and `context`, less the parts that
+    /// `below_score` bounds for each line, or `None` if there is no cheap bound. This is
+    /// what lets `best_matches` skip fuzzy matching a line that can't beat its current results.
+    ///
+    /// Only plain fuzzy queries have a bound. The skim matcher adds, for each query character,
+    /// the match score plus at most the largest of the consecutive bonus and the in place
+    /// bonuses, which the first character of the text has multiplied. Its score divided by the
+    /// query's length in bytes, which is at least its number of characters, is therefore at
+    /// most that per character maximum, and the query score adds at most two of those, for the
+    /// whole text and for the basename, plus any positive bonuses. The position penalty only
+    /// lowers the score when it isn't negative.
+    fn query_score_bound(&self, pattern: Option<&Pattern>, context: &str) -> Option<f64> {
+        let pattern = pattern.filter(|p| !p.query.is_empty())?;
+        let config = &self.config;
+        if config.match_mode != MatchMode::Fuzzy
+            || self.scorer.is_some()
+            || pattern.exact
+            || pattern.glob.is_some()
+            || !pattern.fields.is_empty()
+            || config.typo_tolerance.is_some_and(|edits| edits > 0)
+            || config.position_penalty < 0.
+            || config.query_weight < 0.
+        {
+            return None;
+        }
+        let text_bound = if pattern.query.chars().count() < config.min_fuzzy_len {
+            // `prefix_score`
+            config.query_weight
+        } else {
+            let in_place = config
+                .bonus_head
+                .max(config.bonus_break)
+                .max(config.bonus_camel)
+                .max(0)
+                * config.bonus_first_char_multiplier.max(1);
+            let per_char = SkimScoreConfig::default().score_match
+                + in_place.max(config.bonus_consecutive).max(0);
+            (2. * per_char as f64
+                + config.component_alignment_bonus.max(0.)
+                + config.initialism_bonus.max(0.)
+                + config.exact_case_bonus.max(0.))
+                * config.query_weight
+        };
+        let context_bound = if context.is_empty() {
+            0.
+        } else {
+            max_context_score(pattern.query)
+        };
+        Some(text_bound + context_bound)
+    }
+
+    /// Whether `line` certainly scores below `threshold`, given a `query_score_bound` of
+    /// `query_bound`. Each boost is counted if it is positive, whether or not it would apply,
+    /// and score rounding can add up to half of `MatcherConfig::score_quantum`. The boosts that
+    /// need lookups are only added if the others leave the bound below `threshold`.
+    fn below_score<L: Line>(&self, line: &L, query_bound: f64, threshold: f64) -> bool {
+        let path = line.path();
+        let sticky = if self.previous_top.is_some() {
+            self.config.sticky_margin.max(0.)
+        } else {
+            0.
+        };
+        let quantum = self.config.score_quantum.map_or(0., |q| q.max(0.) / 2.);
+        let bound = query_bound
+            + sticky
+            + (line.extra_score() * self.config.extra_score_weight).max(0.)
+            - self.config.path_length_penalty * path.len() as f64
+            + quantum
+            // The actual score's terms are summed in another order, which can round differently
+            + 1e-9 * (1. + query_bound.abs());
+        bound < threshold
+            && bound + self.frequency_score(path).max(0.) + self.open_score(path).max(0.)
+                < threshold
+    }
+
+    /// `MatcherConfig::current_file_sibling_boost` if `path` is next to the current file.
+    fn sibling_score(&self, path: &str) -> f64 {
+        match &self.current_file {
+            Some(current) if current != path && directory(current) == directory(path) => {
+                self.config.current_file_sibling_boost
+            }
+            _ => 0.,
+        }
+    }
+
+    /// Drops `mtch` if its score is below `MatcherConfig::min_score`.
+    fn above_min_score(&self, mtch: Option<Match>) -> Option<Match> {
+        mtch.filter(|m| m.score >= self.config.min_score)
+    }
+
+    fn text_score(&self, pattern: &Pattern, text: &str) -> Option<TextScore> {
+        if self.config.fold_diacritics && !(text.is_ascii() && pattern.query.is_ascii()) {
+            let query = fold_diacritics(pattern.query);
+            let folded = Pattern {
+                query: &query,
+                glob: pattern.glob.clone(),
+                exact: pattern.exact,
+                component_start: pattern.component_start,
+                fields: Vec::new(),
+            };
+            return self.unfolded_text_score(&folded, &fold_diacritics(text));
+        }
+        self.unfolded_text_score(pattern, text)
+    }
+
+    /// `text_score` ignoring `MatcherConfig::fold_diacritics`.
+    fn unfolded_text_score(&self, pattern: &Pattern, text: &str) -> Option<TextScore> {
+        if self.config.basename_only {
+            let basename = text.rsplit_once('/').map_or(text, |(_, basename)| basename);
+            return self
+                .unrestricted_text_score(pattern, basename)
+                .map(|score| TextScore {
+                    matched_basename: true,
+                    ..score
+                });
+        }
+        self.unrestricted_text_score(pattern, text)
+    }
+
+    /// `text_score` ignoring `MatcherConfig::basename_only`.
+    fn unrestricted_text_score(&self, pattern: &Pattern, text: &str) -> Option<TextScore> {
+        match &pattern.glob {
+            Some(glob) if glob.is_match(text) => Some(TextScore::whole(1.)),
+            Some(_) => None,
+            // A substring search is much cheaper than fuzzy matching, and the score would be
+            // the same for every line anyway
+            None if pattern.exact => {
+                contains_smart_case(text, pattern.query).then(|| TextScore::whole(1.))
+            }
+            None if pattern.component_start => self.component_start_score(pattern.query, text),
+            None if self.config.match_mode == MatchMode::Components => {
+                self.components_score(pattern.query, text)
+            }
+            None if self.config.match_mode == MatchMode::Subsequence => {
+                subsequence_score(pattern.query, text).map(TextScore::whole)
+            }
+            None => self.query_score(pattern.query, text),
+        }
+    }
+
+    /// Scoring for a query starting with `^`, see `MatchMode::Fuzzy`. The query is fuzzy matched
+    /// against the text from each component start where its first character matches, and the
+    /// best of those scores is kept.
+    fn component_start_score(&self, query: &str, text: &str) -> Option<TextScore> {
+        let basename_start = text.rfind('/').map_or(0, |ind| ind + 1);
+        std::iter::once(0)
+            .chain(text.match_indices('/').map(|(ind, _)| ind + 1))
+            .filter(|&start| starts_like(&text[start..], query))
+            .filter_map(|start| {
+                let score = self.skim_matcher.fuzzy_match(&text[start..], query)?;
+                Some(TextScore {
+                    score: score as f64 / query.len() as f64,
+                    matched_basename: start == basename_start,
+                    matched: score > 0,
+                })
+            })
+            .max_by(|x, y| x.score.total_cmp(&y.score))
+    }
+
+    /// Scoring for `MatchMode::Components`. A segment only matches components starting with its
+    /// first character, so segments can't match out of order by landing in the middle of an
+    /// earlier component. Each directory segment takes the first component it matches, which
+    /// leaves the most room for the segments after it.
+    fn components_score(&self, query: &str, text: &str) -> Option<TextScore> {
+        let segment_score = |segment: &str, component: &str| {
+            if !starts_like(component, segment) {
+                return None;
+            }
+            self.skim_matcher
+                .fuzzy_match(component, segment)
+                .map(|x| x as f64 / segment.len() as f64)
+        };
+        let mut segments = query
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>();
+        let last_segment = segments.pop()?;
+        let (dirs, basename) = match text.rfind('/') {
+            Some(ind) => (&text[..ind], &text[ind + 1..]),
+            None => ("", text),
+        };
+        let mut score = segment_score(last_segment, basename)?;
+        let mut components = dirs.split('/');
+        for segment in segments {
+            score += components.find_map(|component| segment_score(segment, component))?;
+        }
+        Some(TextScore {
+            score,
+            matched_basename: true,
+            matched: score > 0.,
+        })
+    }
+
+    fn query_score(&self, query: &str, text: &str) -> Option<TextScore> {
+        if query.chars().count() < self.config.min_fuzzy_len {
+            return prefix_score(query, text);
+        }
+        // Finding the matched positions is slower, so only do it when they're needed
+        let whole_match =
+            if self.config.position_penalty == 0. && self.config.component_alignment_bonus == 0. {
+                self.skim_matcher
+                    .fuzzy_match(text, query)
+                    .map(|s| (s, Vec::new()))
+            } else {
+                self.skim_matcher.fuzzy_indices(text, query)
+            };
+        let (whole_score, matched) = match whole_match {
+            Some((score, positions)) => {
+                let first_position = positions.first().copied().unwrap_or(0);
+                (
+                    score as f64 / query.len() as f64
+                        - self.config.position_penalty * first_position as f64
+                        + self.component_alignment_bonus(text, &positions),
+                    score > 0,
+                )
+            }
+            None => {
+                return match self.config.typo_tolerance {
+                    Some(max_edits) if max_edits > 0 => {
+                        typo_score(query, text, max_edits as usize).map(TextScore::whole)
+                    }
+                    _ => None,
+                }
+            }
+        };
+        let whole_score =
+            whole_score + self.initialism_bonus(query, text) + self.exact_case_bonus(query, text);
+        // Try and find path delimiters
+        let slash = text.rfind('/');
+        let whole = TextScore {
+            score: whole_score,
+            matched_basename: false,
+            matched,
+        };
+        Some(match slash {
+            None => whole,
+            Some(ind) => match self.skim_matcher.fuzzy_match(&text[ind..], query) {
+                Some(x) => TextScore {
+                    score: x as f64 / query.len() as f64 + whole_score,
+                    matched_basename: true,
+                    matched: matched || x > 0,
+                },
+                None => whole,
+            },
+        })
+    }
+
+    /// `MatcherConfig::component_alignment_bonus` if the matched character `positions` of `text`
+    /// all fall between the same two `/`.
+    fn component_alignment_bonus(&self, text: &str, positions: &[usize]) -> f64 {
+        let (first, last) = match (positions.first(), positions.last()) {
+            (Some(&first), Some(&last)) if self.config.component_alignment_bonus != 0. => {
+                (first, last)
+            }
+            _ => return 0.,
+        };
+        // Positions are sorted, so everything in between is in the same component if the first
+        // and last are. A matched `/` starts a new component, so it never aligns.
+        let slashes = text
+            .chars()
+            .take(last + 1)
+            .skip(first)
+            .filter(|&c| c == '/')
+            .count();
+        if slashes == 0 {
+            self.config.component_alignment_bonus
+        } else {
+            0.
+        }
+    }
+
+    /// `MatcherConfig::initialism_bonus` if `query` is short, has no uppercase letters, and
+    /// appears in the initials of `text`'s words.
+    fn initialism_bonus(&self, query: &str, text: &str) -> f64 {
+        if self.config.initialism_bonus == 0.
+            || query.chars().count() > MAX_INITIALISM_LEN
+            || query.chars().any(char::is_uppercase)
+        {
+            return 0.;
+        }
+        if initials(text).contains(query) {
+            self.config.initialism_bonus
+        } else {
+            0.
+        }
+    }
+
+    /// `MatcherConfig::exact_case_bonus` if `query` is a case sensitive substring of `text`.
+    fn exact_case_bonus(&self, query: &str, text: &str) -> f64 {
+        if self.config.exact_case_bonus != 0. && text.contains(query) {
+            self.config.exact_case_bonus
+        } else {
+            0.
+        }
+    }
+
+    /// Find the `num_results` best matches for `query` in `lines`, sorted best first (or worst
+    /// first with `MatcherConfig::ascending`). Returns an error if `num_results` is zero, as
+    /// asking for no results is almost certainly a bug in the caller.
+    pub fn best_matches<L: Line>(
+        &self,
+        query: &str,
+        context: &str,
+        num_results: u64,
+        lines: &[L],
+    ) -> Result<Vec<Match>> {
+        let never = AtomicBool::new(false);
+        self.best_matches_cancellable(query, context, num_results, lines, &never)
+            .map(Option::unwrap_or_default)
+    }
+
+    /// Like `best_matches`, but stops early and returns `Ok(None)` once `cancel` is set. The flag
+    /// is checked every `CANCEL_CHECK_INTERVAL` lines.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "best_matches",
+            skip_all,
+            fields(query = query, num_results = num_results, lines = lines.len())
+        )
+    )]
+    pub fn best_matches_cancellable<L: Line>(
+        &self,
+        query: &str,
+        context: &str,
+        num_results: u64,
+        lines: &[L],
+        cancel: &AtomicBool,
+    ) -> Result<Option<Vec<Match>>> {
+        let cache_key = if self.config.result_cache_size > 0 {
+            let key = (
+                query.to_string(),
+                context.to_string(),
+                num_results,
+                lines_hash(lines),
+            );
+            if let Some(mtchs) = self.result_cache.lock().unwrap().get(&key) {
+                return Ok(Some(mtchs.clone()));
+            }
+            Some(key)
+        } else {
+            None
+        };
+        let num_results = check_num_results(num_results)?;
+        let pattern = self.optional_pattern(query)?;
+        let mtchs = match self.select_matches(pattern.as_ref(), context, num_results, lines, cancel)
+        {
+            Some(mtchs) => mtchs,
+            None => return Ok(None),
+        };
+        let sorted = sorted_matches(mtchs, num_results, &self.config, |i| lines[i].path());
+        if let Some(key) = cache_key {
+            self.result_cache.lock().unwrap().put(key, sorted.clone());
+        }
+        Ok(Some(sorted))
+    }
+
+    /// The matches `best_matches_cancellable` sorts: the best `num_results`, and with
+    /// `MatcherConfig::diversify` every other match tied with the last of them. `None` if
+    /// `cancel` was set.
+    fn select_matches<L: Line>(
+        &self,
+        pattern: Option<&Pattern>,
+        context: &str,
+        num_results: usize,
+        lines: &[L],
+        cancel: &AtomicBool,
+    ) -> Option<BinaryHeap<Match, MinComparator>> {
+        let cancelled = Cell::new(false);
+        let unscored = lines.iter().enumerate().take_while(|(i, _)| {
+            if i % CANCEL_CHECK_INTERVAL == 0 && cancel.load(Ordering::Relaxed) {
+                cancelled.set(true);
+            }
+            !cancelled.get()
+        });
+        let mtchs = if self.config.group_by_path {
+            let scored = unscored.filter_map(|(i, line)| {
+                self.score_line(pattern, context, i, line)
+                    .map(|m| (line.path(), m))
+            });
+            top_matches(
+                best_per_path(scored).map(|(_, m)| m),
+                num_results,
+                self.config.diversify,
+            )
+        } else {
+            let query_bound = self.query_score_bound(pattern, context);
+            let mut entries = BinaryHeap::<Match, MinComparator>::with_capacity_min(
+                num_results.min(MAX_PREALLOCATED_RESULTS),
+            );
+            for (i, line) in unscored {
+                // Once the results are full, a line that can't beat the worst of them doesn't
+                // need to be fuzzy matched. This only happens when boosts lift the results
+                // above what any fuzzy match scores, e.g. open files for a one letter query.
+                if let (Some(bound), Some(smallest)) = (query_bound, entries.peek()) {
+                    if entries.len() >= num_results && self.below_score(line, bound, smallest.score)
+                    {
+                        continue;
+                    }
+                }
+                if let Some(mtch) = self.score_line(pattern, context, i, line) {
+                    push_top(&mut entries, num_results, self.config.diversify, mtch);
+                }
+            }
+            entries
+        };
+        (!cancelled.get()).then_some(mtchs)
+    }
+
+    /// The `num_results` lowest scoring lines that still match `query`, worst first. Useful for
+    /// seeing what barely makes it into the results when tuning scoring.
+    pub fn worst_matches<L: Line>(
+        &self,
+        query: &str,
+        context: &str,
+        num_results: u64,
+        lines: &[L],
+    ) -> Result<Vec<Match>> {
+        let num_results = check_num_results(num_results)?;
+        let pattern = self.optional_pattern(query)?;
+        let scored = lines.iter().enumerate().filter_map(|(i, line)| {
+            self.score_line(pattern.as_ref(), context, i, line)
+                .map(|m| (line.path(), m))
+        });
+        let mtchs = if self.config.group_by_path {
+            top_matches(
+                best_per_path(scored).map(|(_, m)| Reverse(m)),
+                num_results,
+                false,
+            )
+        } else {
+            top_matches(scored.map(|(_, m)| Reverse(m)), num_results, false)
+        };
+        Ok(mtchs.into_iter().map(|Reverse(m)| m).sorted().collect())
+    }
+
+    /// Score `line` following the conventions of Telescope's sorters, where lower is better and
+    /// negative means filtered out. A match scores `1 / (1 + score)` for non-negative scores and
+    /// `1 - score` for negative ones, which is always positive, lower for better matches, and
+    /// finite for any finite score. A line that doesn't match, or an invalid query, gives
+    /// `TELESCOPE_NO_MATCH`.
+    pub fn telescope_score<L: Line>(&self, query: &str, context: &str, line: &L) -> f64 {
+        let pattern = match self.optional_pattern(query) {
+            Ok(pattern) => pattern,
+            Err(_) => return TELESCOPE_NO_MATCH,
+        };
+        self.score_line(pattern.as_ref(), context, 0, line)
+            .map_or(TELESCOPE_NO_MATCH, |m| {
+                if m.score >= 0. {
+                    1. / (1. + m.score)
+                } else {
+                    1. - m.score
+                }
+            })
+    }
+
+    /// The characters of `text` that `query` fuzzy matches, in order, for showing the matched
+    /// part of a line. `None` if it doesn't match. Matches don't keep the positions of their
+    /// matched characters, so this matches again with the same fuzzy matcher used for scoring.
+    pub fn matched_text(&self, query: &str, text: &str) -> Option<String> {
+        // The positions count chars, not bytes
+        let mut indices = self.matched_positions(query, text)?.into_iter().peekable();
+        Some(
+            text.chars()
+                .enumerate()
+                .filter(|(i, _)| {
+                    let matched = indices.peek() == Some(i);
+                    if matched {
+                        indices.next();
+                    }
+                    matched
+                })
+                .map(|(_, c)| c)
+                .collect(),
+        )
+    }
+
+    /// Like `matched_text`, but the positions of the matched characters, coalesced into
+    /// `position_ranges` for highlighting.
+    pub fn matched_ranges(&self, query: &str, text: &str) -> Option<Vec<(u32, u32)>> {
+        Some(position_ranges(&self.matched_positions(query, text)?))
+    }
+
+    /// The char positions in `text` of the characters `query` matches. With
+    /// `MatcherConfig::fold_diacritics` or `MatcherConfig::trim_candidates` the match is against
+    /// the folded or trimmed text, but the positions are mapped back to `text`. `None` for an
+    /// invalid query, as well as one that doesn't match.
+    fn matched_positions(&self, query: &str, text: &str) -> Option<Vec<usize>> {
+        let pattern = self.pattern(query).ok()?;
+        if self.config.trim_candidates {
+            let trimmed = text.trim_start();
+            let offset = text[..text.len() - trimmed.len()].chars().count();
+            let positions = self.untrimmed_matched_positions(&pattern, trimmed.trim_end())?;
+            return Some(positions.into_iter().map(|i| i + offset).collect());
+        }
+        self.untrimmed_matched_positions(&pattern, text)
+    }
+
+    /// `matched_positions` ignoring `MatcherConfig::trim_candidates`.
+    fn untrimmed_matched_positions(&self, pattern: &Pattern, text: &str) -> Option<Vec<usize>> {
+        if self.config.fold_diacritics {
+            let (folded, positions) = fold_diacritics_with_positions(text);
+            let indices =
+                self.pattern_positions(pattern, &fold_diacritics(pattern.query), &folded)?;
+            return Some(indices.into_iter().map(|i| positions[i]).collect());
+        }
+        self.pattern_positions(pattern, pattern.query, text)
+    }
+
+    /// The char positions in `text` of `query`, which is `pattern`'s query or its folded form.
+    /// An exact query highlights the substring it matched, and a `^` query the characters
+    /// matched from the best scoring component start, as `component_start_score` picks it.
+    fn pattern_positions(&self, pattern: &Pattern, query: &str, text: &str) -> Option<Vec<usize>> {
+        if pattern.exact {
+            let start = find_smart_case(text, query)?;
+            return Some((start..start + query.chars().count()).collect());
+        }
+        if pattern.component_start {
+            return std::iter::once(0)
+                .chain(text.match_indices('/').map(|(ind, _)| ind + 1))
+                .filter(|&start| starts_like(&text[start..], query))
+                .filter_map(|start| {
+                    let (score, indices) =
+                        self.skim_matcher.fuzzy_indices(&text[start..], query)?;
+                    let offset = text[..start].chars().count();
+                    Some((score, indices.into_iter().map(|i| i + offset).collect()))
+                })
+                .max_by_key(|(score, _)| *score)
+                .map(|(_, positions)| positions);
+        }
+        self.skim_matcher
+            .fuzzy_indices(text, query)
+            .map(|(_, indices)| indices)
+    }
+
+    /// A match for every line in `lines` along with whether the line matched, sorted best first
+    /// (or worst first with `MatcherConfig::ascending`), for UIs that dim non-matching lines
+    /// rather than hiding them. Lines that don't match, or score below
+    /// `MatcherConfig::min_score`, get `false` and a score of 0, and sort after every match, even
+    /// one whose score is negative. Unlike `best_matches` the result isn't capped, so this sorts
+    /// the whole input and takes O(n log n) time and O(n) memory.
+    pub fn score_all<L: Line>(
+        &self,
+        query: &str,
+        context: &str,
+        lines: &[L],
+    ) -> Result<Vec<(Match, bool)>> {
+        let pattern = self.optional_pattern(query)?;
+        let mut mtchs = lines
+            .iter()
+            .enumerate()
+            .map(
+                |(i, line)| match self.score_line(pattern.as_ref(), context, i, line) {
+                    Some(mtch) => (mtch, true),
+                    None => (
+                        Match {
+                            index: i,
+                            score: 0.,
+                            context_score: 0.,
+                            query_score: 0.,
+                            raw_query_score: 0.,
+                            frequency_score: 0.,
+                            open_score: 0.,
+                            stable_key: stable_key(line.path(), line.line()),
+                            matched_basename: false,
+                        },
+                        false,
+                    ),
+                },
+            )
+            .collect::<Vec<_>>();
+        let order = |x: &(Match, bool), y: &(Match, bool)| (x.1, &x.0).cmp(&(y.1, &y.0));
+        if self.config.ascending {
+            mtchs.sort_by(order);
+        } else {
+            mtchs.sort_by(|x, y| order(x, y).reverse());
+        }
+        Ok(mtchs)
+    }
+
+    /// Like `best_matches`, but also counts how many lines took each path through scoring, to
+    /// see where the time goes when tuning thresholds.
+    pub fn best_matches_with_stats<L: Line>(
+        &self,
+        query: &str,
+        context: &str,
+        num_results: u64,
+        lines: &[L],
+    ) -> Result<(Vec<Match>, ScanStats)> {
+        let num_results = check_num_results(num_results)?;
+        let pattern = self.optional_pattern(query)?;
+        let mut stats = ScanStats::default();
+        let scored = lines.iter().enumerate().filter_map(|(i, line)| {
+            let mtch = match self.unfiltered_score_line(pattern.as_ref(), context, i, line) {
+                Some(mtch) => mtch,
+                None => {
+                    stats.prefiltered_out += 1;
+                    return None;
+                }
+            };
+            stats.scored += 1;
+            if mtch.score < self.config.min_score {
+                stats.below_min_score += 1;
+                return None;
+            }
+            Some((line.path(), mtch))
+        });
+        let mtchs = if self.config.group_by_path {
+            top_matches(
+                best_per_path(scored).map(|(_, m)| m),
+                num_results,
+                self.config.diversify,
+            )
+        } else {
+            top_matches(scored.map(|(_, m)| m), num_results, self.config.diversify)
+        };
+        Ok((
+            sorted_matches(mtchs, num_results, &self.config, |i| lines[i].path()),
+            stats,
+        ))
+    }
+
+    /// Select `path` like `update`, then bring `previous_results` up to date without rescoring
+    /// every line. `previous_results` must be what `best_matches` returned for `query`,
+    /// `context`, `num_results` and `lines` with the matcher as it was before this call. Only
+    /// lines whose path was selected before have a frequency score that can change, so only
+    /// those are rescored. If that drops a result to where a line outside the previous results
+    /// might beat it, or with `MatcherConfig::group_by_path` or `MatcherConfig::diversify`, this
+    /// falls back to a full `best_matches`. Either way `previous_results` ends up equal to what
+    /// `best_matches` would now return.
+    pub fn rescore_after_update<L: Line>(
+        &mut self,
+        path: &str,
+        query: &str,
+        context: &str,
+        num_results: u64,
+        previous_results: &mut Vec<Match>,
+        lines: &[L],
+    ) -> Result<()> {
+        let max_results = check_num_results(num_results)?;
+        // An update ages every remembered path and directory, and may evict one
+        let mut affected = self.frequency.paths().collect::<HashSet<_>>();
+        affected.insert(path.to_string());
+        let mut affected_directories = HashSet::new();
+        if self.config.directory_frequency_weight != 0. {
+            affected_directories.extend(self.directory_frequency.paths());
+            affected_directories.insert(directory(path).to_string());
+        }
+        let is_affected =
+            |p: &str| affected.contains(p) || affected_directories.contains(directory(p));
+        let cutoff = previous_results.iter().min().cloned();
+        let was_full = previous_results.len() >= max_results;
+        self.update(path);
+        // Grouping and diversifying depend on lines outside the results
+        if self.config.group_by_path || self.config.diversify {
+            *previous_results = self.best_matches(query, context, num_results, lines)?;
+            return Ok(());
+        }
+
+        let pattern = self.optional_pattern(query)?;
+        let mtchs = previous_results
+            .drain(..)
+            .filter(|m| !is_affected(lines[m.index].path()))
+            .chain(
+                lines
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, l)| is_affected(l.path()))
+                    .filter_map(|(i, l)| self.score_line(pattern.as_ref(), context, i, l)),
+            )
+            .sorted_by(|x, y| x.cmp(y).reverse())
+            .take(max_results)
+            .collect::<Vec<_>>();
+        // Lines that weren't rescored and weren't in the previous results all rank below
+        // `cutoff`, so they can only get in if the results now reach below it.
+        let exact = !was_full || (mtchs.len() == max_results && mtchs.last() >= cutoff.as_ref());
+        if !exact {
+            *previous_results = self.best_matches(query, context, num_results, lines)?;
+            return Ok(());
+        }
+        *previous_results = sorted_matches(mtchs, max_results, &self.config, |i| lines[i].path());
+        Ok(())
+    }
+
+    /// `best_matches` for each `(query, context)` pair in `queries`, against the same `lines`.
+    /// Faster than separate calls, as each line is only preprocessed once.
+    pub fn best_matches_batch<L: Line>(
+        &self,
+        queries: &[(String, String)],
+        num_results: u64,
+        lines: &[L],
+    ) -> Result<Vec<Vec<Match>>> {
+        let num_results = check_num_results(num_results)?;
+        let prepared = lines
+            .iter()
+            .map(|l| match l.normalized() {
+                Some(normalized) => Cow::Borrowed(normalized),
+                None => self.preprocessed(l.line()),
+            })
+            .collect::<Vec<_>>();
+        queries
+            .iter()
+            .map(|(query, context)| {
+                let pattern = self.optional_pattern(query)?;
+                let scored =
+                    lines
+                        .iter()
+                        .zip(&prepared)
+                        .enumerate()
+                        .filter_map(|(i, (l, line))| {
+                            if self.excluded(l.path()) {
+                                return None;
+                            }
+                            let candidate = Candidate {
+                                index: i,
+                                path: l.path(),
+                                line: l.line(),
+                                extra_score: l.extra_score(),
+                            };
+                            let mtch = match (&self.scorer, &pattern) {
+                                (Some(scorer), _) => self.custom_score(
+                                    scorer,
+                                    query.trim(),
+                                    context,
+                                    l.line(),
+                                    &candidate,
+                                ),
+                                (None, Some(pattern)) => {
+                                    self.score_preprocessed(pattern, context, line, &candidate)
+                                }
+                                (None, None) => Some(self.new_match(
+                                    &candidate,
+                                    context_score("", line, context),
+                                    0.,
+                                    true,
+                                )),
+                            };
+                            self.above_min_score(mtch).map(|m| (l.path(), m))
+                        });
+                let mtchs = if self.config.group_by_path {
+                    top_matches(
+                        best_per_path(scored).map(|(_, m)| m),
+                        num_results,
+                        self.config.diversify,
+                    )
+                } else {
+                    top_matches(scored.map(|(_, m)| m), num_results, self.config.diversify)
+                };
+                Ok(sorted_matches(mtchs, num_results, &self.config, |i| {
+                    lines[i].path()
+                }))
+            })
+            .collect()
+    }
+
+    /// Like `best_matches`, but for several queries that must all match, each against its own
+    /// part of the line, e.g. one search box filtering paths and another filtering the text. A
+    /// line's query score is the sum of the queries' scores, and empty queries match every line.
+    /// There is no context, so there is no context score either.
+    pub fn best_matches_and<L: Line>(
+        &self,
+        queries: &[(MatchTarget, String)],
+        num_results: u64,
+        lines: &[L],
+    ) -> Result<Vec<Match>> {
+        let num_results = check_num_results(num_results)?;
+        let patterns = queries
+            .iter()
+            .filter(|(_, query)| !query.trim().is_empty())
+            .map(|(target, query)| Ok((*target, self.pattern(query)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let scored = lines.iter().enumerate().filter_map(|(i, l)| {
+            if self.excluded(l.path()) {
+                return None;
+            }
+            let line = match l.normalized() {
+                Some(normalized) => Cow::Borrowed(normalized),
+                None => self.preprocessed(l.line()),
+            };
+            let mut total = TextScore::whole(0.);
+            for (target, pattern) in &patterns {
+                let score = self.target_score(pattern, *target, &line, l.path())?;
+                total.score += score.score;
+                total.matched_basename |= score.matched_basename;
+                total.matched |= score.matched;
+            }
+            let candidate = Candidate {
+                index: i,
+                path: l.path(),
+                line: l.line(),
+                extra_score: l.extra_score(),
+            };
+            let matched = patterns.is_empty() || total.matched;
+            let mut mtch = self.new_match(&candidate, 0., total.score, matched);
+            mtch.matched_basename = total.matched_basename;
+            self.above_min_score(Some(mtch)).map(|m| (l.path(), m))
+        });
+        let mtchs = if self.config.group_by_path {
+            top_matches(
+                best_per_path(scored).map(|(_, m)| m),
+                num_results,
+                self.config.diversify,
+            )
+        } else {
+            top_matches(scored.map(|(_, m)| m), num_results, self.config.diversify)
+        };
+        Ok(sorted_matches(mtchs, num_results, &self.config, |i| {
+            lines[i].path()
+        }))
+    }
+
+    /// Like `best_matches`, but consumes `lines` as they are produced instead of needing them all
+    /// in a slice first. A match's `index` is the position of its line in the iterator.
+    pub fn best_matches_iter<L: Line>(
+        &self,
+        query: &str,
+        context: &str,
+        num_results: u64,
+        lines: impl IntoIterator<Item = L>,
+    ) -> Result<Vec<Match>> {
+        let num_results = check_num_results(num_results)?;
+        let pattern = self.optional_pattern(query)?;
+        let scored = lines.into_iter().enumerate();
+        if !self.config.group_by_path && !self.config.diversify {
+            let mtchs = top_matches(
+                scored.filter_map(|(i, line)| self.score_line(pattern.as_ref(), context, i, &line)),
+                num_results,
+                false,
+            );
+            // Without `diversify` the paths aren't looked at
+            return Ok(sorted_matches(mtchs, num_results, &self.config, |_| ""));
+        }
+        // The lines don't outlive the iterator, so their paths have to be copied
+        let scored = scored.filter_map(|(i, line)| {
+            self.score_line(pattern.as_ref(), context, i, &line)
+                .map(|m| (line.path().to_string(), m))
+        });
+        let mtchs = if self.config.group_by_path {
+            top_matches(
+                best_per_path(scored).map(|(p, m)| (m, p)),
+                num_results,
+                self.config.diversify,
+            )
+        } else {
+            top_matches(
+                scored.map(|(p, m)| (m, p)),
+                num_results,
+                self.config.diversify,
+            )
+        };
+        let paths = mtchs
+            .iter()
+            .map(|(m, p)| (m.index, p.clone()))
+            .collect::<HashMap<_, _>>();
+        Ok(sorted_matches(
+            mtchs.into_iter().map(|(m, _)| m),
+            num_results,
+            &self.config,
+            |i| &paths[&i],
+        ))
+    }
+
+    /// `pattern`, except the empty query is the common case of just having opened the finder
+    /// and doesn't need one.
+    fn optional_pattern<'q>(&self, query: &'q str) -> Result<Option<Pattern<'q>>> {
+        if query.trim().is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(self.pattern(query)?))
+        }
+    }
+
+    fn score_line<L: Line>(
+        &self,
+        pattern: Option<&Pattern>,
+        context: &str,
+        index: usize,
+        line: &L,
+    ) -> Option<Match> {
+        self.above_min_score(self.unfiltered_score_line(pattern, context, index, line))
+    }
 
-impl Line for OwnedLine {
-    fn path(&self) -> &str {
-        &self.path
+    /// `score_line` without the `MatcherConfig::min_score` cutoff, so `None` means the line
+    /// didn't match.
+    fn unfiltered_score_line<L: Line>(
+        &self,
+        pattern: Option<&Pattern>,
+        context: &str,
+        index: usize,
+        line: &L,
+    ) -> Option<Match> {
+        if self.excluded(line.path()) {
+            return None;
+        }
+        let candidate = Candidate {
+            index,
+            path: line.path(),
+            line: line.line(),
+            extra_score: line.extra_score(),
+        };
+        if let Some(scorer) = &self.scorer {
+            let query = pattern.map_or("", |p| p.query);
+            return self.custom_score(scorer, query, context, line.line(), &candidate);
+        }
+        let pattern = pattern.filter(|p| !p.query.is_empty());
+        match (pattern, line.normalized()) {
+            (Some(pattern), Some(normalized)) => {
+                self.score_preprocessed(pattern, context, normalized, &candidate)
+            }
+            (Some(pattern), None) => self.score_preprocessed(
+                pattern,
+                context,
+                &self.preprocessed(line.line()),
+                &candidate,
+            ),
+            (None, Some(normalized)) => {
+                let context_score = if context.is_empty() {
+                    0.
+                } else {
+                    context_score("", normalized, context)
+                };
+                Some(self.new_match(&candidate, context_score, 0., true))
+            }
+            (None, None) => self.empty_query_score(context, line.line(), &candidate),
+        }
     }
 
-    fn line(&self) -> &str {
-        &self.line
+    /// Like `best_matches`, but processes `lines` in chunks. More lines can be added later with
+    /// `IncrementalMatcher::feed_lines`. Returns an error if `num_results` is zero.
+    pub fn incremental_match<'b, 'c, L: Line>(
+        &'b self,
+        query: &'c str,
+        context: &'c str,
+        num_results: u64,
+        lines: impl IntoIterator<Item = L>,
+    ) -> Result<IncrementalMatcher<'b, 'c, L>> {
+        let num_results = check_num_results(num_results)?;
+        let mut inc_matcher =
+            IncrementalMatcher::new(self, query, context, Vec::new(), num_results);
+        inc_matcher.feed_lines(lines);
+        Ok(inc_matcher)
     }
 }
 
-#[repr(C)]
-#[derive(Debug, Clone, PartialEq)]
-pub struct Match {
-    pub index: usize,
-    pub score: f64,
-    pub context_score: f64,
-    pub query_score: f64,
-    pub frequency_score: f64,
+fn skim_matcher(config: &MatcherConfig) -> SkimMatcherV2 {
+    let (bonus_first_char_multiplier, bonus_head, bonus_break, bonus_camel, bonus_consecutive) =
+        skim_score_config(config);
+    SkimMatcherV2::default()
+        .use_cache(true)
+        .smart_case()
+        .score_config(SkimScoreConfig {
+            gap_start: -8,
+            gap_extension: -3,
+            penalty_case_mismatch: 0,
+            bonus_first_char_multiplier,
+            bonus_head,
+            bonus_break,
+            bonus_camel,
+            bonus_consecutive,
+            ..SkimScoreConfig::default()
+        })
 }
 
-impl Eq for Match {}
+/// The parts of `config` that `skim_matcher` uses.
+fn skim_score_config(config: &MatcherConfig) -> (i32, i32, i32, i32, i32) {
+    (
+        config.bonus_first_char_multiplier,
+        config.bonus_head,
+        config.bonus_break,
+        config.bonus_camel,
+        config.bonus_consecutive,
+    )
+}
 
-impl PartialOrd for Match {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(other))
+/// How well a query matched some text.
+struct TextScore {
+    score: f64,
+    /// See `Match::matched_basename`.
+    matched_basename: bool,
+    /// Whether the fuzzy match or glob itself scored above zero, before
+    /// `MatcherConfig::position_penalty` and the bonuses. Decides whether the boosts apply, see
+    /// `Matcher::new_match`.
+    matched: bool,
+}
+
+impl TextScore {
+    fn whole(score: f64) -> Self {
+        TextScore {
+            score,
+            matched_basename: false,
+            matched: score > 0.,
+        }
     }
 }
 
-impl Ord for Match {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        (self.score, self.index)
-            .partial_cmp(&(other.score, other.index))
-            .unwrap_or(std::cmp::Ordering::Equal)
+/// Context score decays as the user input gets longer. We want good matches with no input, it
+/// matters less when the user has been explicit about what they want.
+fn context_score(query: &str, line: &str, context: &str) -> f64 {
+    if context.is_empty() {
+        0.
+    } else {
+        max_context_score(query) * normalized_levenshtein(line, context)
     }
 }
 
-pub struct Matcher {
-    frequency: FrequencyCounter,
-    skim_matcher: SkimMatcherV2,
+/// The context score of a line identical to the context.
+fn max_context_score(query: &str) -> f64 {
+    (query.len() as f64 * -0.5).exp() * 10.
 }
 
-impl Matcher {
-    pub fn new() -> Result<Self> {
-        Ok(Matcher {
-            frequency: FrequencyCounter::new()?,
-            skim_matcher: SkimMatcherV2::default()
-                .use_cache(true)
-                .smart_case()
-                .score_config(SkimScoreConfig {
-                    gap_start: -8,
-                    gap_extension: -3,
-                    penalty_case_mismatch: 0,
-                    ..SkimScoreConfig::default()
-                }),
+/// Cheap alternative to fuzzy matching for short queries. Matches if `text` or its basename
+/// starts with `query`, ignoring case.
+fn prefix_score(query: &str, text: &str) -> Option<TextScore> {
+    let starts_with = |s: &str| {
+        s.get(..query.len())
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case(query))
+    };
+    let basename = text.rfind('/').map_or(text, |ind| &text[ind + 1..]);
+    if starts_with(text) {
+        Some(TextScore::whole(1.))
+    } else if starts_with(basename) {
+        Some(TextScore {
+            score: 1.,
+            matched_basename: true,
+            matched: true,
         })
+    } else {
+        None
     }
+}
 
-    pub fn update(&mut self, entry: &str) {
-        self.frequency.update(entry)
+/// Whether `text` starts with the first character of `query`, ignoring case unless the query
+/// has uppercase letters.
+fn starts_like(text: &str, query: &str) -> bool {
+    let case_sensitive = query.chars().any(char::is_uppercase);
+    match (text.chars().next(), query.chars().next()) {
+        (Some(t), Some(q)) if case_sensitive => t == q,
+        (Some(t), Some(q)) => t.to_lowercase().eq(q.to_lowercase()),
+        _ => false,
     }
+}
 
-    pub fn score(
-        &self,
-        query: &str,
-        context: &str,
-        index: usize,
-        line: &str,
-        path: &str,
-    ) -> Option<Match> {
-        let frequency_score = self.frequency.score(path) * 10.;
-        // Context score decays as the user input gets longer. We want good matches with no
-        // input, it matters less when the user has been explicit about what they want.
-        let context_score = (query.len() as f64 * -0.5).exp()
-            * if context.len() > 0 {
-                normalized_levenshtein(line, context) * 10.
+/// The char position of the first occurrence of `query` in `text`, ignoring case unless the
+/// query has uppercase letters. Unlike `contains_smart_case` this works in chars, for
+/// highlighting.
+fn find_smart_case(text: &str, query: &str) -> Option<usize> {
+    let case_sensitive = query.chars().any(char::is_uppercase);
+    let text = text.chars().collect::<Vec<_>>();
+    let query = query.chars().collect::<Vec<_>>();
+    if query.is_empty() {
+        return Some(0);
+    }
+    text.windows(query.len()).position(|window| {
+        window.iter().zip(&query).all(|(&t, &q)| {
+            if case_sensitive {
+                t == q
             } else {
-                0.
-            };
-        let query_score = if query.len() > 0 {
-            let whole_score =
-                self.skim_matcher.fuzzy_match(line, query)? as f64 / query.len() as f64;
-            // Try and find path delimiters
-            let slash = line.rfind('/');
-            match slash {
-                None => whole_score,
-                Some(ind) => {
-                    self.skim_matcher
-                        .fuzzy_match(&line[ind..], query)
-                        .map_or(0., |x| x as f64 / query.len() as f64)
-                        + whole_score
-                }
+                t.to_lowercase().eq(q.to_lowercase())
             }
-        } else {
-            0.
+        })
+    })
+}
+
+/// Whether `text` contains `query`, ignoring case unless `query` has uppercase letters.
+fn contains_smart_case(text: &str, query: &str) -> bool {
+    if query.chars().any(char::is_uppercase) {
+        text.contains(query)
+    } else if query.is_ascii() {
+        // Avoid allocating a lowercased copy of every line
+        text.as_bytes()
+            .windows(query.len())
+            .any(|window| window.eq_ignore_ascii_case(query.as_bytes()))
+    } else {
+        text.to_lowercase().contains(query)
+    }
+}
+
+/// Coalesce sorted character positions into half-open ranges of consecutive positions, so
+/// `[0, 1, 2, 5, 6]` becomes `[(0, 3), (5, 7)]`. A UI can then highlight each range at once.
+pub fn position_ranges(positions: &[usize]) -> Vec<(u32, u32)> {
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for &position in positions {
+        let position = position as u32;
+        match ranges.last_mut() {
+            Some((_, end)) if *end == position => *end += 1,
+            _ => ranges.push((position, position + 1)),
+        }
+    }
+    ranges
+}
+
+/// Base letters of U+00C0 to U+017F for `fold_diacritic`, `_` where a character has none.
+const LATIN_BASES: &[u8; 192] = b"\
+AAAAAA_CEEEEIIII_NOOOOO__UUUUY__aaaaaa_ceeeeiiii_nooooo__uuuuy_y\
+AaAaAaCcCcCcCcDd__EeEeEeEeEeGgGgGgGgHh__IiIiIiIiI___JjKk_LlLlLl_\
+___NnNnNn___OoOoOo__RrRrRrSsSsSsSsTtTt__UuUuUuUuUuUuWwYyYZzZzZz_";
+
+/// `c` without its diacritics, or `None` for a combining mark, which is dropped. Covers Latin
+/// letters up to U+017F, which includes most European languages.
+fn fold_diacritic(c: char) -> Option<char> {
+    match c {
+        '\u{300}'..='\u{36f}'
+        | '\u{1ab0}'..='\u{1aff}'
+        | '\u{1dc0}'..='\u{1dff}'
+        | '\u{20d0}'..='\u{20ff}'
+        | '\u{fe20}'..='\u{fe2f}' => None,
+        '\u{c0}'..='\u{17f}' => match LATIN_BASES[c as usize - 0xc0] {
+            b'_' => Some(c),
+            base => Some(base as char),
+        },
+        _ => Some(c),
+    }
+}
+
+/// `text` with `fold_diacritic` applied to every char, for `MatcherConfig::fold_diacritics`.
+fn fold_diacritics(text: &str) -> Cow<'_, str> {
+    if text.is_ascii() {
+        Cow::Borrowed(text)
+    } else {
+        Cow::Owned(text.chars().filter_map(fold_diacritic).collect())
+    }
+}
+
+/// `fold_diacritics`, along with the char position in `text` of each char of the result.
+fn fold_diacritics_with_positions(text: &str) -> (String, Vec<usize>) {
+    text.chars()
+        .enumerate()
+        .filter_map(|(i, c)| fold_diacritic(c).map(|c| (c, i)))
+        .unzip()
+}
+
+/// The first letter of each word in `text`, lowercased. Words start at the beginning of `text`,
+/// after `_`, `-` or `/`, and at an uppercase letter following a lowercase one, so
+/// `src/getFooMeta` gives `sgfm`.
+fn initials(text: &str) -> String {
+    let mut initials = String::new();
+    let mut prev: Option<char> = None;
+    for c in text.chars() {
+        let separator = matches!(c, '_' | '-' | '/');
+        let starts_word = match prev {
+            None => true,
+            Some(p) => matches!(p, '_' | '-' | '/') || (c.is_uppercase() && p.is_lowercase()),
         };
-        Some(Match {
-            index: index,
-            score: frequency_score + context_score + query_score,
-            context_score,
-            frequency_score,
-            query_score,
+        if starts_word && !separator {
+            initials.extend(c.to_lowercase());
+        }
+        prev = Some(c);
+    }
+    initials
+}
+
+/// Scoring for `MatchMode::Subsequence`.
+fn subsequence_score(query: &str, text: &str) -> Option<f64> {
+    let case_sensitive = query.chars().any(char::is_uppercase);
+    let same = |q: char, t: char| {
+        if case_sensitive {
+            q == t
+        } else {
+            t.to_lowercase().eq(q.to_lowercase())
+        }
+    };
+    let mut text_chars = text.chars();
+    let mut query_len = 0;
+    for q in query.chars() {
+        text_chars.find(|&t| same(q, t))?;
+        query_len += 1;
+    }
+    Some(query_len as f64 / text.chars().count() as f64)
+}
+
+/// Fallback for queries that don't fuzzy match. Matches if some substring of `text` is at most
+/// `max_edits` insertions, deletions, substitutions or transpositions away from `query`, ignoring
+/// case unless the query has uppercase letters. Scores at most 1, less for every edit needed.
+fn typo_score(query: &str, text: &str, max_edits: usize) -> Option<f64> {
+    let (query, text) = if query.chars().any(char::is_uppercase) {
+        (query.to_string(), text.to_string())
+    } else {
+        (query.to_lowercase(), text.to_lowercase())
+    };
+    let query_len = query.chars().count();
+    // Every edit changes at most one of the query's characters, so a close enough substring
+    // contains all but `max_edits` of them. Most lines the fuzzy matcher rejected fail this,
+    // which is much cheaper than the edit distance scan.
+    let mut missing = query.chars().collect::<Vec<_>>();
+    for t in text.chars() {
+        if let Some(i) = missing.iter().position(|&q| q == t) {
+            missing.swap_remove(i);
+        }
+    }
+    if missing.len() > max_edits {
+        return None;
+    }
+    let bounds = text
+        .char_indices()
+        .map(|(i, _)| i)
+        .chain(std::iter::once(text.len()))
+        .collect::<Vec<_>>();
+    let bounds = &bounds;
+    let edits = (0..bounds.len())
+        .flat_map(|start| {
+            (query_len.saturating_sub(max_edits).max(1)..=query_len + max_edits)
+                .filter_map(move |len| Some((bounds[start], *bounds.get(start + len)?)))
         })
+        .map(|(start, end)| osa_distance(&query, &text[start..end]))
+        .min()?;
+    if edits <= max_edits {
+        Some(query_len.saturating_sub(edits) as f64 / query_len as f64)
+    } else {
+        None
     }
+}
 
-    pub fn best_matches<L: Line>(
-        &self,
-        query: &str,
-        context: &str,
-        num_results: u64,
-        lines: &[L],
-    ) -> Result<Vec<Match>> {
-        let mtchs = process_results(
-            lines
-                .into_iter()
-                .enumerate()
-                .map(|(i, line)| -> Result<Option<Match>> {
-                    Ok(self.score(query, context, i, line.line(), line.path()))
-                }),
-            |iter| {
-                iter.filter_map(|x| x).fold(
-                    BinaryHeap::<Match, MinComparator>::with_capacity_min(num_results as usize),
-                    |mut entries, mtch| {
-                        if entries.len() < num_results as usize {
-                            entries.push(mtch);
-                            entries
-                        } else {
-                            match entries.peek() {
-                                Some(smallest) if &mtch > smallest => {
-                                    entries.pop();
-                                    entries.push(mtch);
-                                }
-                                _ => (),
-                            }
-                            entries
-                        }
-                    },
-                )
-            },
-        )?;
-        Ok(mtchs
-            .into_iter()
-            .sorted_by(|x, y| x.cmp(&y).reverse())
-            .take(num_results as usize)
-            .collect::<Vec<_>>())
+/// Something ranked by `top_matches`, for telling which entries tie.
+trait Scored {
+    fn score(&self) -> f64;
+}
+
+impl Scored for Match {
+    fn score(&self) -> f64 {
+        self.score
     }
+}
 
-    pub fn incremental_match<'a, 'b, 'c, L: Line>(
-        &'b self,
-        query: &'c str,
-        context: &'c str,
-        num_results: u64,
-        lines: &'a [L],
-    ) -> IncrementalMatcher<'a, 'b, 'c, L> {
-        IncrementalMatcher::new(self, query, context, lines, num_results as usize)
+impl Scored for Reverse<Match> {
+    fn score(&self) -> f64 {
+        self.0.score
+    }
+}
+
+impl<P> Scored for (Match, P) {
+    fn score(&self) -> f64 {
+        self.0.score
+    }
+}
+
+/// Keep the `num_results` largest matches, and with `keep_ties` any others scoring the same as
+/// the smallest of those, see `push_top`.
+fn top_matches<M: Ord + Scored>(
+    mtchs: impl Iterator<Item = M>,
+    num_results: usize,
+    keep_ties: bool,
+) -> BinaryHeap<M, MinComparator> {
+    mtchs.fold(
+        BinaryHeap::<M, MinComparator>::with_capacity_min(
+            num_results.min(MAX_PREALLOCATED_RESULTS),
+        ),
+        |mut entries, mtch| {
+            push_top(&mut entries, num_results, keep_ties, mtch);
+            entries
+        },
+    )
+}
+
+/// Add `mtch` to `entries` if it is among the best `num_results`. With `keep_ties`, which
+/// `MatcherConfig::diversify` needs to choose among every line at the cutoff score, matches with
+/// the same score as the `num_results`th best are kept as well, so `entries` can grow past
+/// `num_results`.
+fn push_top<M: Ord + Scored>(
+    entries: &mut BinaryHeap<M, MinComparator>,
+    num_results: usize,
+    keep_ties: bool,
+    mtch: M,
+) {
+    if entries.len() < num_results {
+        entries.push(mtch);
+        return;
+    }
+    let cutoff = match entries.peek() {
+        Some(smallest) if keep_ties => smallest.score(),
+        Some(smallest) if &mtch > smallest => {
+            entries.pop();
+            entries.push(mtch);
+            return;
+        }
+        _ => return,
+    };
+    if mtch.score() < cutoff {
+        return;
+    }
+    let raises_cutoff = mtch.score() > cutoff;
+    entries.push(mtch);
+    if raises_cutoff {
+        // Fewer than `num_results` entries scored above the cutoff before. If there are now
+        // enough of them, the ties at the old cutoff drop out.
+        let mut tied = Vec::new();
+        while entries.peek().is_some_and(|m| m.score() == cutoff) {
+            tied.extend(entries.pop());
+        }
+        if entries.len() < num_results {
+            entries.extend(tied);
+        }
+    }
+}
+
+/// The best `num_results` of `mtchs`, sorted best first, or worst first with
+/// `MatcherConfig::ascending`. `path` gives the path of the line at an index, which
+/// `MatcherConfig::diversify` needs. Diversifying happens before cutting the results down to
+/// `num_results`, so `mtchs` should hold every match tied at the cutoff score.
+fn sorted_matches<'a>(
+    mtchs: impl IntoIterator<Item = Match>,
+    num_results: usize,
+    config: &MatcherConfig,
+    path: impl Fn(usize) -> &'a str,
+) -> Vec<Match> {
+    let mut sorted = mtchs
+        .into_iter()
+        .sorted_by(|x, y| x.cmp(y).reverse())
+        .collect::<Vec<_>>();
+    if config.diversify {
+        diversify(&mut sorted, path);
+    }
+    sorted.truncate(num_results);
+    if config.ascending {
+        sorted.reverse();
+    }
+    sorted
+}
+
+/// Reorder each run of equal scores in `sorted` to take one line from each of its paths in
+/// turn. Lines from the same path keep their relative order.
+fn diversify<'a>(sorted: &mut [Match], path: impl Fn(usize) -> &'a str) {
+    let mut start = 0;
+    while start < sorted.len() {
+        let score = sorted[start].score;
+        let end = start
+            + sorted[start..]
+                .iter()
+                .take_while(|m| m.score == score)
+                .count();
+        let mut groups = Vec::<VecDeque<Match>>::new();
+        let mut group_of = HashMap::<&str, usize>::new();
+        for m in &sorted[start..end] {
+            let group = *group_of.entry(path(m.index)).or_insert_with(|| {
+                groups.push(VecDeque::new());
+                groups.len() - 1
+            });
+            groups[group].push_back(m.clone());
+        }
+        let mut i = start;
+        while i < end {
+            for group in groups.iter_mut() {
+                if let Some(m) = group.pop_front() {
+                    sorted[i] = m;
+                    i += 1;
+                }
+            }
+        }
+        start = end;
+    }
+}
+
+/// Keep only the largest match for each path. The matches come out in `HashMap` order, which
+/// varies between runs, so they must be ranked by `Match`'s total order before being returned.
+fn best_per_path<P: Hash + Eq>(
+    mtchs: impl Iterator<Item = (P, Match)>,
+) -> impl Iterator<Item = (P, Match)> {
+    let mut best = HashMap::<P, Match>::new();
+    for (path, mtch) in mtchs {
+        match best.get(&path) {
+            Some(existing) if existing >= &mtch => (),
+            _ => {
+                best.insert(path, mtch);
+            }
+        }
+    }
+    best.into_iter()
+}
+
+/// Hash of everything about `lines` that scoring looks at, for `Matcher::result_cache`.
+fn lines_hash<L: Line>(lines: &[L]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for line in lines {
+        (line.path(), line.line(), line.normalized()).hash(&mut hasher);
+        line.extra_score().to_bits().hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// Compile globs for `Matcher::set_exclude_globs`.
+pub(crate) fn exclude_set(globs: &[&str]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(Glob::new(glob)?);
+    }
+    Ok(builder.build()?)
+}
+
+/// The part of `path` before its last `/`, or the empty string for a path without one.
+fn directory(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(directory, _)| directory)
+}
+
+/// Reject a `num_results` of zero, and convert it to a `usize`. Values that don't fit, like
+/// `u64::MAX` on a 32-bit target, mean "all of them" just like `usize::MAX` would.
+fn check_num_results(num_results: u64) -> Result<usize> {
+    if num_results == 0 {
+        Err(anyhow!("num_results must be at least 1"))
+    } else {
+        Ok(usize::try_from(num_results).unwrap_or(usize::MAX))
     }
 }
 
-pub struct IncrementalMatcher<'a, 'b, 'c, L: Line> {
+pub struct IncrementalMatcher<'b, 'c, L: Line> {
     matcher: &'b Matcher,
     query: &'c str,
     context: &'c str,
-    lines: &'a [L],
+    lines: Vec<L>,
     progressed_to: usize,
     results: BinaryHeap<Match, MinComparator>,
+    /// `results` in `MatcherConfig::ascending` order, cached once all lines have been processed.
+    sorted_results: Option<Vec<Match>>,
     num_results: usize,
+    /// Number of lines `process_adaptive` will process next.
+    chunk_size: usize,
+    /// Lines dropped for exceeding `MatcherConfig::max_lines`.
+    dropped: usize,
+    /// Where `process` reports its progress, see `set_progress_sender`.
+    progress_send: Option<Sender<(usize, usize)>>,
+}
+
+/// How much work `Matcher::best_matches_with_stats` did. Every line is either prefiltered out or
+/// scored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ScanStats {
+    /// Lines that got a full score.
+    pub scored: usize,
+    /// Lines rejected by the cheap check of whether the query matches at all, before anything
+    /// was scored. This is the fuzzy matcher's subsequence check, or the glob or exact substring
+    /// test.
+    pub prefiltered_out: usize,
+    /// Scored lines that were then dropped by `MatcherConfig::min_score`.
+    pub below_min_score: usize,
 }
 
+/// `Matcher::telescope_score` of a line that doesn't match.
+pub const TELESCOPE_NO_MATCH: f64 = -1.;
+
+/// How many lines `Matcher::best_matches_cancellable` scores between checks of its flag.
+pub const CANCEL_CHECK_INTERVAL: usize = 256;
+
+/// Longest query `MatcherConfig::initialism_bonus` applies to. Longer queries are rarely
+/// initialisms and fuzzy match well enough on their own.
+pub const MAX_INITIALISM_LEN: usize = 6;
+
+/// Results heaps start with room for at most this many matches and grow if more are found, so
+/// a huge `num_results` doesn't allocate memory for results that will never exist.
+const MAX_PREALLOCATED_RESULTS: usize = 1024;
+
+/// `Matcher::estimate_cost`'s time per query character per line character before `calibrate`,
+/// about a microsecond for a four character query against a typical path.
+const DEFAULT_NANOS_PER_CELL: f64 = 10.;
+
+/// Number of lines `Matcher::calibrate` scores.
+const CALIBRATION_LINES: usize = 2000;
+
+/// Bounds on the number of lines `IncrementalMatcher::process_adaptive` processes at once.
+const MIN_CHUNK_SIZE: usize = 1;
+const MAX_CHUNK_SIZE: usize = 100_000;
+
 #[derive(Eq, PartialEq, Debug)]
 pub enum Progress {
     Working,
     Done(Vec<Match>),
 }
 
-impl<'a, 'b, 'c, L: Line> IncrementalMatcher<'a, 'b, 'c, L> {
+impl<'b, 'c, L: Line> IncrementalMatcher<'b, 'c, L> {
     fn new(
         matcher: &'b Matcher,
         query: &'c str,
         context: &'c str,
-        lines: &'a [L],
+        lines: Vec<L>,
         num_results: usize,
     ) -> Self {
         IncrementalMatcher {
@@ -223,54 +2585,352 @@ impl<'a, 'b, 'c, L: Line> IncrementalMatcher<'a, 'b, 'c, L> {
             context,
             lines,
             progressed_to: 0,
-            results: BinaryHeap::<Match, MinComparator>::with_capacity_min(num_results),
+            results: BinaryHeap::<Match, MinComparator>::with_capacity_min(
+                num_results.min(MAX_PREALLOCATED_RESULTS),
+            ),
+            sorted_results: None,
             num_results,
+            chunk_size: 100,
+            dropped: 0,
+            progress_send: None,
+        }
+    }
+
+    /// Have every `process` call that processes lines send the new `stats` to `progress`, e.g.
+    /// for a progress bar on another thread. Updates are sent with `try_send`, so a full bounded
+    /// channel or a dropped receiver loses updates instead of stalling matching.
+    pub fn set_progress_sender(&mut self, progress: Option<Sender<(usize, usize)>>) {
+        self.progress_send = progress;
+    }
+
+    /// Add more lines to be matched. Their indices follow on from the lines already fed.
+    ///
+    /// Lines beyond `MatcherConfig::max_lines` are dropped. Returns how many were dropped, so the
+    /// caller can show that the list was truncated.
+    pub fn feed_lines(&mut self, lines: impl IntoIterator<Item = L>) -> usize {
+        let num_lines = self.lines.len();
+        let room = self
+            .matcher
+            .config
+            .max_lines
+            .map_or(usize::MAX, |max| max.saturating_sub(num_lines));
+        let mut lines = lines.into_iter();
+        self.lines.extend(lines.by_ref().take(room));
+        if self.lines.len() > num_lines {
+            self.sorted_results = None;
+        }
+        let dropped = lines.count();
+        self.dropped += dropped;
+        dropped
+    }
+
+    /// Replace all the lines with `lines`, for providers that send their whole list again on
+    /// every update. Returns how many of `lines` were dropped for exceeding
+    /// `MatcherConfig::max_lines`, like `feed_lines`.
+    ///
+    /// Lines are compared by position: the longest prefix of `lines` with the same paths, texts
+    /// and extra scores as the lines already fed is kept, and everything after it counts as
+    /// changed. If none of the current results are in the changed part, the results and the
+    /// progress through the unchanged prefix are kept, so only the changed lines are scored.
+    /// Otherwise, as with `remove_lines`, the line that would replace a result is unknown, so
+    /// every line is scored again. Lists that only grow or change at the end get the most out
+    /// of this, while a change near the start rescores nearly everything.
+    pub fn feed_replace(&mut self, lines: Vec<L>) -> usize {
+        let unchanged = self
+            .lines
+            .iter()
+            .zip(&lines)
+            .take_while(|(old, new)| {
+                old.path() == new.path()
+                    && old.line() == new.line()
+                    && old.extra_score() == new.extra_score()
+            })
+            .count();
+        if unchanged < self.lines.len() || unchanged < lines.len() {
+            self.sorted_results = None;
+            if self.results.iter().any(|m| m.index >= unchanged) {
+                self.results.clear();
+                self.progressed_to = 0;
+            } else {
+                self.progressed_to = self.progressed_to.min(unchanged);
+            }
+        }
+        self.lines.clear();
+        self.dropped = 0;
+        self.feed_lines(lines)
+    }
+
+    /// Total number of lines dropped by `feed_lines` for exceeding `MatcherConfig::max_lines`.
+    pub fn dropped_lines(&self) -> usize {
+        self.dropped
+    }
+
+    /// Remove all lines for which `predicate` returns true, returning how many were removed.
+    ///
+    /// Match indices refer to positions in the fed lines, so the surviving lines are renumbered.
+    /// `progressed_to` becomes the number of surviving lines that had already been processed, so
+    /// unprocessed lines are still processed next. If none of the removed lines are in the
+    /// current results, the results are renumbered and kept. Otherwise the line that would
+    /// replace a removed result is unknown, so the results are cleared and every surviving line
+    /// is processed again.
+    pub fn remove_lines(&mut self, predicate: impl Fn(&L) -> bool) -> usize {
+        let removed = self.lines.iter().map(&predicate).collect::<Vec<_>>();
+        let num_removed = removed.iter().filter(|r| **r).count();
+        if num_removed == 0 {
+            return 0;
+        }
+        self.sorted_results = None;
+        // Number of removed lines before each index
+        let shift = removed
+            .iter()
+            .scan(0, |count, r| {
+                let before = *count;
+                *count += *r as usize;
+                Some(before)
+            })
+            .collect::<Vec<_>>();
+        if self.results.iter().any(|m| removed[m.index]) {
+            self.results.clear();
+            self.progressed_to = 0;
+        } else {
+            let renumbered = self
+                .results
+                .drain()
+                .map(|m| Match {
+                    index: m.index - shift[m.index],
+                    ..m
+                })
+                .collect::<Vec<_>>();
+            self.results.extend(renumbered);
+            self.progressed_to -= removed[..self.progressed_to].iter().filter(|r| **r).count();
+        }
+        let mut i = 0;
+        self.lines.retain(|_| {
+            i += 1;
+            !removed[i - 1]
+        });
+        num_removed
+    }
+
+    /// Like `process`, but chooses how many lines to process so that each call takes roughly
+    /// `target`, based on the per line cost observed in previous calls.
+    pub fn process_adaptive(&mut self, target: Duration) -> Result<Progress> {
+        let start = Instant::now();
+        let before = self.progressed_to;
+        let progress = self.process(self.chunk_size)?;
+        let processed = self.progressed_to.saturating_sub(before);
+        self.adjust_chunk_size(processed, start.elapsed(), target);
+        Ok(progress)
+    }
+
+    /// Update `chunk_size` after `processed` lines took `elapsed`, aiming for chunks that take
+    /// `target`. This is what `process_adaptive` does after each chunk, for callers that time
+    /// `process` themselves.
+    pub fn adjust_chunk_size(&mut self, processed: usize, elapsed: Duration, target: Duration) {
+        if processed > 0 {
+            let per_line = elapsed.as_secs_f64() / processed as f64;
+            let ideal = (target.as_secs_f64() / per_line.max(1e-12))
+                .clamp(MIN_CHUNK_SIZE as f64, MAX_CHUNK_SIZE as f64)
+                as usize;
+            // Only move halfway to the ideal size to smooth out timing noise
+            self.chunk_size = (self.chunk_size + ideal) / 2;
+        }
+    }
+
+    /// Feed lines from `lines` until it ends, then return the final results. Meant for lines
+    /// read from a subprocess like `rg` or `fd`: whenever no line is ready, a chunk of the lines
+    /// received so far is processed, so matching keeps up with the input instead of starting
+    /// once it ends. A tokio channel can be fed through `tokio_stream::wrappers::ReceiverStream`.
+    #[cfg(feature = "async")]
+    pub async fn feed_stream(
+        &mut self,
+        lines: impl futures_core::Stream<Item = L>,
+    ) -> Result<Vec<Match>> {
+        use std::future::poll_fn;
+        use std::task::Poll;
+        let mut lines = std::pin::pin!(lines);
+        loop {
+            // Poll once without waiting, to tell whether a line is ready
+            match poll_fn(|cx| Poll::Ready(lines.as_mut().poll_next(cx))).await {
+                Poll::Ready(Some(line)) => {
+                    self.feed_lines(Some(line));
+                }
+                Poll::Ready(None) => break,
+                Poll::Pending if self.progressed_to < self.lines.len() => {
+                    self.process(self.chunk_size)?;
+                    tokio::task::yield_now().await;
+                }
+                Poll::Pending => match poll_fn(|cx| lines.as_mut().poll_next(cx)).await {
+                    Some(line) => {
+                        self.feed_lines(Some(line));
+                    }
+                    None => break,
+                },
+            }
+        }
+        match self.process(self.lines.len() - self.progressed_to)? {
+            Progress::Done(mtchs) => Ok(mtchs),
+            Progress::Working => unreachable!("every line was processed"),
+        }
+    }
+
+    /// The number of lines processed so far and the total number of lines fed.
+    pub fn stats(&self) -> (usize, usize) {
+        (self.progressed_to, self.lines.len())
+    }
+
+    /// Number of lines the next `process_adaptive` call will process.
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Take back the lines, e.g. to match them again with a different query.
+    pub fn into_lines(self) -> Vec<L> {
+        self.lines
+    }
+
+    /// The best matches among the lines processed so far, sorted like the final results, for
+    /// showing something while matching is still running. Lines processed later can push these
+    /// down or out of the results. Once every line has been processed this is the final result.
+    pub fn current_best(&self) -> Vec<Match> {
+        if let Some(sorted) = &self.sorted_results {
+            return sorted.clone();
         }
+        let lines = &self.lines;
+        sorted_matches(
+            self.results.clone(),
+            self.num_results,
+            &self.matcher.config,
+            |i| lines[i].path(),
+        )
+    }
+
+    /// The final results, in `MatcherConfig::ascending` order, if all lines have been processed.
+    /// Unlike `process`, this doesn't copy the results.
+    pub fn done_results(&self) -> Option<&[Match]> {
+        self.sorted_results.as_deref()
     }
 
+    /// Process up to `num_lines` more lines. Once every line has been processed the sorted
+    /// results are cached, so polling a finished matcher only copies them.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            skip(self),
+            fields(progressed_to = self.progressed_to, lines = self.lines.len())
+        )
+    )]
     pub fn process(&mut self, num_lines: usize) -> Result<Progress> {
-        if self.progressed_to == self.lines.len() {
-            return Ok(Progress::Done(self.results.clone().into_sorted_vec()));
+        if let Some(sorted) = &self.sorted_results {
+            return Ok(Progress::Done(sorted.clone()));
         }
 
         let ending_progressed_to = (self.progressed_to + num_lines).min(self.lines.len());
-        let new_matches = self.matcher.best_matches(
-            self.query,
-            self.context,
-            self.num_results as u64,
-            &self.lines[self.progressed_to..ending_progressed_to],
-        )?;
+        let pattern = self.matcher.optional_pattern(self.query)?;
+        let never = AtomicBool::new(false);
+        // Not `best_matches`, which would cut the chunk's ties with `MatcherConfig::diversify`
+        let new_matches = self
+            .matcher
+            .select_matches(
+                pattern.as_ref(),
+                self.context,
+                self.num_results,
+                &self.lines[self.progressed_to..ending_progressed_to],
+                &never,
+            )
+            .unwrap_or_else(|| unreachable!("the chunk was not cancelled"));
         for mm in new_matches {
             let m = Match {
                 index: mm.index + self.progressed_to,
                 ..mm
             };
-            // Have room for more matches
-            if self.results.len() < self.num_results {
-                self.results.push(m);
-            } else {
-                // add match if it is bigger than the smallest best one we've found so far.
-                match self.results.peek() {
-                    Some(smallest) if &m > smallest => {
-                        self.results.pop();
-                        self.results.push(m);
+            if self.matcher.config.group_by_path {
+                // Chunks are grouped separately, so a path may already have a result from an
+                // earlier chunk.
+                let path = self.lines[m.index].path();
+                match self
+                    .results
+                    .iter()
+                    .find(|r| self.lines[r.index].path() == path)
+                {
+                    Some(existing) if existing >= &m => continue,
+                    Some(existing) => {
+                        let existing = existing.index;
+                        let kept = self
+                            .results
+                            .drain()
+                            .filter(|r| r.index != existing)
+                            .collect::<Vec<_>>();
+                        self.results.extend(kept);
                     }
-                    _ => (),
+                    None => (),
                 }
             }
+            push_top(
+                &mut self.results,
+                self.num_results,
+                self.matcher.config.diversify,
+                m,
+            );
         }
         self.progressed_to = ending_progressed_to;
+        if let Some(progress) = &self.progress_send {
+            let _ = progress.try_send(self.stats());
+        }
         if self.progressed_to == self.lines.len() {
-            Ok(Progress::Done(self.results.clone().into_sorted_vec()))
+            let lines = &self.lines;
+            let sorted = sorted_matches(
+                self.results.clone(),
+                self.num_results,
+                &self.matcher.config,
+                |i| lines[i].path(),
+            );
+            self.sorted_results = Some(sorted.clone());
+            Ok(Progress::Done(sorted))
         } else {
             Ok(Progress::Working)
         }
     }
 }
 
+/// Version of the `Matcher::to_bytes` format. bincode isn't self-describing, so this must be
+/// bumped whenever `MatcherState` serializes differently, including any change to the fields of
+/// `MatcherConfig`.
+pub const STATE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct MatcherState {
+    config: MatcherConfig,
+    frequency: FrequencySnapshot,
+    directory_frequency: FrequencySnapshot,
+}
+
+#[derive(Serialize, Deserialize)]
+struct FrequencySnapshot {
+    capacity: usize,
+    clock: usize,
+    /// Cached entries ordered from least to most recently used.
+    entries: Vec<(String, usize)>,
+    /// Demoted entries ordered from least to most recently demoted.
+    demoted: Vec<String>,
+}
+
+/// Clock value at which `FrequencyCounter` renormalizes its stored times. Far below where
+/// `usize` overflows or `f64` loses precision, renormalizing only touches the cache's entries so
+/// doing it often is cheap.
+const RENORMALIZE_AT: usize = 1 << 20;
+/// `exp(-MAX_AGE)` is 0 as an `f64`.
+const MAX_AGE: usize = 1000;
+/// Most entries `FrequencyCounter` keeps demoted. Past this the least recently demoted entry is
+/// forgotten, so the set and the state saved by `Matcher::to_bytes` stay bounded.
+pub const MAX_DEMOTED: usize = 256;
+
 struct FrequencyCounter {
     cache: LruCache<String, usize>,
     clock: usize,
+    /// Entries that score -1 until they are next updated, or until `MAX_DEMOTED` newer ones
+    /// push them out. Only the keys are used.
+    demoted: LruCache<String, ()>,
 }
 
 impl FrequencyCounter {
@@ -278,15 +2938,92 @@ impl FrequencyCounter {
         Ok(FrequencyCounter {
             cache: LruCache::new(20),
             clock: 0,
+            demoted: LruCache::new(MAX_DEMOTED),
+        })
+    }
+
+    /// Every path with a nonzero score, in no particular order.
+    fn paths(&self) -> impl Iterator<Item = String> + '_ {
+        self.cache.iter().map(|(k, _)| k.clone())
+    }
+
+    /// Every path with its score, most recently updated first.
+    fn scored_paths(&self) -> Vec<(String, f64)> {
+        self.cache
+            .iter()
+            .map(|(path, _)| (path.clone(), self.score(path)))
+            .collect()
+    }
+
+    fn snapshot(&self) -> FrequencySnapshot {
+        FrequencySnapshot {
+            capacity: self.cache.cap(),
+            clock: self.clock,
+            entries: self
+                .cache
+                .iter()
+                .rev()
+                .map(|(k, v)| (k.clone(), *v))
+                .collect(),
+            demoted: self.demoted.iter().rev().map(|(k, _)| k.clone()).collect(),
+        }
+    }
+
+    fn from_snapshot(snapshot: FrequencySnapshot) -> Result<Self> {
+        if snapshot.capacity == 0 {
+            return Err(anyhow!("Frequency cache capacity must be nonzero"));
+        }
+        let mut cache = LruCache::new(snapshot.capacity);
+        for (entry, time) in snapshot.entries {
+            cache.put(entry, time);
+        }
+        let mut demoted = LruCache::new(MAX_DEMOTED);
+        for entry in snapshot.demoted {
+            demoted.put(entry, ());
+        }
+        Ok(FrequencyCounter {
+            cache,
+            clock: snapshot.clock,
+            demoted,
         })
     }
 
     pub fn update(&mut self, entry: &str) {
+        self.demoted.pop(&entry.to_string());
         self.clock += 1;
         self.cache.put(entry.to_string(), self.clock);
+        if self.clock >= RENORMALIZE_AT {
+            self.renormalize();
+        }
+    }
+
+    /// Shift the clock and all stored times down so they stay small enough to convert to `f64`
+    /// exactly. Scores only depend on the difference between a time and the clock, so they are
+    /// unchanged, except that entries more than `MAX_AGE` old are clamped to that age. Their
+    /// scores already round to 0.
+    fn renormalize(&mut self) {
+        let oldest = self
+            .cache
+            .iter()
+            .map(|(_, t)| *t)
+            .min()
+            .unwrap_or(self.clock);
+        let shift = oldest.max(self.clock - MAX_AGE);
+        for (_, t) in self.cache.iter_mut() {
+            *t = t.saturating_sub(shift);
+        }
+        self.clock -= shift;
+    }
+
+    pub fn demote(&mut self, entry: &str) {
+        self.cache.pop(&entry.to_string());
+        self.demoted.put(entry.to_string(), ());
     }
 
     pub fn score(&self, entry: &str) -> f64 {
+        if self.demoted.contains(&entry.to_string()) {
+            return -1.;
+        }
         match self.cache.peek(&entry.to_string()) {
             // TODO: should not have to do str -> String
             Some(c) => (*c as f64 - self.clock as f64).exp(),
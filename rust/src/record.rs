@@ -0,0 +1,110 @@
+//! Recording real queries in the format `sylph --test-file` reads, to build a regression corpus
+//! from actual use.
+
+use anyhow::Result;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+use super::matcher::*;
+
+#[derive(Serialize)]
+struct RecordLine<'a> {
+    line: &'a str,
+    path: &'a str,
+}
+
+impl<'a> RecordLine<'a> {
+    fn new<L: Line>(line: &'a L) -> Self {
+        RecordLine {
+            line: line.line(),
+            path: line.path(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    query: &'a str,
+    launched_from: &'a str,
+    lines: Vec<RecordLine<'a>>,
+    selected: RecordLine<'a>,
+}
+
+/// Write one `--test-file` record: the query, where the finder was launched from, the lines it
+/// was matched against and the line the user selected. Records are single lines of JSON.
+pub fn write_test_record<L: Line>(
+    out: &mut impl Write,
+    query: &str,
+    launched_from: &str,
+    lines: &[L],
+    selected: &L,
+) -> Result<()> {
+    let record = Record {
+        query,
+        launched_from,
+        lines: lines.iter().map(RecordLine::new).collect(),
+        selected: RecordLine::new(selected),
+    };
+    serde_json::to_writer(&mut *out, &record)?;
+    out.write_all(b"\n")?;
+    Ok(())
+}
+
+struct RecordedQuery {
+    query: String,
+    launched_from: String,
+    lines: Vec<OwnedLine>,
+}
+
+/// Appends each query the user finished with a selection to a file, with `write_test_record`.
+pub struct Recorder {
+    file: File,
+    /// The most recent query, waiting for the user to select a line from it.
+    last: Mutex<Option<RecordedQuery>>,
+}
+
+impl Recorder {
+    /// Append records to `path`, creating it if needed.
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Recorder {
+            file: OpenOptions::new().create(true).append(true).open(path)?,
+            last: Mutex::new(None),
+        })
+    }
+
+    /// Remember a query, replacing the previous one.
+    pub fn set_query<L: Line>(&self, query: &str, launched_from: &str, lines: &[L]) {
+        *self.last.lock().unwrap() = Some(RecordedQuery {
+            query: query.to_string(),
+            launched_from: launched_from.to_string(),
+            lines: lines
+                .iter()
+                .map(|l| OwnedLine {
+                    path: l.path().to_string(),
+                    line: l.line().to_string(),
+                })
+                .collect(),
+        });
+    }
+
+    /// Write the last query with `selected` as the line the user picked. Does nothing if there
+    /// was no query since the last selection.
+    pub fn record_selection<L: Line>(&self, selected: &L) -> Result<()> {
+        match self.last.lock().unwrap().take() {
+            Some(last) => write_test_record(
+                &mut &self.file,
+                &last.query,
+                &last.launched_from,
+                &last.lines,
+                &OwnedLine {
+                    path: selected.path().to_string(),
+                    line: selected.line().to_string(),
+                },
+            ),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use filter::{AsyncMatcher, Matcher, OwnedLine, CANCEL_CHECK_INTERVAL};
+use futures::stream;
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier};
+use tokio::sync::Notify;
+use tokio_stream::wrappers::ReceiverStream;
+
+fn lines(n: usize) -> Vec<OwnedLine> {
+    (0..n)
+        .map(|i| OwnedLine {
+            path: format!("src/file{}.rs", i),
+            line: format!("src/file{}.rs", i),
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn query_resolves_with_matches() {
+    let matcher = AsyncMatcher::new(Matcher::new().unwrap());
+    matcher.update("src/file3.rs").await.unwrap();
+    let results = matcher
+        .query("file".to_string(), "".to_string(), 2, lines(10))
+        .await
+        .unwrap();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0].index, 3);
+}
+
+#[tokio::test]
+async fn dropping_query_cancels_it() {
+    let scored = Arc::new(AtomicUsize::new(0));
+    let started = Arc::new(Notify::new());
+    let release = Arc::new(Barrier::new(2));
+    let mut matcher = Matcher::new().unwrap();
+    {
+        let (scored, started, release) = (scored.clone(), started.clone(), release.clone());
+        // The first line holds the scoring until the query has been dropped
+        matcher.set_preprocess(Some(Box::new(move |line: &str| {
+            if scored.fetch_add(1, Ordering::SeqCst) == 0 {
+                started.notify_one();
+                release.wait();
+            }
+            Cow::Borrowed(line)
+        })));
+    }
+    let matcher = AsyncMatcher::new(matcher);
+    let num_lines = 100_000;
+    tokio::select! {
+        _ = matcher.query("file".to_string(), "".to_string(), 10, lines(num_lines)) => {
+            panic!("the query finished while held")
+        }
+        _ = started.notified() => {}
+    }
+    release.wait();
+    // Waits for the running query to stop
+    matcher.update("src/file1.rs").await.unwrap();
+    assert!(scored.load(Ordering::SeqCst) <= CANCEL_CHECK_INTERVAL);
+}
+
+#[tokio::test]
+async fn feed_stream_matches_batch() {
+    let matcher = Matcher::new().unwrap();
+    let fed = lines(1000);
+    let batch = matcher.best_matches("fle1", "", 10, &fed).unwrap();
+    let mut inc_matcher = matcher
+        .incremental_match("fle1", "", 10, Vec::new())
+        .unwrap();
+    assert_eq!(
+        inc_matcher.feed_stream(stream::iter(fed)).await.unwrap(),
+        batch
+    );
+    assert_eq!(inc_matcher.stats(), (1000, 1000));
+}
+
+#[tokio::test]
+async fn feed_stream_matches_batch_from_channel() {
+    let matcher = Matcher::new().unwrap();
+    let (sender, receiver) = tokio::sync::mpsc::channel(16);
+    let sent = lines(1000);
+    let batch = matcher.best_matches("fle1", "", 10, &sent).unwrap();
+    tokio::spawn(async move {
+        for line in sent {
+            sender.send(line).await.unwrap();
+        }
+    });
+    let mut inc_matcher = matcher
+        .incremental_match("fle1", "", 10, Vec::new())
+        .unwrap();
+    assert_eq!(
+        inc_matcher
+            .feed_stream(ReceiverStream::new(receiver))
+            .await
+            .unwrap(),
+        batch
+    );
+    assert_eq!(inc_matcher.stats(), (1000, 1000));
+}
@@ -0,0 +1,24 @@
+#include <assert.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <string.h>
+
+#include "bindings.h"
+
+int main(void) {
+  Matcher *matcher = sylph_matcher_new();
+  assert(matcher != NULL);
+  SylphLine lines[] = {
+      {"src/main.rs", "src/main.rs"},
+      {"src/matcher.rs", "src/matcher.rs"},
+  };
+  size_t len = 0;
+  Match *matches = sylph_best_matches(matcher, "matcher", "", 10, lines, 2, &len);
+  assert(matches != NULL);
+  assert(len == 1);
+  assert(matches[0].index == 1);
+  sylph_matches_free(matches, len);
+  sylph_matcher_free(matcher);
+  return 0;
+}
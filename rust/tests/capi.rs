@@ -0,0 +1,136 @@
+use filter::*;
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::ptr;
+
+#[test]
+fn best_matches_through_c_api() {
+    let paths = ["src/main.rs", "src/matcher.rs"]
+        .iter()
+        .map(|p| CString::new(*p).unwrap())
+        .collect::<Vec<_>>();
+    let lines = paths
+        .iter()
+        .map(|p| SylphLine {
+            path: p.as_ptr(),
+            line: p.as_ptr(),
+        })
+        .collect::<Vec<_>>();
+    let query = CString::new("matcher").unwrap();
+    let context = CString::new("").unwrap();
+    unsafe {
+        let matcher = sylph_matcher_new();
+        assert!(!matcher.is_null());
+        let mut len = 0;
+        let mtchs = sylph_best_matches(
+            matcher,
+            query.as_ptr(),
+            context.as_ptr(),
+            10,
+            lines.as_ptr(),
+            lines.len(),
+            &mut len,
+        );
+        assert!(!mtchs.is_null());
+        assert_eq!(len, 1);
+        assert_eq!((*mtchs).index, 1);
+        sylph_matches_free(mtchs, len);
+
+        let mtchs = sylph_best_matches(
+            matcher,
+            ptr::null(),
+            context.as_ptr(),
+            10,
+            lines.as_ptr(),
+            lines.len(),
+            &mut len,
+        );
+        assert!(mtchs.is_null());
+        assert_eq!(len, 0);
+        sylph_matcher_free(matcher);
+    }
+}
+
+/// Test binaries live in target/<profile>/deps, next to the libraries' directory.
+fn lib_dir() -> PathBuf {
+    std::env::current_exe()
+        .unwrap()
+        .parent()
+        .unwrap()
+        .parent()
+        .unwrap()
+        .to_path_buf()
+}
+
+/// Compile a C program from `tests` against the generated header, linked with `link_args`.
+fn compile(source: &str, exe_name: &str, link_args: &[String]) -> PathBuf {
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let exe = lib_dir().join(exe_name);
+    let status = Command::new("cc")
+        .arg(manifest_dir.join("tests").join(source))
+        .arg("-I")
+        .arg(manifest_dir.join("target"))
+        .args(link_args)
+        .arg("-o")
+        .arg(&exe)
+        .status()
+        .expect("a C compiler is required");
+    assert!(status.success(), "failed to compile tests/{}", source);
+    exe
+}
+
+/// Run a script from `tests/lua` in LuaJIT with the `filter` module loaded.
+fn run_lua(script: &str) {
+    let luajit = Command::new("pkg-config")
+        .args(["--cflags", "--libs", "luajit"])
+        .output()
+        .expect("pkg-config is required to find luajit");
+    let lib_dir = lib_dir();
+    let mut link_args = vec![
+        "-L".to_string(),
+        lib_dir.display().to_string(),
+        format!("-Wl,-rpath,{}", lib_dir.display()),
+        "-lfilter".to_string(),
+    ];
+    link_args.extend(
+        String::from_utf8(luajit.stdout)
+            .unwrap()
+            .split_whitespace()
+            .map(str::to_string),
+    );
+    let exe = compile(
+        "lua.c",
+        &format!("lua_test_{}", script.trim_end_matches(".lua")),
+        &link_args,
+    );
+    let script = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/lua")
+        .join(script);
+    assert!(Command::new(&exe).arg(script).status().unwrap().success());
+}
+
+/// Compile `tests/capi.c` against the generated header and the static library, then run it.
+/// Nothing links Lua, like a host that only uses the C API.
+#[test]
+fn c_program_links_against_bindings() {
+    let link_args = [
+        lib_dir().join("libfilter.a").display().to_string(),
+        "-Wl,--gc-sections".to_string(),
+        "-lpthread".to_string(),
+        "-ldl".to_string(),
+        "-lm".to_string(),
+    ];
+    let exe = compile("capi.c", "capi_test", &link_args);
+    assert!(Command::new(&exe).status().unwrap().success());
+}
+
+#[test]
+fn lua_columnar_results_match_tables() {
+    run_lua("columnar.lua");
+}
+
+#[test]
+fn lua_config_round_trips() {
+    run_lua("config.lua");
+}
@@ -0,0 +1,29 @@
+#include <lauxlib.h>
+#include <lua.h>
+#include <lualib.h>
+#include <stdio.h>
+
+int luaopen_filter(lua_State *L);
+
+/* Runs the Lua script given as the only argument with `require("filter")` loading the module
+ * from the linked library. */
+int main(int argc, char **argv) {
+  if (argc != 2) {
+    fprintf(stderr, "usage: %s script.lua\n", argv[0]);
+    return 2;
+  }
+  lua_State *L = luaL_newstate();
+  luaL_openlibs(L);
+  lua_getglobal(L, "package");
+  lua_getfield(L, -1, "preload");
+  lua_pushcfunction(L, luaopen_filter);
+  lua_setfield(L, -2, "filter");
+  lua_pop(L, 2);
+  if (luaL_dofile(L, argv[1]) != 0) {
+    fprintf(stderr, "%s\n", lua_tostring(L, -1));
+    lua_close(L);
+    return 1;
+  }
+  lua_close(L);
+  return 0;
+}
@@ -0,0 +1,50 @@
+-- get_result_columnar returns the same matches as get_result, one array per field.
+local filter = require("filter")
+
+local function wait(matcher, get)
+  for _ = 1, 100000000 do
+    local result, err = get(matcher)
+    assert(err == nil, err)
+    if result ~= nil then
+      return result
+    end
+  end
+  error("timed out waiting for results")
+end
+
+local lines = {}
+for i = 1, 150 do
+  local path = "src/file" .. i .. ".rs"
+  lines[i] = { location = { path = path }, line = path }
+end
+
+local matcher = filter.threaded_matcher()
+matcher:query("file", "", 100, lines)
+local columns = wait(matcher, matcher.get_result_columnar)
+matcher:query("file", "", 100, lines)
+local tables = wait(matcher, matcher.get_result)
+
+assert(#tables == 100, #tables)
+local fields = {
+  "index",
+  "score",
+  "context_score",
+  "query_score",
+  "raw_query_score",
+  "frequency_score",
+  "open_score",
+  "stable_key",
+  "matched_basename",
+}
+local count = 0
+for _ in pairs(columns) do
+  count = count + 1
+end
+assert(count == #fields, "unexpected columns")
+for _, field in ipairs(fields) do
+  local column = columns[field]
+  assert(#column == #tables, field)
+  for i, m in ipairs(tables) do
+    assert(column[i] == m[field], field .. " differs at " .. i)
+  end
+end
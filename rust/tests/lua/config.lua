@@ -0,0 +1,43 @@
+-- get_config returns a table that set_config takes back unchanged.
+local filter = require("filter")
+
+local function equal(a, b)
+  if type(a) ~= "table" or type(b) ~= "table" then
+    return a == b
+  end
+  for k, v in pairs(a) do
+    if not equal(v, b[k]) then
+      return false
+    end
+  end
+  for k in pairs(b) do
+    if a[k] == nil then
+      return false
+    end
+  end
+  return true
+end
+
+local matcher = filter.threaded_matcher()
+local default = matcher:get_config()
+assert(type(default) == "table")
+assert(default.frequency_weight ~= nil)
+matcher:set_config(default)
+assert(equal(matcher:get_config(), default), "round trip changed the default config")
+
+-- Keys that were set come back, the rest keep their values
+matcher:set_config({ frequency_weight = 3, typo_tolerance = 2, group_by_path = true })
+local config = matcher:get_config()
+assert(config.frequency_weight == 3)
+assert(config.typo_tolerance == 2)
+assert(config.group_by_path == true)
+for k, v in pairs(default) do
+  if k ~= "frequency_weight" and k ~= "typo_tolerance" and k ~= "group_by_path" then
+    assert(equal(config[k], v), k .. " changed")
+  end
+end
+
+-- A config read from one matcher configures another the same way
+local other = filter.threaded_matcher()
+other:set_config(config)
+assert(equal(other:get_config(), config), "config did not carry over")
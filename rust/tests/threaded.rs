@@ -0,0 +1,325 @@
+use anyhow::Result;
+use filter::{
+    lines_from_columns, FrequencyEntry, Match, Matcher, MatcherConfig, OwnedLine, ThreadedMatcher,
+};
+use std::thread;
+use std::time::Duration;
+
+fn file_lines(paths: &[&str]) -> Vec<OwnedLine> {
+    paths
+        .iter()
+        .map(|p| OwnedLine {
+            path: p.to_string(),
+            line: p.to_string(),
+        })
+        .collect()
+}
+
+fn wait_for_result(matcher: &ThreadedMatcher) -> Vec<Match> {
+    wait_for_result_or_err(matcher).unwrap()
+}
+
+fn wait_for_result_or_err(matcher: &ThreadedMatcher) -> Result<Vec<Match>> {
+    for _ in 0..1000 {
+        if let Some(result) = matcher.get_result() {
+            return result;
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+    panic!("timed out waiting for results");
+}
+
+#[test]
+fn set_config_applies_to_later_queries() {
+    let lines = file_lines(&["src/a.rs", "src/b.rs"]);
+    let mut matcher = ThreadedMatcher::new();
+    matcher.update("src/a.rs");
+    matcher.query("", "", 2, &lines);
+    assert_eq!(wait_for_result(&matcher)[0].index, 0);
+
+    matcher.set_config(MatcherConfig {
+        frequency_weight: 0.,
+        ..MatcherConfig::default()
+    });
+    matcher.query("", "", 2, &lines);
+    // Without frequency the lines tie and the later one wins
+    let results = wait_for_result(&matcher);
+    assert_eq!(results[0].index, 1);
+    assert!(results.iter().all(|m| m.frequency_score == 0.));
+}
+
+#[test]
+fn query_more_extends_the_last_query() {
+    let lines = file_lines(&["src/main.rs", "src/matcher.rs", "tests/matcher.rs"]);
+    let mut matcher = ThreadedMatcher::new();
+    let id = matcher.query("mat", "", 1, &lines);
+    assert_eq!(wait_for_result(&matcher).len(), 1);
+
+    let more_id = matcher.query_more(id, 2);
+    let expected = Matcher::new()
+        .unwrap()
+        .best_matches("mat", "", 3, &lines)
+        .unwrap();
+    assert_eq!(wait_for_result(&matcher), expected);
+
+    // The original query was superseded by the extended one
+    matcher.query_more(id, 1);
+    assert!(wait_for_result_or_err(&matcher).is_err());
+    let more_id = matcher.query_more(more_id, 1);
+    assert_eq!(wait_for_result(&matcher), expected);
+
+    // Asking for more results than fit in a usize gets all of them
+    matcher.query_more(more_id, usize::MAX);
+    assert_eq!(wait_for_result(&matcher), expected);
+}
+
+#[test]
+fn match_sync_agrees_with_threaded_query() {
+    let lines = file_lines(&[
+        "src/main.rs",
+        "src/matcher.rs",
+        "src/ffi.rs",
+        "tests/matcher.rs",
+    ]);
+    let mut matcher = ThreadedMatcher::new();
+    matcher.update("tests/matcher.rs");
+    matcher.query("mat", "", 3, &lines);
+    let threaded = wait_for_result(&matcher);
+    assert_eq!(matcher.match_sync("mat", "", 3, &lines).unwrap(), threaded);
+    assert!(threaded[0].frequency_score > 0.);
+}
+
+#[test]
+fn progress_reports_finished_query() {
+    let lines = file_lines(&["src/main.rs", "src/matcher.rs", "src/ffi.rs"]);
+    let mut matcher = ThreadedMatcher::new();
+    assert_eq!(matcher.progress().unwrap(), (0, 0));
+    matcher.query("mat", "", 2, &lines);
+    wait_for_result(&matcher);
+    assert_eq!(matcher.progress().unwrap(), (3, 3));
+}
+
+#[test]
+fn columnar_lines_match_like_tables() {
+    let paths = vec!["src/main.rs".to_string(), "src/matcher.rs".to_string()];
+    let texts = vec!["fn main".to_string(), "struct Matcher".to_string()];
+    let lines = lines_from_columns(paths.clone(), texts.clone()).unwrap();
+    assert_eq!(lines[1].path, paths[1]);
+    assert_eq!(lines[1].line, texts[1]);
+    assert!(lines_from_columns(paths, texts[..1].to_vec()).is_err());
+
+    let mut matcher = ThreadedMatcher::new();
+    matcher.query_owned("mat", "", 2, lines.clone());
+    assert_eq!(
+        wait_for_result(&matcher),
+        matcher.match_sync("mat", "", 2, &lines).unwrap()
+    );
+}
+
+#[test]
+fn commands_during_a_query_do_not_drop_its_results() {
+    let paths = (0..50_000)
+        .map(|i| format!("src/file{}.rs", i))
+        .collect::<Vec<_>>();
+    let lines = file_lines(&paths.iter().map(|p| p.as_str()).collect::<Vec<_>>());
+    let mut matcher = ThreadedMatcher::new();
+    matcher.query("file", "", 5, &lines);
+    matcher.update("src/file7.rs");
+    matcher.set_config(MatcherConfig::default());
+    assert_eq!(wait_for_result(&matcher).len(), 5);
+    // Delivered exactly once
+    thread::sleep(Duration::from_millis(50));
+    assert!(matcher.get_result().is_none());
+
+    // The update was applied once the query finished
+    matcher.query("file7.rs", "", 1, &lines);
+    assert!(wait_for_result(&matcher)[0].frequency_score > 0.);
+}
+
+#[test]
+fn unread_results_do_not_pile_up() {
+    let lines = file_lines(&["src/main.rs", "src/matcher.rs"]);
+    let mut matcher = ThreadedMatcher::new();
+    for _ in 0..100 {
+        matcher.query("ma", "", 2, &lines);
+        // Each query drops the unread result of the one before, so only its own gets queued
+        while matcher.queued_results() == 0 {
+            thread::yield_now();
+        }
+        assert_eq!(matcher.queued_results(), 1);
+    }
+    assert_eq!(wait_for_result(&matcher).len(), 2);
+    assert_eq!(matcher.queued_results(), 0);
+}
+
+#[test]
+fn queued_results_are_capped() {
+    let lines = file_lines(&["src/main.rs", "src/matcher.rs"]);
+    let mut matcher = ThreadedMatcher::new();
+    matcher.set_max_queued_results(3);
+    for _ in 0..100 {
+        matcher.match_now("ma", "", 2, lines.clone());
+    }
+    // Answered once the worker has handled every query before it
+    matcher.progress().unwrap();
+    assert!(matcher.queued_results() <= 3);
+    // The newest result is never the one dropped
+    assert_eq!(wait_for_result(&matcher).len(), 2);
+}
+
+#[test]
+fn set_exclude_globs_applies_to_worker_and_sync() {
+    let lines = file_lines(&["node_modules/react/index.js", "src/index.js"]);
+    let mut matcher = ThreadedMatcher::new();
+    matcher.set_exclude_globs(&["node_modules/**"]).unwrap();
+    matcher.query("index", "", 5, &lines);
+    assert_eq!(wait_for_result(&matcher).len(), 1);
+    assert_eq!(matcher.match_sync("index", "", 5, &lines).unwrap().len(), 1);
+    assert!(matcher.set_exclude_globs(&["src/[a"]).is_err());
+}
+
+#[test]
+fn records_queries_with_their_selection() {
+    let path = std::env::temp_dir().join(format!("sylph-record-{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let lines = file_lines(&["src/main.rs", "src/matcher.rs"]);
+    let mut matcher = ThreadedMatcher::new();
+    // Selecting without a query records nothing
+    matcher.set_record_file(Some(&path)).unwrap();
+    matcher.record_selection(&lines[0]).unwrap();
+    matcher.query("mat", "", 2, &lines);
+    wait_for_result(&matcher);
+    matcher.record_selection(&lines[1]).unwrap();
+    matcher.match_sync("ma", "src/lib.rs", 2, &lines).unwrap();
+    matcher.record_selection(&lines[0]).unwrap();
+    matcher.set_record_file(None).unwrap();
+
+    let recorded = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let records = recorded
+        .lines()
+        .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["query"], "mat");
+    assert_eq!(records[0]["selected"]["path"], "src/matcher.rs");
+    assert_eq!(records[1]["launched_from"], "src/lib.rs");
+    assert_eq!(records[1]["lines"].as_array().unwrap().len(), 2);
+}
+
+#[test]
+fn match_now_agrees_with_query() {
+    let lines = file_lines(&[
+        "src/main.rs",
+        "src/matcher.rs",
+        "src/ffi.rs",
+        "tests/matcher.rs",
+    ]);
+    let mut matcher = ThreadedMatcher::new();
+    matcher.update("src/ffi.rs");
+    matcher.query("rs", "", 3, &lines);
+    let queried = wait_for_result(&matcher);
+    let id = matcher.match_now("rs", "", 3, lines.clone());
+    assert_eq!(wait_for_result(&matcher), queried);
+    // The lines are kept for `query_more` like any other query
+    matcher.query_more(id, 1);
+    assert_eq!(wait_for_result(&matcher).len(), 4);
+}
+
+#[test]
+fn frequency_round_trips_through_dump_and_load() {
+    let mut matcher = ThreadedMatcher::new();
+    for path in &["src/a.rs", "src/b.rs", "src/c.rs", "src/a.rs"] {
+        matcher.update(path);
+    }
+    let dumped = matcher.dump_frequency().unwrap();
+    let paths =
+        |entries: &[FrequencyEntry]| entries.iter().map(|e| e.path.clone()).collect::<Vec<_>>();
+    assert_eq!(paths(&dumped), vec!["src/b.rs", "src/c.rs", "src/a.rs"]);
+
+    let mut loaded = ThreadedMatcher::new();
+    loaded.load_frequency(dumped.clone());
+    assert_eq!(paths(&loaded.dump_frequency().unwrap()), paths(&dumped));
+    // The worker got the history too
+    let lines = file_lines(&["src/a.rs", "src/b.rs", "src/c.rs"]);
+    loaded.query("rs", "", 3, &lines);
+    let indices = wait_for_result(&loaded)
+        .iter()
+        .map(|m| m.index)
+        .collect::<Vec<_>>();
+    assert_eq!(indices, vec![0, 2, 1]);
+}
+
+#[test]
+fn config_reads_back_what_was_set() {
+    let mut matcher = ThreadedMatcher::new();
+    assert_eq!(matcher.config(), &MatcherConfig::default());
+    let config = MatcherConfig {
+        frequency_weight: 3.,
+        group_by_path: true,
+        typo_tolerance: Some(2),
+        ..MatcherConfig::default()
+    };
+    matcher.set_config(config.clone());
+    assert_eq!(matcher.config(), &config);
+    assert_eq!(
+        Matcher::with_config(config.clone()).unwrap().config(),
+        &config
+    );
+}
+
+#[test]
+fn reset_drops_the_last_query() {
+    let paths = (0..200_000)
+        .map(|i| format!("src/file{}.rs", i))
+        .collect::<Vec<_>>();
+    let lines = file_lines(&paths.iter().map(|p| p.as_str()).collect::<Vec<_>>());
+    let mut matcher = ThreadedMatcher::new();
+    let id = matcher.query("file", "", 10, &lines);
+    matcher.reset();
+    // Waits for the worker to handle the reset, whether or not the query had finished
+    assert_eq!(matcher.progress().unwrap(), (0, 0));
+    assert!(matcher.get_result().is_none());
+    // Its lines are gone, so it can't be extended
+    matcher.query_more(id, 1);
+    assert!(wait_for_result_or_err(&matcher).is_err());
+
+    let small = file_lines(&["src/matcher.rs", "tests/matcher.rs"]);
+    matcher.query("mat", "", 2, &small);
+    assert_eq!(wait_for_result(&matcher).len(), 2);
+    assert_eq!(matcher.progress().unwrap(), (2, 2));
+}
+
+#[test]
+fn lines_fed_before_a_query_are_matched() {
+    let fed = file_lines(&["src/matcher.rs", "README.md"]);
+    let lines = file_lines(&["tests/matcher.rs", "src/main.rs"]);
+    let mut matcher = ThreadedMatcher::new();
+    matcher.feed(fed[..1].to_vec());
+    matcher.feed(fed[1..].to_vec());
+    matcher.query("mat", "", 10, &lines);
+    let all = fed.iter().chain(&lines).cloned().collect::<Vec<_>>();
+    let expected = Matcher::new()
+        .unwrap()
+        .best_matches("mat", "", 10, &all)
+        .unwrap();
+    assert!(expected.iter().any(|m| m.index == 0));
+    assert_eq!(wait_for_result(&matcher), expected);
+
+    // The fed lines went to that query, the next one only has its own
+    matcher.match_now("mat", "", 10, lines.clone());
+    assert_eq!(
+        wait_for_result(&matcher),
+        Matcher::new()
+            .unwrap()
+            .best_matches("mat", "", 10, &lines)
+            .unwrap()
+    );
+
+    // Even with no lines of its own
+    matcher.feed(fed.clone());
+    matcher.match_now("read", "", 10, Vec::new());
+    let results = wait_for_result(&matcher);
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].index, 1);
+}
@@ -1,7 +1,16 @@
-use filter::{Line, Matcher, Progress};
+use filter::{
+    position_ranges, stable_key, IncrementalMatcher, Line, LiveQuery, Match, MatchMode,
+    MatchTarget, Matcher, MatcherConfig, OwnedLine, Progress, ResultDiff, ScanStats, ScoreInputs,
+    CANCEL_CHECK_INTERVAL, MAX_DEMOTED, STATE_VERSION, TELESCOPE_NO_MATCH,
+};
 use serde::Deserialize;
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Deserialize)]
 struct JSONLine {
@@ -44,8 +53,9 @@ fn incremental_same_as_batch() {
                 let mtchs_batch = matcher
                     .best_matches(&json.query, &json.launched_from, 5, &json.lines)
                     .unwrap();
-                let mut inc_matcher =
-                    matcher.incremental_match(&json.query, &json.launched_from, 5, &json.lines);
+                let mut inc_matcher = matcher
+                    .incremental_match(&json.query, &json.launched_from, 5, &json.lines)
+                    .unwrap();
                 let mut progress = inc_matcher.process(10).unwrap();
                 while progress == Progress::Working {
                     progress = inc_matcher.process(10).unwrap();
@@ -70,3 +80,2123 @@ fn incremental_same_as_batch() {
         }
     }
 }
+
+fn configured_matcher(configure: impl FnOnce(&mut MatcherConfig)) -> Matcher {
+    let mut config = MatcherConfig::default();
+    configure(&mut config);
+    Matcher::with_config(config).unwrap()
+}
+
+fn ripgrep_line() -> Vec<OwnedLine> {
+    vec![OwnedLine {
+        path: "src/matcher.rs".to_string(),
+        line: "fn score(&self)".to_string(),
+    }]
+}
+
+#[test]
+fn match_target_line_ignores_path() {
+    let matcher = Matcher::new().unwrap();
+    let mtchs = matcher
+        .best_matches("matcher", "", 5, &ripgrep_line())
+        .unwrap();
+    assert!(mtchs.is_empty());
+}
+
+#[test]
+fn match_target_path() {
+    let matcher = configured_matcher(|c| c.match_target = MatchTarget::Path);
+    let mtchs = matcher
+        .best_matches("matcher", "", 5, &ripgrep_line())
+        .unwrap();
+    assert_eq!(mtchs.len(), 1);
+    assert!(mtchs[0].query_score > 0.);
+    assert!(matcher
+        .best_matches("score", "", 5, &ripgrep_line())
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn match_target_both_takes_best() {
+    let path_matcher = configured_matcher(|c| c.match_target = MatchTarget::Path);
+    let both_matcher = configured_matcher(|c| c.match_target = MatchTarget::Both);
+    let both = both_matcher
+        .best_matches("matcher", "", 5, &ripgrep_line())
+        .unwrap();
+    let path = path_matcher
+        .best_matches("matcher", "", 5, &ripgrep_line())
+        .unwrap();
+    assert_eq!(both, path);
+    assert_eq!(
+        both_matcher
+            .best_matches("score", "", 5, &ripgrep_line())
+            .unwrap()
+            .len(),
+        1
+    );
+}
+
+#[test]
+fn zero_num_results_is_an_error() {
+    let matcher = Matcher::new().unwrap();
+    assert!(matcher.best_matches("", "", 0, &ripgrep_line()).is_err());
+    assert!(matcher
+        .incremental_match("", "", 0, &ripgrep_line())
+        .is_err());
+}
+
+#[test]
+fn huge_num_results_returns_every_match() {
+    let matcher = Matcher::new().unwrap();
+    let lines = file_lines(&["src/main.rs", "src/lib.rs", "src/ffi.rs"]);
+    assert_eq!(
+        matcher
+            .best_matches("", "", u64::MAX, &lines)
+            .unwrap()
+            .len(),
+        3
+    );
+    assert_eq!(
+        matcher
+            .best_matches_iter("rs", "", u64::MAX, lines.iter())
+            .unwrap()
+            .len(),
+        3
+    );
+    let mut inc_matcher = matcher
+        .incremental_match("rs", "", u64::MAX, lines.iter())
+        .unwrap();
+    match inc_matcher.process(3).unwrap() {
+        Progress::Done(mtchs) => assert_eq!(mtchs.len(), 3),
+        Progress::Working => panic!("all lines were processed"),
+    }
+}
+
+#[test]
+fn frequency_does_not_surface_unrelated_lines() {
+    let mut matcher = Matcher::new().unwrap();
+    let lines = vec![
+        OwnedLine {
+            path: "src/main.rs".to_string(),
+            line: "src/main.rs".to_string(),
+        },
+        OwnedLine {
+            path: "src/xyz.rs".to_string(),
+            line: "src/xyz.rs".to_string(),
+        },
+    ];
+    matcher.update("src/main.rs");
+    let mtchs = matcher.best_matches("xyz", "", 5, &lines).unwrap();
+    assert!(mtchs.iter().all(|m| m.index != 0));
+    assert!(mtchs.iter().all(|m| m.frequency_score == 0.));
+    let mtchs = matcher.best_matches("", "", 5, &lines).unwrap();
+    assert_eq!(mtchs[0].index, 0);
+    assert!(mtchs[0].frequency_score > 0.);
+}
+
+#[test]
+// Depends on the skim matcher scoring long gaps below zero
+#[cfg(feature = "fuzzy-matcher")]
+fn frequency_only_boosts_lines_with_a_positive_query_score() {
+    let weak = format!("a{}b", "_".repeat(60));
+    let lines = file_lines(&[&weak, "ab_file"]);
+    let mut matcher = Matcher::new().unwrap();
+    matcher.update(&weak);
+    matcher.update("ab_file");
+    let mtchs = matcher.best_matches("ab", "", 5, &lines).unwrap();
+    assert_eq!(mtchs.len(), 2);
+    let by_index = |i| mtchs.iter().find(|m| m.index == i).unwrap();
+    // Both lines are results, but only the one that really matches is boosted
+    assert!(by_index(0).query_score <= 0.);
+    assert_eq!(by_index(0).frequency_score, 0.);
+    assert!(by_index(1).query_score > 0.);
+    assert!(by_index(1).frequency_score > 0.);
+
+    // The empty query boosts every line
+    let mtchs = matcher.best_matches("", "", 5, &lines).unwrap();
+    assert!(mtchs.iter().all(|m| m.frequency_score > 0.));
+}
+
+#[test]
+fn min_fuzzy_len_uses_prefix_for_short_queries() {
+    let matcher = configured_matcher(|c| c.min_fuzzy_len = 2);
+    let lines = vec![
+        OwnedLine {
+            path: "src/main.rs".to_string(),
+            line: "src/main.rs".to_string(),
+        },
+        OwnedLine {
+            path: "src/ffi_m.rs".to_string(),
+            line: "src/ffi_m.rs".to_string(),
+        },
+    ];
+    let mtchs = matcher.best_matches("m", "", 5, &lines).unwrap();
+    assert_eq!(mtchs.len(), 1);
+    assert_eq!(mtchs[0].index, 0);
+    // At the threshold we fuzzy match, so a non-prefix query still matches
+    let mtchs = matcher.best_matches("mn", "", 5, &lines).unwrap();
+    assert_eq!(mtchs.len(), 1);
+    assert_eq!(mtchs[0].index, 0);
+    assert_eq!(
+        Matcher::new()
+            .unwrap()
+            .best_matches("m", "", 5, &lines)
+            .unwrap()
+            .len(),
+        2
+    );
+}
+
+#[test]
+fn matcher_bytes_round_trip() {
+    let mut matcher = configured_matcher(|c| c.match_target = MatchTarget::Both);
+    let lines = vec![
+        OwnedLine {
+            path: "src/main.rs".to_string(),
+            line: "fn main()".to_string(),
+        },
+        OwnedLine {
+            path: "src/matcher.rs".to_string(),
+            line: "fn score()".to_string(),
+        },
+        OwnedLine {
+            path: "src/ffi.rs".to_string(),
+            line: "fn filter()".to_string(),
+        },
+    ];
+    matcher.update("src/ffi.rs");
+    matcher.update("src/main.rs");
+    let restored = Matcher::from_bytes(&matcher.to_bytes()).unwrap();
+    for query in &["", "m", "src", "fn"] {
+        assert_eq!(
+            matcher.best_matches(query, "", 5, &lines).unwrap(),
+            restored.best_matches(query, "", 5, &lines).unwrap()
+        );
+    }
+    assert!(Matcher::from_bytes(&[1, 2, 3]).is_err());
+
+    // State saved by another version is rejected rather than misread
+    let mut bytes = matcher.to_bytes();
+    assert_eq!(bytes[..4], STATE_VERSION.to_le_bytes());
+    bytes[..4].copy_from_slice(&(STATE_VERSION + 1).to_le_bytes());
+    let err = Matcher::from_bytes(&bytes).err().unwrap();
+    assert!(err.to_string().contains("version"));
+}
+
+#[test]
+fn state_format_is_versioned() {
+    // A change to this length means the format changed, so bump STATE_VERSION and update it
+    assert_eq!(Matcher::new().unwrap().to_bytes().len(), 251);
+}
+
+#[test]
+fn glob_mode() {
+    let matcher = configured_matcher(|c| c.match_mode = MatchMode::Glob);
+    let lines = [
+        "src/lib.rs",
+        "src/ffi/lua.rs",
+        "src/ffi/lua.h",
+        "benches/main.rs",
+    ]
+    .iter()
+    .map(|p| OwnedLine {
+        path: p.to_string(),
+        line: p.to_string(),
+    })
+    .collect::<Vec<_>>();
+    let mtchs = matcher.best_matches("src/**/*.rs", "", 10, &lines).unwrap();
+    let mut indices = mtchs.iter().map(|m| m.index).collect::<Vec<_>>();
+    indices.sort();
+    assert_eq!(indices, vec![0, 1]);
+    assert!(matcher.best_matches("src/[", "", 10, &lines).is_err());
+}
+
+#[test]
+fn path_length_penalty_prefers_shallow_paths() {
+    let lines = ["foo.rs", "a/b/c/d/e/foo.rs"]
+        .iter()
+        .map(|p| OwnedLine {
+            path: p.to_string(),
+            line: "fn foo()".to_string(),
+        })
+        .collect::<Vec<_>>();
+    // Equal scores are broken by index, so without a penalty the deeper path wins
+    let mtchs = Matcher::new()
+        .unwrap()
+        .best_matches("foo", "", 2, &lines)
+        .unwrap();
+    assert_eq!(mtchs[0].index, 1);
+    let matcher = configured_matcher(|c| c.path_length_penalty = 0.1);
+    let mtchs = matcher.best_matches("foo", "", 2, &lines).unwrap();
+    assert_eq!(mtchs[0].index, 0);
+}
+
+fn file_lines(paths: &[&str]) -> Vec<OwnedLine> {
+    paths
+        .iter()
+        .map(|p| OwnedLine {
+            path: p.to_string(),
+            line: p.to_string(),
+        })
+        .collect()
+}
+
+fn run_to_completion<L: Line>(inc_matcher: &mut IncrementalMatcher<L>) -> Vec<Match> {
+    loop {
+        if let Progress::Done(results) = inc_matcher.process(2).unwrap() {
+            return results;
+        }
+    }
+}
+
+#[test]
+fn remove_fed_lines() {
+    let matcher = Matcher::new().unwrap();
+    let all = file_lines(&[
+        "src/main.rs",
+        "src/matcher.rs",
+        "src/ffi.rs",
+        "tests/matcher.rs",
+        "src/lib.rs",
+    ]);
+    let mut inc_matcher = matcher
+        .incremental_match("mat", "", 2, all[..3].to_vec())
+        .unwrap();
+    inc_matcher.process(2).unwrap();
+    inc_matcher.feed_lines(all[3..].to_vec());
+    assert_eq!(inc_matcher.remove_lines(|l| l.path == "src/matcher.rs"), 1);
+    let results = run_to_completion(&mut inc_matcher);
+    let surviving = all
+        .iter()
+        .filter(|l| l.path != "src/matcher.rs")
+        .collect::<Vec<_>>();
+    assert!(results
+        .iter()
+        .all(|m| surviving[m.index].path != "src/matcher.rs"));
+    assert_eq!(
+        results,
+        matcher.best_matches("mat", "", 2, &surviving).unwrap()
+    );
+
+    // Removing a line that isn't a result keeps the results, renumbered
+    assert_eq!(inc_matcher.remove_lines(|l| l.path == "src/main.rs"), 1);
+    let results = run_to_completion(&mut inc_matcher);
+    let surviving = surviving
+        .into_iter()
+        .filter(|l| l.path != "src/main.rs")
+        .collect::<Vec<_>>();
+    assert_eq!(
+        results,
+        matcher.best_matches("mat", "", 2, &surviving).unwrap()
+    );
+}
+
+#[test]
+fn polling_done_reuses_sorted_results() {
+    let matcher = Matcher::new().unwrap();
+    let lines = file_lines(&["src/main.rs", "src/matcher.rs", "tests/matcher.rs"]);
+    let mut inc_matcher = matcher.incremental_match("mat", "", 2, &lines).unwrap();
+    assert!(inc_matcher.done_results().is_none());
+    let results = run_to_completion(&mut inc_matcher);
+    let cached = inc_matcher.done_results().unwrap().as_ptr();
+    for _ in 0..1000 {
+        assert_eq!(
+            inc_matcher.process(2).unwrap(),
+            Progress::Done(results.clone())
+        );
+        assert_eq!(inc_matcher.done_results().unwrap().as_ptr(), cached);
+    }
+    inc_matcher.feed_lines(vec![&lines[0]]);
+    assert!(inc_matcher.done_results().is_none());
+}
+
+#[test]
+fn preprocess_strips_line_numbers() {
+    let lines = vec![
+        OwnedLine {
+            path: "src/a.rs".to_string(),
+            line: "42:fn main()".to_string(),
+        },
+        OwnedLine {
+            path: "src/b.rs".to_string(),
+            line: "7:let x = 42".to_string(),
+        },
+    ];
+    let mut matcher = Matcher::new().unwrap();
+    assert_eq!(matcher.best_matches("42", "", 5, &lines).unwrap().len(), 2);
+    matcher.set_preprocess(Some(Box::new(|line: &str| {
+        Cow::Borrowed(line.split_once(':').map_or(line, |(_, rest)| rest))
+    })));
+    let mtchs = matcher.best_matches("42", "", 5, &lines).unwrap();
+    assert_eq!(mtchs.len(), 1);
+    assert_eq!(mtchs[0].index, 1);
+}
+
+#[test]
+fn group_by_path_keeps_best_line_per_file() {
+    let lines = vec![
+        OwnedLine {
+            path: "src/a.rs".to_string(),
+            line: "fn matcher_new()".to_string(),
+        },
+        OwnedLine {
+            path: "src/a.rs".to_string(),
+            line: "fn matcher()".to_string(),
+        },
+        OwnedLine {
+            path: "src/b.rs".to_string(),
+            line: "struct Matcher".to_string(),
+        },
+    ];
+    let ungrouped = Matcher::new()
+        .unwrap()
+        .best_matches("matcher", "", 10, &lines)
+        .unwrap();
+    assert_eq!(ungrouped.len(), 3);
+    let best_a = ungrouped
+        .iter()
+        .find(|m| lines[m.index].path == "src/a.rs")
+        .unwrap()
+        .index;
+
+    let matcher = configured_matcher(|c| c.group_by_path = true);
+    let mut grouped = matcher.best_matches("matcher", "", 10, &lines).unwrap();
+    assert_eq!(grouped.len(), 2);
+    let mut indices = grouped.iter().map(|m| m.index).collect::<Vec<_>>();
+    indices.sort();
+    assert_eq!(indices, vec![best_a, 2]);
+
+    // Chunks of one line force grouping across chunks
+    let mut inc_matcher = matcher
+        .incremental_match("matcher", "", 10, &lines)
+        .unwrap();
+    let results = loop {
+        if let Progress::Done(results) = inc_matcher.process(1).unwrap() {
+            break results;
+        }
+    };
+    grouped.sort_by(|x, y| x.cmp(y).reverse());
+    assert_eq!(results, grouped);
+}
+
+#[test]
+fn warmup_does_not_change_results() {
+    let lines = file_lines(&["src/main.rs", "src/matcher.rs", "src/warmup.rs"]);
+    let cold = Matcher::new().unwrap();
+    let warm = Matcher::new().unwrap();
+    warm.warmup();
+    for query in &["", "warmup", "mat", "src"] {
+        assert_eq!(
+            cold.best_matches(query, "src/lib.rs", 5, &lines).unwrap(),
+            warm.best_matches(query, "src/lib.rs", 5, &lines).unwrap()
+        );
+    }
+}
+
+#[test]
+fn adaptive_chunk_size_converges() {
+    let lines = file_lines(&["src/main.rs"]);
+    let matcher = Matcher::new().unwrap();
+    let mut inc_matcher = matcher.incremental_match("main", "", 10, &lines).unwrap();
+    let target = Duration::from_millis(1);
+    // Chunks that cost `per_line` for each of their lines
+    let mut run = |per_line: Duration, chunks: usize| {
+        (0..chunks)
+            .map(|_| {
+                let size = inc_matcher.chunk_size();
+                inc_matcher.adjust_chunk_size(size, per_line * size as u32, target);
+                inc_matcher.chunk_size()
+            })
+            .collect::<Vec<_>>()
+    };
+    let sizes = run(Duration::from_micros(1), 20);
+    assert!(sizes.windows(2).all(|w| w[0] <= w[1]), "{:?}", sizes);
+    assert!((990..=1000).contains(sizes.last().unwrap()), "{:?}", sizes);
+    // Lines getting slower shrinks the chunks to match
+    let sizes = run(Duration::from_micros(4), 20);
+    assert!((245..=250).contains(sizes.last().unwrap()), "{:?}", sizes);
+}
+
+#[test]
+fn stable_key_survives_feeding_and_removal() {
+    let matcher = Matcher::new().unwrap();
+    let all = file_lines(&["src/main.rs", "src/matcher.rs", "tests/matcher.rs"]);
+    let mut inc_matcher = matcher
+        .incremental_match("", "", 10, all[..2].to_vec())
+        .unwrap();
+    let first = run_to_completion(&mut inc_matcher)
+        .into_iter()
+        .map(|m| (all[m.index].path.as_str(), (m.index, m.stable_key)))
+        .collect::<HashMap<_, _>>();
+
+    inc_matcher.feed_lines(all[2..].to_vec());
+    inc_matcher.remove_lines(|l| l.path == "src/main.rs");
+    let second = run_to_completion(&mut inc_matcher)
+        .into_iter()
+        .map(|m| (all[m.index + 1].path.as_str(), (m.index, m.stable_key)))
+        .collect::<HashMap<_, _>>();
+
+    // The index shifted when src/main.rs was removed, but the key did not
+    assert_eq!(first["src/matcher.rs"].0, 1);
+    assert_eq!(second["src/matcher.rs"].0, 0);
+    assert_eq!(first["src/matcher.rs"].1, second["src/matcher.rs"].1);
+    assert_ne!(second["src/matcher.rs"].1, second["tests/matcher.rs"].1);
+
+    // Keys don't depend on the Rust release, so saved keys stay valid
+    assert_eq!(stable_key("src/main.rs", "fn main() {}"), 8250020767373731);
+    assert_ne!(stable_key("src/main.rs", ""), stable_key("src/main.r", "s"));
+}
+
+#[test]
+fn joined_target_spans_path_and_line() {
+    let lines = vec![
+        OwnedLine {
+            path: "tests/auth.rs".to_string(),
+            line: "fn login".to_string(),
+        },
+        OwnedLine {
+            path: "src/auth.rs".to_string(),
+            line: "fn login".to_string(),
+        },
+    ];
+    let matcher = configured_matcher(|c| c.match_target = MatchTarget::Joined);
+    let results = matcher.best_matches("src login", "", 10, &lines).unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].index, 1);
+
+    // Neither the path nor the line alone contains the whole query
+    let matcher = configured_matcher(|c| c.match_target = MatchTarget::Both);
+    assert!(matcher
+        .best_matches("src login", "", 10, &lines)
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn frequency_scores_survive_many_updates() {
+    let mut matcher = Matcher::new().unwrap();
+    let lines = file_lines(&["src/old.rs", "src/a.rs", "src/b.rs", "src/c.rs"]);
+    matcher.update("src/old.rs");
+    for i in 0..10_000_000 {
+        matcher.update(&lines[1 + i % 3].path);
+    }
+    // The last update was src/a.rs, then src/c.rs before it
+    let results = matcher.best_matches("", "", 4, &lines).unwrap();
+    let order = results.iter().map(|m| m.index).collect::<Vec<_>>();
+    assert_eq!(order, vec![1, 3, 2, 0]);
+    let scores = results
+        .iter()
+        .map(|m| m.frequency_score)
+        .collect::<Vec<_>>();
+    assert!(scores.iter().all(|s| s.is_finite()));
+    assert!((scores[0] - 10.).abs() < 1e-9);
+    assert!((scores[1] - 10. * (-1f64).exp()).abs() < 1e-9);
+    assert!((scores[2] - 10. * (-2f64).exp()).abs() < 1e-9);
+    assert_eq!(scores[3], 0.);
+}
+
+#[test]
+fn typo_tolerance_matches_transposed_query() {
+    let lines = file_lines(&["src/main.rs", "src/ffi.rs"]);
+    let matcher = configured_matcher(|c| c.typo_tolerance = Some(1));
+    let results = matcher.best_matches("mian", "", 10, &lines).unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].index, 0);
+    // Exact fuzzy matches still score higher
+    let exact = matcher.best_matches("main", "", 10, &lines).unwrap();
+    assert!(exact[0].query_score > results[0].query_score);
+    // Substitutions count against the tolerance like any other edit
+    assert_eq!(
+        matcher.best_matches("mxin", "", 10, &lines).unwrap().len(),
+        1
+    );
+    assert!(matcher
+        .best_matches("mxyn", "", 10, &lines)
+        .unwrap()
+        .is_empty());
+
+    let matcher = configured_matcher(|c| c.typo_tolerance = Some(0));
+    assert!(matcher
+        .best_matches("mian", "", 10, &lines)
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn open_set_boosts_unselected_files() {
+    let lines = file_lines(&["src/aa.rs", "src/bb.rs"]);
+    let mut matcher = configured_matcher(|c| c.open_boost = 5.);
+    let results = matcher.best_matches(".rs", "", 2, &lines).unwrap();
+    assert_eq!(results[0].query_score, results[1].query_score);
+    assert_eq!(results[0].index, 1);
+
+    matcher.set_open_set(vec!["src/aa.rs".to_string()].into_iter().collect());
+    let results = matcher.best_matches(".rs", "", 2, &lines).unwrap();
+    assert_eq!(results[0].index, 0);
+    assert_eq!(results[0].frequency_score, 0.);
+    assert_eq!(results[0].open_score, 5.);
+    assert_eq!(results[1].open_score, 0.);
+    assert_eq!(results[0].score, results[1].score + results[0].open_score);
+
+    // By default open files aren't boosted
+    matcher.set_config(MatcherConfig::default());
+    let results = matcher.best_matches(".rs", "", 2, &lines).unwrap();
+    assert_eq!(results[0].index, 1);
+    assert_eq!(results[0].open_score, 0.);
+}
+
+#[test]
+fn current_file_boosts_its_siblings() {
+    let lines = file_lines(&["lib/aa.rs", "src/aa.rs", "lib/main.rs"]);
+    let mut matcher = Matcher::new().unwrap();
+    let results = matcher.best_matches("aa", "", 2, &lines).unwrap();
+    assert_eq!(results[0].score, results[1].score);
+    assert_eq!(results[0].index, 1);
+
+    matcher.set_current_file(Some("lib/main.rs".to_string()));
+    let results = matcher.best_matches("aa", "", 2, &lines).unwrap();
+    assert_eq!(results[0].index, 0);
+    assert_eq!(
+        results[0].score,
+        results[1].score + MatcherConfig::default().current_file_sibling_boost
+    );
+    assert_eq!(
+        results[0].open_score,
+        MatcherConfig::default().current_file_sibling_boost
+    );
+    // The current file isn't its own sibling
+    let main = matcher.best_matches("main", "", 1, &lines).unwrap();
+    matcher.set_current_file(None);
+    assert_eq!(main, matcher.best_matches("main", "", 1, &lines).unwrap());
+}
+
+#[test]
+fn best_matches_iter_matches_slice_version() {
+    let lines = file_lines(&[
+        "src/main.rs",
+        "src/matcher.rs",
+        "src/ffi.rs",
+        "tests/matcher.rs",
+        "src/matcher.rs",
+    ]);
+    for group_by_path in [false, true].iter() {
+        let matcher = configured_matcher(|c| c.group_by_path = *group_by_path);
+        for query in ["", "mat", "rs"].iter() {
+            let streamed = lines.iter().map(|l| OwnedLine {
+                path: l.path.clone(),
+                line: l.line.clone(),
+            });
+            assert_eq!(
+                matcher.best_matches_iter(query, "", 3, streamed).unwrap(),
+                matcher.best_matches(query, "", 3, &lines).unwrap()
+            );
+        }
+    }
+}
+
+#[test]
+// Depends on the skim matcher's scoring
+#[cfg(feature = "fuzzy-matcher")]
+fn first_char_multiplier_prefers_prefix_matches() {
+    let lines = file_lines(&["logxx", "x logx"]);
+    let results = Matcher::new()
+        .unwrap()
+        .best_matches("log", "", 2, &lines)
+        .unwrap();
+    assert_eq!(results[0].index, 0);
+    assert!(results[0].query_score > results[1].query_score);
+
+    // Without the multiplier starting the line is no better than starting a later word
+    let matcher = configured_matcher(|c| c.bonus_first_char_multiplier = 1);
+    let results = matcher.best_matches("log", "", 2, &lines).unwrap();
+    assert_eq!(results[0].query_score, results[1].query_score);
+    assert_eq!(results[0].index, 1);
+}
+
+#[test]
+fn batch_matches_single_queries() {
+    let lines = file_lines(&[
+        "src/main.rs",
+        "src/matcher.rs",
+        "src/ffi.rs",
+        "tests/matcher.rs",
+    ]);
+    let mut matcher = Matcher::new().unwrap();
+    matcher.update("src/ffi.rs");
+    matcher.set_preprocess(Some(Box::new(|line: &str| {
+        Cow::Owned(line.replace('/', " "))
+    })));
+    let queries = [
+        ("", ""),
+        ("", "src/main.rs"),
+        ("mat", ""),
+        ("ffi", "src/main.rs"),
+    ]
+    .iter()
+    .map(|(q, c)| (q.to_string(), c.to_string()))
+    .collect::<Vec<_>>();
+    let batch = matcher.best_matches_batch(&queries, 2, &lines).unwrap();
+    assert_eq!(batch.len(), queries.len());
+    for ((query, context), results) in queries.iter().zip(batch) {
+        assert_eq!(
+            results,
+            matcher.best_matches(query, context, 2, &lines).unwrap()
+        );
+    }
+}
+
+#[test]
+fn stats_track_fed_and_processed_lines() {
+    let matcher = Matcher::new().unwrap();
+    let lines = file_lines(&["a.rs", "b.rs", "c.rs", "d.rs", "e.rs"]);
+    let mut inc_matcher = matcher
+        .incremental_match("rs", "", 2, lines[..3].to_vec())
+        .unwrap();
+    assert_eq!(inc_matcher.stats(), (0, 3));
+    inc_matcher.process(2).unwrap();
+    assert_eq!(inc_matcher.stats(), (2, 3));
+    inc_matcher.feed_lines(lines[3..].to_vec());
+    assert_eq!(inc_matcher.stats(), (2, 5));
+    inc_matcher.process(2).unwrap();
+    assert_eq!(inc_matcher.stats(), (4, 5));
+    run_to_completion(&mut inc_matcher);
+    assert_eq!(inc_matcher.stats(), (5, 5));
+}
+
+#[test]
+fn cancelled_best_matches_returns_early() {
+    let paths = (0..10_000)
+        .map(|i| format!("src/file{}.rs", i))
+        .collect::<Vec<_>>();
+    let lines = file_lines(&paths.iter().map(|p| p.as_str()).collect::<Vec<_>>());
+    let cancel = Arc::new(AtomicBool::new(false));
+    let scored = Arc::new(AtomicUsize::new(0));
+    let mut matcher = Matcher::new().unwrap();
+    {
+        let cancel = cancel.clone();
+        let scored = scored.clone();
+        // Cancel after the first few lines, like a Ctrl-C handler would
+        matcher.set_preprocess(Some(Box::new(move |line: &str| {
+            if scored.fetch_add(1, Ordering::SeqCst) == 5 {
+                cancel.store(true, Ordering::SeqCst);
+            }
+            Cow::Borrowed(line)
+        })));
+    }
+    let result = matcher
+        .best_matches_cancellable("file", "", 10, &lines, &cancel)
+        .unwrap();
+    assert!(result.is_none());
+    assert!(scored.load(Ordering::SeqCst) <= CANCEL_CHECK_INTERVAL);
+
+    cancel.store(false, Ordering::SeqCst);
+    let result = matcher
+        .best_matches_cancellable("file", "", 10, &lines[..100], &cancel)
+        .unwrap();
+    assert_eq!(result.unwrap().len(), 10);
+}
+
+#[test]
+fn min_score_drops_frequency_noise() {
+    let lines = file_lines(&["src/main.rs", "src/matcher.rs"]);
+    // With no query and no history every line matches with a score of 0
+    let matcher = configured_matcher(|c| c.min_score = 0.);
+    let results = matcher.best_matches("", "", 10, &lines).unwrap();
+    assert_eq!(results.len(), 2);
+    assert!(results.iter().all(|m| m.score == 0.));
+
+    let mut matcher = configured_matcher(|c| c.min_score = 1.);
+    assert!(matcher.best_matches("", "", 10, &lines).unwrap().is_empty());
+    matcher.update("src/matcher.rs");
+    let results = matcher.best_matches("", "", 10, &lines).unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].index, 1);
+}
+
+#[test]
+fn matched_basename_flag() {
+    let lines = file_lines(&["src/matcher.rs"]);
+    let matcher = Matcher::new().unwrap();
+    let basename = matcher.best_matches("matcher", "", 1, &lines).unwrap();
+    assert!(basename[0].matched_basename);
+    let whole = matcher.best_matches("src/mat", "", 1, &lines).unwrap();
+    assert!(!whole[0].matched_basename);
+}
+
+#[test]
+fn demoted_path_ranks_below_neutral() {
+    let lines = file_lines(&["src/generated.rs", "src/main.rs"]);
+    let mut matcher = Matcher::new().unwrap();
+    matcher.update("src/generated.rs");
+    let results = matcher.best_matches("rs", "", 2, &lines).unwrap();
+    assert_eq!(results[0].index, 0);
+
+    matcher.demote("src/generated.rs");
+    let results = matcher.best_matches("rs", "", 2, &lines).unwrap();
+    assert_eq!(results[0].index, 1);
+    assert!(results[1].frequency_score < 0.);
+    // Survives a round trip through bytes
+    let restored = Matcher::from_bytes(&matcher.to_bytes()).unwrap();
+    assert_eq!(restored.best_matches("rs", "", 2, &lines).unwrap(), results);
+
+    // Selecting it again lifts the demotion
+    matcher.update("src/generated.rs");
+    let results = matcher.best_matches("rs", "", 2, &lines).unwrap();
+    assert_eq!(results[0].index, 0);
+}
+
+#[test]
+fn oldest_demotion_is_forgotten_past_the_cap() {
+    let lines = file_lines(&["src/generated.rs", "src/main.rs"]);
+    let mut matcher = Matcher::new().unwrap();
+    matcher.demote("src/generated.rs");
+    for i in 0..MAX_DEMOTED - 1 {
+        matcher.demote(&format!("other{}.rs", i));
+    }
+    let results = matcher.best_matches("rs", "", 2, &lines).unwrap();
+    assert!(results[1].frequency_score < 0.);
+
+    matcher.demote("one_more.rs");
+    let results = matcher.best_matches("rs", "", 2, &lines).unwrap();
+    assert!(results.iter().all(|m| m.frequency_score == 0.));
+}
+
+#[test]
+fn max_lines_caps_fed_lines() {
+    let matcher = configured_matcher(|c| c.max_lines = Some(3));
+    let lines = file_lines(&["a.rs", "b.rs", "c.rs", "d.rs", "e.rs"]);
+    let mut inc_matcher = matcher
+        .incremental_match("rs", "", 10, lines[..2].to_vec())
+        .unwrap();
+    assert_eq!(inc_matcher.feed_lines(lines[2..].to_vec()), 2);
+    assert_eq!(inc_matcher.stats(), (0, 3));
+    assert_eq!(inc_matcher.feed_lines(lines[..1].to_vec()), 1);
+    assert_eq!(inc_matcher.stats(), (0, 3));
+    assert_eq!(inc_matcher.dropped_lines(), 3);
+    assert_eq!(run_to_completion(&mut inc_matcher).len(), 3);
+
+    // Removing lines makes room again
+    inc_matcher.remove_lines(|l| l.path == "a.rs");
+    assert_eq!(inc_matcher.feed_lines(lines[3..4].to_vec()), 0);
+    assert_eq!(inc_matcher.stats().1, 3);
+}
+
+#[test]
+fn components_mode_matches_segments_in_order() {
+    let lines = file_lines(&[
+        "rust/src/matcher.rs",
+        "src/rust/matcher.rs",
+        "lua/rustfilter.lua",
+    ]);
+    let matcher = configured_matcher(|c| c.match_mode = MatchMode::Components);
+    let results = matcher.best_matches("r/s/mat", "", 10, &lines).unwrap();
+    // src/rust/matcher.rs has an r in src and an s in rust, but segments match from the start
+    // of a component
+    assert_eq!(results.iter().map(|m| m.index).collect::<Vec<_>>(), vec![0]);
+    let results = matcher.best_matches("s/r/mat", "", 10, &lines).unwrap();
+    assert_eq!(results.iter().map(|m| m.index).collect::<Vec<_>>(), vec![1]);
+
+    let results = matcher
+        .best_matches("rust/src/mat", "", 10, &lines)
+        .unwrap();
+    assert_eq!(results.iter().map(|m| m.index).collect::<Vec<_>>(), vec![0]);
+
+    // Without a slash the query only matches the file name
+    let results = matcher.best_matches("rust", "", 10, &lines).unwrap();
+    assert_eq!(results.iter().map(|m| m.index).collect::<Vec<_>>(), vec![2]);
+}
+
+#[test]
+fn worst_matches_are_disjoint_from_best() {
+    let lines = file_lines(&["src/matcher.rs", "tests/unit.rs", "src/ffi.rs", "README.md"]);
+    let matcher = Matcher::new().unwrap();
+    let best = matcher.best_matches("rs", "", 1, &lines).unwrap();
+    let worst = matcher.worst_matches("rs", "", 2, &lines).unwrap();
+    assert_eq!(best.len(), 1);
+    assert_eq!(worst.len(), 2);
+    assert!(worst[0] <= worst[1]);
+    assert!(worst[1] <= best[0]);
+    assert!(worst.iter().all(|w| w.index != best[0].index));
+    // README.md doesn't match at all
+    assert!(worst.iter().all(|w| w.index != 3));
+}
+
+struct NormalizedLine {
+    line: OwnedLine,
+    normalized: String,
+}
+
+impl Line for NormalizedLine {
+    fn path(&self) -> &str {
+        &self.line.path
+    }
+    fn line(&self) -> &str {
+        &self.line.line
+    }
+    fn normalized(&self) -> Option<&str> {
+        Some(&self.normalized)
+    }
+}
+
+#[test]
+fn normalized_lines_score_like_preprocessed() {
+    let strip = |line: &str| {
+        line.split_once(':')
+            .map_or(line, |(_, rest)| rest)
+            .to_string()
+    };
+    let lines = vec![
+        OwnedLine {
+            path: "src/a.rs".to_string(),
+            line: "42:fn main()".to_string(),
+        },
+        OwnedLine {
+            path: "src/b.rs".to_string(),
+            line: "7:let main = 42".to_string(),
+        },
+        OwnedLine {
+            path: "src/c.rs".to_string(),
+            line: "13:mod ffi".to_string(),
+        },
+    ];
+    let cached = lines
+        .iter()
+        .map(|l| NormalizedLine {
+            normalized: strip(&l.line),
+            line: l.clone(),
+        })
+        .collect::<Vec<_>>();
+    let calls = Arc::new(AtomicUsize::new(0));
+    let mut matcher = Matcher::new().unwrap();
+    {
+        let calls = calls.clone();
+        matcher.set_preprocess(Some(Box::new(move |line: &str| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Cow::Owned(strip(line))
+        })));
+    }
+    for &query in &["main", "42", ""] {
+        let uncached = matcher.best_matches(query, "src/a.rs", 5, &lines).unwrap();
+        calls.store(0, Ordering::SeqCst);
+        let with_cache = matcher.best_matches(query, "src/a.rs", 5, &cached).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+        assert_eq!(uncached, with_cache);
+    }
+}
+
+#[test]
+fn ascending_reverses_result_order() {
+    let lines = file_lines(&[
+        "src/matcher.rs",
+        "tests/unit.rs",
+        "src/ffi.rs",
+        "rust/Cargo.toml",
+    ]);
+    let matcher = Matcher::new().unwrap();
+    let ascending = configured_matcher(|c| c.ascending = true);
+    let best_first = matcher.best_matches("rs", "", 3, &lines).unwrap();
+    let mut worst_first = ascending.best_matches("rs", "", 3, &lines).unwrap();
+    assert_eq!(best_first.len(), 3);
+    worst_first.reverse();
+    assert_eq!(best_first, worst_first);
+
+    let mut inc_matcher = ascending.incremental_match("rs", "", 3, &lines).unwrap();
+    let mut incremental = run_to_completion(&mut inc_matcher);
+    incremental.reverse();
+    assert_eq!(best_first, incremental);
+}
+
+#[test]
+fn whitespace_query_is_empty_query() {
+    let lines = file_lines(&["src/matcher.rs", "tests/unit.rs", "src/ffi.rs"]);
+    let mut matcher = Matcher::new().unwrap();
+    matcher.update("src/ffi.rs");
+    let empty = matcher.best_matches("", "src/lib.rs", 3, &lines).unwrap();
+    let spaces = matcher.best_matches("  ", "src/lib.rs", 3, &lines).unwrap();
+    assert_eq!(empty.len(), 3);
+    assert_eq!(empty, spaces);
+    assert_eq!(
+        matcher.best_matches(" ffi ", "", 3, &lines).unwrap(),
+        matcher.best_matches("ffi", "", 3, &lines).unwrap()
+    );
+}
+
+#[test]
+fn score_all_returns_every_line() {
+    let lines = file_lines(&["src/matcher.rs", "README.md", "src/ffi.rs", "lua/sylph.lua"]);
+    let matcher = Matcher::new().unwrap();
+    let all = matcher.score_all("ffi", "", &lines).unwrap();
+    assert_eq!(all.len(), lines.len());
+    assert_eq!(all[0].0.index, 2);
+    assert!(all[0].1);
+    assert!(all[0].0.score > 0.);
+    assert!(all[1..]
+        .iter()
+        .all(|(m, matched)| !matched && m.score == 0.));
+
+    // Matches with negative scores still rank above the lines that don't match
+    let matcher = configured_matcher(|c| c.path_length_penalty = 100.);
+    let all = matcher.score_all("ffi", "", &lines).unwrap();
+    assert_eq!(all[0].0.index, 2);
+    assert!(all[0].1);
+    assert!(all[0].0.score < 0.);
+    assert!(all[1..]
+        .iter()
+        .all(|(m, matched)| !matched && m.score == 0.));
+
+    // And below them when sorting worst first
+    let matcher = configured_matcher(|c| c.ascending = true);
+    let all = matcher.score_all("ffi", "", &lines).unwrap();
+    assert_eq!(all[3].0.index, 2);
+    assert!(all[..3].iter().all(|(_, matched)| !matched));
+}
+
+#[test]
+// Depends on the skim matcher's scoring
+#[cfg(feature = "fuzzy-matcher")]
+fn sticky_margin_keeps_previous_top() {
+    let lines = file_lines(&["lua/match_config.lua", "rust/make_target.rs"]);
+    let mut matcher = configured_matcher(|c| c.sticky_margin = 5.);
+    let top = matcher.best_matches("m", "", 2, &lines).unwrap()[0].clone();
+    assert_eq!(top.index, 1);
+    // Without a previous top the next keystroke swaps the order, but only by a little
+    let swapped = matcher.best_matches("mt", "", 2, &lines).unwrap();
+    assert_eq!(swapped[0].index, 0);
+    assert!(swapped[0].score - swapped[1].score < 5.);
+
+    matcher.set_previous_top(Some(top.stable_key));
+    let sticky = matcher.best_matches("mt", "", 2, &lines).unwrap();
+    assert_eq!(sticky[0].index, 1);
+    // A query the previous top doesn't match still drops it
+    let other = matcher.best_matches("conf", "", 2, &lines).unwrap();
+    assert_eq!(other.len(), 1);
+    assert_eq!(other[0].index, 0);
+}
+
+struct RecentLine {
+    path: String,
+    recency: f64,
+}
+
+impl Line for RecentLine {
+    fn path(&self) -> &str {
+        &self.path
+    }
+    fn line(&self) -> &str {
+        &self.path
+    }
+    fn extra_score(&self) -> f64 {
+        self.recency
+    }
+}
+
+#[test]
+fn extra_score_flips_equal_lines() {
+    let recent = |path: &str, recency| RecentLine {
+        path: path.to_string(),
+        recency,
+    };
+    let matcher = Matcher::new().unwrap();
+    let plain = matcher
+        .best_matches(
+            "rs",
+            "",
+            2,
+            &[recent("src/a.rs", 0.), recent("src/b.rs", 0.)],
+        )
+        .unwrap();
+    assert_eq!(plain[0].score, plain[1].score);
+    assert_eq!(plain[0].index, 1);
+
+    let lines = [recent("src/a.rs", 1.), recent("src/b.rs", 0.)];
+    let boosted = matcher.best_matches("rs", "", 2, &lines).unwrap();
+    assert_eq!(boosted[0].index, 0);
+    assert_eq!(boosted[0].score, plain[0].score + 1.);
+    let weighted = configured_matcher(|c| c.extra_score_weight = 0.)
+        .best_matches("rs", "", 2, &lines)
+        .unwrap();
+    assert_eq!(weighted, plain);
+}
+
+#[test]
+fn rescore_after_update_matches_full_rerun() {
+    let paths = (0..200)
+        .map(|i| format!("src/module{}/file{}.rs", i % 7, i))
+        .collect::<Vec<_>>();
+    let lines = file_lines(&paths.iter().map(|p| p.as_str()).collect::<Vec<_>>());
+    for &directory_weight in &[0., 0.5] {
+        let mut matcher = configured_matcher(|c| c.directory_frequency_weight = directory_weight);
+        for p in paths.iter().step_by(13) {
+            matcher.update(p);
+        }
+        for &(query, num_results) in &[("file1", 5), ("mod", 10), ("", 3), ("file199", 5)] {
+            let mut results = matcher
+                .best_matches(query, "", num_results, &lines)
+                .unwrap();
+            for selected in &[&paths[42], &paths[0], &paths[199]] {
+                matcher
+                    .rescore_after_update(selected, query, "", num_results, &mut results, &lines)
+                    .unwrap();
+                assert_eq!(
+                    results,
+                    matcher
+                        .best_matches(query, "", num_results, &lines)
+                        .unwrap()
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn telescope_score_lower_is_better() {
+    let lines = file_lines(&["src/matcher.rs", "tests/matcher_test.rs", "README.md"]);
+    let matcher = configured_matcher(|c| c.path_length_penalty = 10.);
+    let best = matcher.best_matches("mat", "", 2, &lines).unwrap();
+    // The penalty makes both scores negative
+    assert!(best[0].score < 0.);
+    let first = matcher.telescope_score("mat", "", &lines[best[0].index]);
+    let second = matcher.telescope_score("mat", "", &lines[best[1].index]);
+    assert!(0. < first && first < second);
+    assert_eq!(
+        matcher.telescope_score("mat", "", &lines[2]),
+        TELESCOPE_NO_MATCH
+    );
+    assert_eq!(TELESCOPE_NO_MATCH, -1.);
+
+    // Scores far outside what e^-score could represent keep their order
+    for &penalty in &[1e3, -1e3] {
+        let matcher = configured_matcher(|c| c.path_length_penalty = penalty);
+        let best = matcher.best_matches("mat", "", 2, &lines).unwrap();
+        let first = matcher.telescope_score("mat", "", &lines[best[0].index]);
+        let second = matcher.telescope_score("mat", "", &lines[best[1].index]);
+        assert!(0. < first && first < second && second.is_finite());
+    }
+}
+
+fn match_bits(mtchs: &[Match]) -> Vec<(usize, u64, u64, u64, u64, u64, bool)> {
+    mtchs
+        .iter()
+        .map(|m| {
+            (
+                m.index,
+                m.score.to_bits(),
+                m.context_score.to_bits(),
+                m.query_score.to_bits(),
+                m.frequency_score.to_bits(),
+                m.stable_key,
+                m.matched_basename,
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn repeated_queries_are_bit_identical() {
+    // Many lines with tied scores, spread over few paths so group_by_path has to pick
+    let lines = (0..500)
+        .map(|i| OwnedLine {
+            path: format!("src/module{}/file{}.rs", i % 11, i % 23),
+            line: format!("fn item{}()", i % 3),
+        })
+        .collect::<Vec<_>>();
+    for &group_by_path in &[false, true] {
+        let mut matcher = configured_matcher(|c| {
+            c.group_by_path = group_by_path;
+            c.match_target = MatchTarget::Both;
+        });
+        for i in 0..30 {
+            matcher.update(&lines[i * 7 % lines.len()].path);
+        }
+        // A restored matcher has its own hash seeds
+        let restored = Matcher::from_bytes(&matcher.to_bytes()).unwrap();
+        for &query in &["", "item", "mod", "file1", "fn"] {
+            let first = matcher
+                .best_matches(query, "src/lib.rs", 15, &lines)
+                .unwrap();
+            for m in &[&matcher, &restored] {
+                let again = m.best_matches(query, "src/lib.rs", 15, &lines).unwrap();
+                assert_eq!(match_bits(&first), match_bits(&again));
+                let iter = m
+                    .best_matches_iter(query, "src/lib.rs", 15, lines.iter())
+                    .unwrap();
+                assert_eq!(match_bits(&first), match_bits(&iter));
+            }
+        }
+    }
+}
+
+#[test]
+fn directory_frequency_boosts_siblings() {
+    let lines = file_lines(&["src/a.rs", "src/b.rs", "lib/c.rs"]);
+    let mut matcher = configured_matcher(|c| c.directory_frequency_weight = 0.5);
+    matcher.update("src/a.rs");
+    let frequency = |matcher: &Matcher| {
+        let mut mtchs = matcher.best_matches("", "", 3, &lines).unwrap();
+        mtchs.sort_by_key(|m| m.index);
+        mtchs.iter().map(|m| m.frequency_score).collect::<Vec<_>>()
+    };
+    let scores = frequency(&matcher);
+    assert!(scores[0] > scores[1]);
+    assert!(scores[1] > 0.);
+    assert_eq!(scores[2], 0.);
+
+    // Survives a round trip, and is off by default
+    let restored = Matcher::from_bytes(&matcher.to_bytes()).unwrap();
+    assert_eq!(frequency(&restored), scores);
+    let mut plain = Matcher::new().unwrap();
+    plain.update("src/a.rs");
+    assert_eq!(frequency(&plain)[1], 0.);
+}
+
+#[test]
+fn exact_query_matches_substrings() {
+    let paths = [
+        "src/config.rs",
+        "lua/Config.lua",
+        "src/conf/fig.rs",
+        "tests/matcher_config_test.rs",
+        "README.md",
+    ];
+    let lines = file_lines(&paths);
+    let mut matcher = Matcher::new().unwrap();
+    matcher.update("tests/matcher_config_test.rs");
+    let indices = |query| {
+        matcher
+            .best_matches(query, "", 10, &lines)
+            .unwrap()
+            .iter()
+            .map(|m| m.index)
+            .collect::<Vec<_>>()
+    };
+    // Same as a substring search, and frequency still ranks them
+    let expected = (0..paths.len())
+        .filter(|&i| paths[i].to_lowercase().contains("config"))
+        .collect::<Vec<_>>();
+    assert_eq!(expected, vec![0, 1, 3]);
+    let exact = indices("'config");
+    assert_eq!(exact[0], 3);
+    let mut sorted = exact.clone();
+    sorted.sort();
+    assert_eq!(sorted, expected);
+    // The fuzzy query also matches the split up path, smart case still applies
+    assert!(indices("config").contains(&2));
+    assert_eq!(indices("'Config"), vec![1]);
+}
+
+#[test]
+fn scan_stats_account_for_every_line() {
+    let paths = (0..100)
+        .map(|i| format!("src/module{}/file{}.rs", i % 4, i))
+        .collect::<Vec<_>>();
+    let lines = file_lines(&paths.iter().map(|p| p.as_str()).collect::<Vec<_>>());
+    let matcher = configured_matcher(|c| c.min_score = 20.);
+    let (mtchs, stats) = matcher
+        .best_matches_with_stats("'module1/", "", 100, &lines)
+        .unwrap();
+    assert_eq!(stats.prefiltered_out + stats.scored, lines.len());
+    assert_eq!(
+        stats,
+        ScanStats {
+            scored: 25,
+            prefiltered_out: 75,
+            // Exact matches all score 1
+            below_min_score: 25,
+        }
+    );
+    assert!(mtchs.is_empty());
+
+    let (mtchs, stats) = matcher
+        .best_matches_with_stats("fil1", "", 5, &lines)
+        .unwrap();
+    assert_eq!(stats.prefiltered_out + stats.scored, lines.len());
+    assert_eq!(mtchs, matcher.best_matches("fil1", "", 5, &lines).unwrap());
+    assert!(stats.scored - stats.below_min_score >= mtchs.len());
+}
+
+#[test]
+fn live_query_polls_between_batches() {
+    let paths = (0..300)
+        .map(|i| format!("src/module{}/file{}.rs", i % 7, i))
+        .collect::<Vec<_>>();
+    let lines = file_lines(&paths.iter().map(|p| p.as_str()).collect::<Vec<_>>());
+    let matcher = Matcher::new().unwrap();
+    let mut live = LiveQuery::new(&matcher, "file1", "", 5).unwrap();
+    let poll_until_done = |live: &mut LiveQuery<OwnedLine>| loop {
+        if let Some(results) = live.poll().unwrap() {
+            return results;
+        }
+    };
+    for (i, batch) in lines.chunks(100).enumerate() {
+        live.feed(batch.to_vec());
+        let fed = &lines[..(i + 1) * 100];
+        assert_eq!(
+            poll_until_done(&mut live),
+            matcher.best_matches("file1", "", 5, fed).unwrap()
+        );
+    }
+    assert_eq!(
+        live.finish().unwrap(),
+        matcher.best_matches("file1", "", 5, &lines).unwrap()
+    );
+}
+
+#[test]
+fn basename_only_ignores_directories() {
+    let lines = file_lines(&["a/test.rs", "test/a.rs"]);
+    let indices = |matcher: &Matcher, query| {
+        matcher
+            .best_matches(query, "", 5, &lines)
+            .unwrap()
+            .iter()
+            .map(|m| m.index)
+            .collect::<Vec<_>>()
+    };
+    let matcher = configured_matcher(|c| c.basename_only = true);
+    assert_eq!(indices(&matcher, "test"), vec![0]);
+    assert!(indices(&matcher, "src").is_empty());
+    assert_eq!(indices(&Matcher::new().unwrap(), "test").len(), 2);
+}
+
+#[test]
+fn exclude_globs_drop_paths() {
+    let lines = file_lines(&[
+        "node_modules/react/index.js",
+        "src/index.js",
+        "dist/app.min.js",
+    ]);
+    let mut matcher = Matcher::new().unwrap();
+    matcher
+        .set_exclude_globs(&["node_modules/**", "*.min.js"])
+        .unwrap();
+    for &query in &["index", ""] {
+        let mtchs = matcher.best_matches(query, "", 5, &lines).unwrap();
+        assert_eq!(mtchs.iter().map(|m| m.index).collect::<Vec<_>>(), vec![1]);
+    }
+    assert!(matcher
+        .score("index", "", 0, &lines[0].line, &lines[0].path)
+        .is_none());
+    assert!(matcher.set_exclude_globs(&["src/[a"]).is_err());
+    matcher.set_exclude_globs(&[]).unwrap();
+    assert_eq!(matcher.best_matches("", "", 5, &lines).unwrap().len(), 3);
+}
+
+#[test]
+fn diversify_alternates_paths_with_equal_scores() {
+    let lines = ["a.rs", "a.rs", "a.rs", "b.rs", "b.rs"]
+        .iter()
+        .map(|p| OwnedLine {
+            path: p.to_string(),
+            line: "fn foo()".to_string(),
+        })
+        .collect::<Vec<_>>();
+    let paths = |matcher: &Matcher| {
+        let mtchs = matcher.best_matches("foo", "", 5, &lines).unwrap();
+        assert!(mtchs.iter().all(|m| m.score == mtchs[0].score));
+        mtchs
+            .iter()
+            .map(|m| lines[m.index].path.as_str())
+            .collect::<Vec<_>>()
+    };
+    let matcher = configured_matcher(|c| c.diversify = true);
+    assert_eq!(
+        paths(&matcher),
+        vec!["b.rs", "a.rs", "b.rs", "a.rs", "a.rs"]
+    );
+    assert_eq!(
+        matcher
+            .best_matches_iter("foo", "", 5, lines.iter())
+            .unwrap(),
+        matcher.best_matches("foo", "", 5, &lines).unwrap()
+    );
+    assert_eq!(
+        paths(&Matcher::new().unwrap()),
+        vec!["b.rs", "b.rs", "a.rs", "a.rs", "a.rs"]
+    );
+}
+
+#[test]
+fn diversify_chooses_among_ties_past_the_results() {
+    let mut lines = ["a.rs", "a.rs", "a.rs", "b.rs", "b.rs", "c.rs"]
+        .iter()
+        .map(|p| OwnedLine {
+            path: p.to_string(),
+            line: "fn foo()".to_string(),
+        })
+        .collect::<Vec<_>>();
+    // A better match doesn't push out the ties it leaves room for
+    lines.push(OwnedLine {
+        path: "foo.rs".to_string(),
+        line: "foo".to_string(),
+    });
+    let matcher = configured_matcher(|c| c.diversify = true);
+    let paths = |mtchs: Vec<Match>| {
+        mtchs
+            .iter()
+            .map(|m| lines[m.index].path.as_str())
+            .collect::<Vec<_>>()
+    };
+    // Without diversifying the three tied results would be c.rs, b.rs and b.rs
+    let expected = vec!["foo.rs", "c.rs", "b.rs", "a.rs"];
+    assert_eq!(
+        paths(matcher.best_matches("foo", "", 4, &lines).unwrap()),
+        expected
+    );
+    assert_eq!(
+        paths(
+            matcher
+                .best_matches_iter("foo", "", 4, lines.iter())
+                .unwrap()
+        ),
+        expected
+    );
+    let mut inc_matcher = matcher
+        .incremental_match("foo", "", 4, lines.clone())
+        .unwrap();
+    let mut progress = inc_matcher.process(2).unwrap();
+    while progress == Progress::Working {
+        progress = inc_matcher.process(2).unwrap();
+    }
+    assert_eq!(
+        progress,
+        Progress::Done(matcher.best_matches("foo", "", 4, &lines).unwrap())
+    );
+}
+
+#[test]
+// Depends on the skim matcher's scoring
+#[cfg(feature = "fuzzy-matcher")]
+fn position_penalty_prefers_early_matches() {
+    let lines = file_lines(&["thing_name", "name_thing"]);
+    let matcher = configured_matcher(|c| c.position_penalty = 1.);
+    let mtchs = matcher.best_matches("name", "", 5, &lines).unwrap();
+    assert_eq!(mtchs[0].index, 1);
+    assert!(mtchs[0].query_score > mtchs[1].query_score + 5.);
+}
+
+#[test]
+fn position_penalty_keeps_boosts_of_late_matches() {
+    let lines = file_lines(&["thing_name", "name_thing"]);
+    let mut matcher = configured_matcher(|c| c.position_penalty = 100.);
+    matcher.update("thing_name");
+    let mtchs = matcher.best_matches("name", "", 5, &lines).unwrap();
+    let late = mtchs.iter().find(|m| m.index == 0).unwrap();
+    // Penalized below zero, but still a match
+    assert!(late.query_score < 0.);
+    assert!(late.frequency_score > 0.);
+}
+
+#[test]
+fn initialism_bonus_prefers_word_initials() {
+    let lines = file_lines(&["gofmt", "getFooMeta", "get_foo_meta"]);
+    let matcher = configured_matcher(|c| c.initialism_bonus = 10.);
+    let mtchs = matcher.best_matches("gfm", "", 5, &lines).unwrap();
+    assert_eq!(mtchs.last().unwrap().index, 0);
+    let default = Matcher::new().unwrap();
+    let unboosted = default.best_matches("gfm", "", 5, &lines).unwrap();
+    let query_score =
+        |mtchs: &[Match], index| mtchs.iter().find(|m| m.index == index).unwrap().query_score;
+    assert_eq!(query_score(&mtchs, 1), query_score(&unboosted, 1) + 10.);
+    assert_eq!(query_score(&mtchs, 0), query_score(&unboosted, 0));
+    // Uppercase queries aren't initialisms
+    assert_eq!(
+        query_score(&matcher.best_matches("gFM", "", 5, &lines).unwrap(), 1),
+        query_score(&default.best_matches("gFM", "", 5, &lines).unwrap(), 1)
+    );
+}
+
+#[test]
+fn matched_text_handles_multibyte_chars() {
+    let matcher = Matcher::new().unwrap();
+    assert_eq!(
+        matcher.matched_text("ñb", "año/über.rs"),
+        Some("ñb".to_string())
+    );
+    assert_eq!(
+        matcher.matched_text("mat", "src/größe_MATCHER.rs"),
+        Some("MAT".to_string())
+    );
+    assert_eq!(matcher.matched_text("xyz", "año/über.rs"), None);
+}
+
+#[test]
+fn field_prefixes_route_words() {
+    let lines = vec![
+        OwnedLine {
+            path: "src/auth.rs".to_string(),
+            line: "fn login()".to_string(),
+        },
+        OwnedLine {
+            path: "tests/login.rs".to_string(),
+            line: "fn check()".to_string(),
+        },
+        OwnedLine {
+            path: "src/main.rs".to_string(),
+            line: "fn main()".to_string(),
+        },
+    ];
+    let matcher = Matcher::new().unwrap();
+    let indices = |query| {
+        let mut indices = matcher
+            .best_matches(query, "", 5, &lines)
+            .unwrap()
+            .iter()
+            .map(|m| m.index)
+            .collect::<Vec<_>>();
+        indices.sort_unstable();
+        indices
+    };
+    // Without prefixes the query is matched against `match_target`, the line by default
+    assert_eq!(indices("login"), vec![0]);
+    assert_eq!(indices("path:src login"), vec![0]);
+    // An unprefixed word may match either the path or the line
+    assert_eq!(indices("path:tests login"), vec![1]);
+    assert_eq!(indices("line:check path:login"), vec![1]);
+    assert_eq!(indices("path:src"), vec![0, 2]);
+    assert!(indices("line:src").is_empty());
+}
+
+#[test]
+fn current_best_snapshots_partial_results() {
+    let mut paths = vec![
+        "a/file.rs".to_string(),
+        "b/file.rs".to_string(),
+        "c/file.rs".to_string(),
+    ];
+    paths.extend((0..100).map(|i| format!("f_i_l_e_{}.rs", i)));
+    let lines = paths
+        .iter()
+        .map(|p| OwnedLine {
+            path: p.clone(),
+            line: p.clone(),
+        })
+        .collect::<Vec<_>>();
+    let matcher = Matcher::new().unwrap();
+    let mut inc_matcher = matcher.incremental_match("file", "", 5, &lines).unwrap();
+    assert!(inc_matcher.current_best().is_empty());
+    inc_matcher.process(3).unwrap();
+    let partial = inc_matcher.current_best();
+    assert_eq!(partial.len(), 3);
+    assert!(partial.windows(2).all(|w| w[0].score >= w[1].score));
+    let done = match inc_matcher.process(lines.len()).unwrap() {
+        Progress::Done(mtchs) => mtchs,
+        Progress::Working => panic!("all lines were processed"),
+    };
+    assert_eq!(done.len(), 5);
+    assert_eq!(&done[..3], &partial[..]);
+    assert_eq!(inc_matcher.current_best(), done);
+}
+
+#[test]
+fn max_frequency_contribution_caps_frequency() {
+    let lines = file_lines(&["src/matcher.rs", "src/mount/a_t_c_h_e_r.rs"]);
+    let config = MatcherConfig {
+        frequency_weight: 50.,
+        ..MatcherConfig::default()
+    };
+    let mut matcher = Matcher::with_config(config.clone()).unwrap();
+    for _ in 0..10 {
+        matcher.update("src/mount/a_t_c_h_e_r.rs");
+    }
+    // Frequency lifts the weak match over the strong one
+    let results = matcher.best_matches("matcher", "", 2, &lines).unwrap();
+    assert_eq!(results[0].index, 1);
+
+    matcher.set_config(MatcherConfig {
+        max_frequency_contribution: 5.,
+        ..config
+    });
+    let results = matcher.best_matches("matcher", "", 2, &lines).unwrap();
+    assert_eq!(results[0].index, 0);
+    assert_eq!(results[1].frequency_score, 5.);
+}
+
+#[test]
+fn subsequence_mode_requires_order() {
+    let lines = file_lines(&["axbxc", "AXBXC", "acb", "abcxxxxx"]);
+    let matcher = configured_matcher(|c| c.match_mode = MatchMode::Subsequence);
+    let mtchs = matcher.best_matches("abc", "", 5, &lines).unwrap();
+    let mut indices = mtchs.iter().map(|m| m.index).collect::<Vec<_>>();
+    indices.sort_unstable();
+    assert_eq!(indices, vec![0, 1, 3]);
+    // Shorter texts score higher
+    assert_eq!(mtchs.last().unwrap().index, 3);
+    assert_eq!(mtchs[0].query_score, 0.6);
+    // Uppercase queries are case sensitive
+    let mtchs = matcher.best_matches("ABC", "", 5, &lines).unwrap();
+    assert_eq!(mtchs.iter().map(|m| m.index).collect::<Vec<_>>(), vec![1]);
+}
+
+#[test]
+fn position_ranges_coalesce_runs() {
+    assert_eq!(position_ranges(&[0, 1, 2, 5, 6]), vec![(0, 3), (5, 7)]);
+    assert!(position_ranges(&[]).is_empty());
+    let matcher = Matcher::new().unwrap();
+    assert_eq!(
+        matcher.matched_ranges("mat", "src/matcher.rs"),
+        Some(vec![(4, 7)])
+    );
+    assert_eq!(matcher.matched_ranges("xyz", "src/matcher.rs"), None);
+}
+
+#[test]
+fn matched_ranges_follow_query_prefixes() {
+    let matcher = Matcher::new().unwrap();
+    assert_eq!(
+        matcher.matched_ranges("^mat", "src/matcher.rs"),
+        Some(vec![(4, 7)])
+    );
+    // The characters come from the component the query is anchored to
+    assert_eq!(
+        matcher.matched_ranges("^at", "data/attic.rs"),
+        Some(vec![(5, 7)])
+    );
+    assert_eq!(matcher.matched_ranges("^at", "src/matcher.rs"), None);
+
+    assert_eq!(
+        matcher.matched_ranges("'tch", "src/matcher.rs"),
+        Some(vec![(6, 9)])
+    );
+    // The first occurrence of the substring, not the best fuzzy placement
+    assert_eq!(
+        matcher.matched_ranges("'ser", "src/user_service.rs"),
+        Some(vec![(5, 8)])
+    );
+    assert_eq!(matcher.matched_ranges("'MAT", "src/matcher.rs"), None);
+    assert_eq!(matcher.matched_ranges("'mta", "src/matcher.rs"), None);
+}
+
+#[test]
+// Depends on the skim matcher's scoring
+#[cfg(feature = "fuzzy-matcher")]
+fn component_alignment_bonus_prefers_single_component_matches() {
+    let lines = file_lines(&["src/main/test.rs", "src/matcher.rs"]);
+    let query_scores = |matcher: &Matcher| {
+        let mut mtchs = matcher.best_matches("mat", "", 2, &lines).unwrap();
+        mtchs.sort_by_key(|m| m.index);
+        mtchs.iter().map(|m| m.query_score).collect::<Vec<_>>()
+    };
+    let unaligned = query_scores(&Matcher::new().unwrap());
+    let matcher = configured_matcher(|c| c.component_alignment_bonus = 5.);
+    let aligned = query_scores(&matcher);
+    assert_eq!(aligned[0], unaligned[0]);
+    assert_eq!(aligned[1], unaligned[1] + 5.);
+    assert_eq!(
+        matcher.best_matches("mat", "", 2, &lines).unwrap()[0].index,
+        1
+    );
+}
+
+#[test]
+fn result_cache_skips_rescoring() {
+    let lines = file_lines(&["src/main.rs", "src/matcher.rs", "src/ffi.rs"]);
+    let scored = Arc::new(AtomicUsize::new(0));
+    let mut matcher = configured_matcher(|c| c.result_cache_size = 4);
+    {
+        let scored = scored.clone();
+        matcher.set_preprocess(Some(Box::new(move |line: &str| {
+            scored.fetch_add(1, Ordering::SeqCst);
+            Cow::Borrowed(line)
+        })));
+    }
+    let first = matcher.best_matches("ma", "", 5, &lines).unwrap();
+    assert_eq!(scored.load(Ordering::SeqCst), 3);
+    assert_eq!(matcher.best_matches("ma", "", 5, &lines).unwrap(), first);
+    assert_eq!(scored.load(Ordering::SeqCst), 3);
+
+    // Different lines miss
+    matcher.best_matches("ma", "", 5, &lines[..2]).unwrap();
+    assert_eq!(scored.load(Ordering::SeqCst), 5);
+    // Selecting a path changes frequency scores
+    matcher.update("src/main.rs");
+    let updated = matcher.best_matches("ma", "", 5, &lines).unwrap();
+    assert_eq!(scored.load(Ordering::SeqCst), 8);
+    assert!(updated[0].frequency_score > 0.);
+}
+
+#[test]
+fn max_atoms_rejects_long_split_queries() {
+    let lines = file_lines(&["src/a0/a1.rs"]);
+    let matcher = configured_matcher(|c| c.max_atoms = 8);
+    let words = (0..50).map(|i| format!("a{}", i)).collect::<Vec<_>>();
+    let fielded = format!("path:{}", words.join(" "));
+    assert!(matcher.best_matches(&fielded, "", 5, &lines).is_err());
+    assert!(matcher.best_matches("path:a0 a1", "", 5, &lines).is_ok());
+    // Without prefixes the query is matched whole, so its length doesn't matter
+    assert!(matcher
+        .best_matches(&words.join(" "), "", 5, &lines)
+        .is_ok());
+
+    let matcher = configured_matcher(|c| {
+        c.max_atoms = 8;
+        c.match_mode = MatchMode::Components;
+    });
+    assert!(matcher
+        .best_matches(&words.join("/"), "", 5, &lines)
+        .is_err());
+    assert_eq!(
+        matcher.best_matches("a0/a1", "", 5, &lines).unwrap().len(),
+        1
+    );
+}
+
+#[test]
+fn diff_classifies_results() {
+    let results = |indices: &[usize]| {
+        indices
+            .iter()
+            .map(|&index| Match {
+                index,
+                score: 0.,
+                context_score: 0.,
+                query_score: 0.,
+                raw_query_score: 0.,
+                frequency_score: 0.,
+                open_score: 0.,
+                stable_key: 0,
+                matched_basename: false,
+            })
+            .collect::<Vec<_>>()
+    };
+    let diff = Match::diff(&results(&[1, 2, 3, 4]), &results(&[2, 1, 3, 5]));
+    assert_eq!(
+        diff,
+        ResultDiff {
+            added: vec![5],
+            removed: vec![4],
+            moved: vec![2, 1],
+        }
+    );
+    assert_eq!(
+        Match::diff(&results(&[1, 2]), &results(&[1, 2])),
+        ResultDiff::default()
+    );
+}
+
+#[test]
+fn fold_diacritics_matches_base_letters() {
+    let lines = file_lines(&["café.txt", "cafe\u{301}s.txt"]);
+    let unfolded = Matcher::new().unwrap();
+    // Only the decomposed spelling has a plain "e" to match
+    let unfolded_results = unfolded.best_matches("cafe", "", 5, &lines).unwrap();
+    assert_eq!(unfolded_results.len(), 1);
+    assert_eq!(unfolded_results[0].index, 1);
+    assert_eq!(unfolded.matched_ranges("cafe", "café.txt"), None);
+
+    let matcher = configured_matcher(|c| c.fold_diacritics = true);
+    assert_eq!(
+        matcher.best_matches("cafe", "", 5, &lines).unwrap().len(),
+        2
+    );
+    assert_eq!(
+        matcher.best_matches("café", "", 5, &lines).unwrap().len(),
+        2
+    );
+    // Positions point into the original text
+    assert_eq!(
+        matcher.matched_ranges("cafe", "my café.txt"),
+        Some(vec![(3, 7)])
+    );
+    assert_eq!(
+        matcher.matched_text("cafet", "my café.txt"),
+        Some("caf\u{e9}t".to_string())
+    );
+    assert_eq!(
+        matcher.matched_ranges("afes", "cafe\u{301}s"),
+        Some(vec![(1, 4), (5, 6)])
+    );
+}
+
+#[test]
+fn custom_scorer_replaces_scoring() {
+    let lines = file_lines(&[
+        "src/deeply/nested/matcher.rs",
+        "src/matcher.rs",
+        "matcher.rs",
+        "src/main.rs",
+    ]);
+    let mut matcher = Matcher::new().unwrap();
+    matcher.set_scorer(Some(Box::new(|inputs: &ScoreInputs| {
+        if !inputs.path.contains(inputs.query) {
+            return None;
+        }
+        let score = -(inputs.path.len() as f64);
+        Some(Match {
+            index: inputs.index,
+            score,
+            context_score: 0.,
+            query_score: score,
+            raw_query_score: score,
+            frequency_score: inputs.frequency_score,
+            open_score: 0.,
+            stable_key: 0,
+            matched_basename: false,
+        })
+    })));
+    let indices = |results: Vec<Match>| results.iter().map(|m| m.index).collect::<Vec<_>>();
+    assert_eq!(
+        indices(matcher.best_matches("matcher", "", 5, &lines).unwrap()),
+        vec![2, 1, 0]
+    );
+    assert_eq!(
+        indices(matcher.best_matches("", "", 5, &lines).unwrap()),
+        vec![2, 3, 1, 0]
+    );
+
+    matcher.set_scorer(None);
+    assert_eq!(
+        matcher.best_matches("mtchr", "", 5, &lines).unwrap().len(),
+        3
+    );
+}
+
+#[test]
+fn replace_control_chars_before_scoring() {
+    let lines = vec![
+        OwnedLine {
+            path: "src/main.rs".to_string(),
+            line: "fn main".to_string(),
+        },
+        OwnedLine {
+            path: "src/matcher.rs".to_string(),
+            line: "raw\0bytes\tstruct Matcher".to_string(),
+        },
+    ];
+    let raw = Matcher::new().unwrap();
+    assert!(raw
+        .best_matches("bytes struct", "", 5, &lines)
+        .unwrap()
+        .is_empty());
+
+    let matcher = configured_matcher(|c| c.replace_control_chars = true);
+    let results = matcher.best_matches("bytes struct", "", 5, &lines).unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].index, 1);
+    assert!(matcher.best_matches("raw bytes", "", 5, &lines).unwrap()[0].score > 0.);
+}
+
+#[test]
+fn incremental_progress_is_sent_per_chunk() {
+    let lines = (0..1000)
+        .map(|i| OwnedLine {
+            path: format!("src/file{}.rs", i),
+            line: format!("src/file{}.rs", i),
+        })
+        .collect::<Vec<_>>();
+    let matcher = Matcher::new().unwrap();
+    let mut inc_matcher = matcher.incremental_match("file", "", 10, &lines).unwrap();
+    let (progress_send, progress_recv) = crossbeam_channel::unbounded();
+    inc_matcher.set_progress_sender(Some(progress_send));
+    while inc_matcher.process(300).unwrap() == Progress::Working {}
+    // A finished matcher doesn't report again
+    inc_matcher.process(300).unwrap();
+    drop(inc_matcher);
+
+    let updates = progress_recv.iter().collect::<Vec<_>>();
+    assert_eq!(
+        updates,
+        vec![(300, 1000), (600, 1000), (900, 1000), (1000, 1000)]
+    );
+}
+
+#[test]
+fn exact_case_bonus_breaks_case_ties() {
+    let lines = file_lines(&["Makefile", "makefile"]);
+    let matcher = Matcher::new().unwrap();
+    let results = matcher.best_matches("makefile", "", 2, &lines).unwrap();
+    assert_eq!(results[0].query_score, results[1].query_score);
+
+    let matcher = configured_matcher(|c| c.exact_case_bonus = 1.);
+    let results = matcher.best_matches("makefile", "", 2, &lines).unwrap();
+    assert_eq!(results[0].index, 1);
+    assert_eq!(results[0].query_score, results[1].query_score + 1.);
+    // An uppercase query only matches the same case, as before
+    let results = matcher.best_matches("Makefile", "", 2, &lines).unwrap();
+    assert_eq!(results[0].index, 0);
+}
+
+#[test]
+fn calibrated_cost_estimate_scales_with_lines() {
+    let mut matcher = Matcher::new().unwrap();
+    assert!(matcher.estimate_cost(4, 1000, 30) > Duration::from_secs(0));
+    matcher.calibrate();
+    assert!(matcher.scoring_throughput() > 0.);
+    let small = matcher.estimate_cost(4, 1000, 30);
+    let large = matcher.estimate_cost(4, 10_000, 30);
+    assert!(small > Duration::from_secs(0));
+    let ratio = large.as_secs_f64() / small.as_secs_f64();
+    assert!((ratio - 10.).abs() < 1e-6, "ratio {}", ratio);
+}
+
+#[test]
+fn best_matches_and_requires_every_query() {
+    let lines = vec![
+        OwnedLine {
+            path: "src/matcher.rs".to_string(),
+            line: "fn best_matches".to_string(),
+        },
+        OwnedLine {
+            path: "src/ffi.rs".to_string(),
+            line: "fn best_matches".to_string(),
+        },
+        OwnedLine {
+            path: "src/matcher.rs".to_string(),
+            line: "fn score".to_string(),
+        },
+    ];
+    let matcher = Matcher::new().unwrap();
+    let queries = vec![
+        (MatchTarget::Path, "matcher".to_string()),
+        (MatchTarget::Line, "best".to_string()),
+    ];
+    let results = matcher.best_matches_and(&queries, 5, &lines).unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].index, 0);
+    let separate = matcher.best_matches("matcher", "", 1, &file_lines(&["src/matcher.rs"]));
+    let line_only = matcher.best_matches("best", "", 1, &lines).unwrap();
+    assert_eq!(
+        results[0].query_score,
+        separate.unwrap()[0].query_score + line_only[0].query_score
+    );
+
+    // An empty query doesn't constrain anything
+    let queries = vec![
+        (MatchTarget::Path, "".to_string()),
+        (MatchTarget::Line, "best".to_string()),
+    ];
+    assert_eq!(
+        matcher.best_matches_and(&queries, 5, &lines).unwrap().len(),
+        2
+    );
+}
+
+#[test]
+fn score_quantum_equalizes_rounding_differences() {
+    let recent = |path: &str, recency| RecentLine {
+        path: path.to_string(),
+        recency,
+    };
+    // Recencies off by accumulated rounding error, as if computed by different code paths
+    let lines = [recent("src/a.rs", 0.6), recent("src/b.rs", 0.6 + 1e-12)];
+    let raw = Matcher::new()
+        .unwrap()
+        .best_matches("rs", "", 2, &lines)
+        .unwrap();
+    assert_ne!(raw[0].score, raw[1].score);
+
+    let matcher = configured_matcher(|c| c.score_quantum = Some(1e-9));
+    let quantized = matcher.best_matches("rs", "", 2, &lines).unwrap();
+    assert_eq!(quantized[0].score, quantized[1].score);
+    let mut inc_matcher = matcher.incremental_match("rs", "", 2, &lines).unwrap();
+    while inc_matcher.process(1).unwrap() == Progress::Working {}
+    assert_eq!(inc_matcher.done_results().unwrap(), &quantized[..]);
+}
+
+#[test]
+fn skipping_hopeless_lines_keeps_top_results() {
+    let paths = (0..2000)
+        .map(|i| format!("src/module{}/sub{}/file{}.rs", i % 17, i % 5, i))
+        .collect::<Vec<_>>();
+    let lines = file_lines(&paths.iter().map(|p| p.as_str()).collect::<Vec<_>>());
+    let open = paths
+        .iter()
+        .step_by(100)
+        .cloned()
+        .collect::<std::collections::HashSet<_>>();
+    for &open_boost in &[5., 1000.] {
+        let mut matcher = configured_matcher(|c| c.open_boost = open_boost);
+        matcher.set_open_set(open.clone());
+        matcher.update(&paths[7]);
+        let scored = Arc::new(AtomicUsize::new(0));
+        {
+            let scored = scored.clone();
+            matcher.set_preprocess(Some(Box::new(move |line: &str| {
+                scored.fetch_add(1, Ordering::SeqCst);
+                Cow::Borrowed(line)
+            })));
+        }
+        for (query, context) in &[("e", ""), ("fle", ""), ("e", "src/module3/sub1/file1.rs")] {
+            scored.store(0, Ordering::SeqCst);
+            let fast = matcher.best_matches(query, context, 10, &lines).unwrap();
+            let skipped = lines.len() - scored.load(Ordering::SeqCst);
+            // Scores every line
+            let (full, _) = matcher
+                .best_matches_with_stats(query, context, 10, &lines)
+                .unwrap();
+            assert_eq!(fast, full);
+            // Open files outscore any fuzzy match by far, so most lines are skipped
+            assert_eq!(skipped > lines.len() / 2, open_boost > 100.);
+        }
+    }
+}
+
+#[test]
+fn query_weight_scales_raw_query_score() {
+    let lines = file_lines(&["src/matcher.rs", "src/main.rs", "tests/unit.rs"]);
+    let plain = Matcher::new()
+        .unwrap()
+        .best_matches("ma", "", 3, &lines)
+        .unwrap();
+    assert!(plain.iter().all(|m| m.query_score == m.raw_query_score));
+
+    let matcher = configured_matcher(|c| c.query_weight = 2.5);
+    let weighted = matcher.best_matches("ma", "", 3, &lines).unwrap();
+    for (w, p) in weighted.iter().zip(&plain) {
+        assert_eq!(w.raw_query_score, p.raw_query_score);
+        assert_eq!(w.query_score, w.raw_query_score * 2.5);
+    }
+}
+
+#[test]
+fn feed_replace_only_rescores_changed_suffix() {
+    let paths = (0..1000)
+        .map(|i| format!("src/file{}.rs", i))
+        .collect::<Vec<_>>();
+    let lines = file_lines(&paths.iter().map(|p| p.as_str()).collect::<Vec<_>>());
+    let mut changed = lines.clone();
+    for line in &mut changed[990..] {
+        line.path = line.path.replace("src/", "other/");
+        line.line = line.path.clone();
+    }
+
+    let scored = Arc::new(AtomicUsize::new(0));
+    let mut matcher = Matcher::new().unwrap();
+    {
+        let scored = scored.clone();
+        matcher.set_preprocess(Some(Box::new(move |line: &str| {
+            scored.fetch_add(1, Ordering::SeqCst);
+            Cow::Borrowed(line)
+        })));
+    }
+    let mut inc_matcher = matcher.incremental_match("fle1", "", 5, lines).unwrap();
+    while inc_matcher.process(100).unwrap() == Progress::Working {}
+    assert_eq!(scored.load(Ordering::SeqCst), 1000);
+
+    scored.store(0, Ordering::SeqCst);
+    assert_eq!(inc_matcher.feed_replace(changed.clone()), 0);
+    assert_eq!(inc_matcher.stats(), (990, 1000));
+    let results = match inc_matcher.process(100).unwrap() {
+        Progress::Done(results) => results,
+        Progress::Working => panic!("only the changed lines should be left"),
+    };
+    assert_eq!(scored.load(Ordering::SeqCst), 10);
+    assert_eq!(
+        results,
+        matcher.best_matches("fle1", "", 5, &changed).unwrap()
+    );
+
+    // Changing a line that is a result means scoring everything again
+    let mut moved = changed.clone();
+    let top = results[0].index;
+    moved[top].line = "unrelated".to_string();
+    moved[top].path = "unrelated".to_string();
+    inc_matcher.feed_replace(moved.clone());
+    assert_eq!(inc_matcher.stats(), (0, 1000));
+    while inc_matcher.process(100).unwrap() == Progress::Working {}
+    assert_eq!(
+        inc_matcher.done_results().unwrap(),
+        &matcher.best_matches("fle1", "", 5, &moved).unwrap()[..]
+    );
+}
+
+#[test]
+fn trim_candidates_ignores_padding() {
+    let padded = vec![OwnedLine {
+        path: "src/matcher.rs".to_string(),
+        line: "\t\tfn score  ".to_string(),
+    }];
+    let bare = vec![OwnedLine {
+        path: "src/matcher.rs".to_string(),
+        line: "fn score".to_string(),
+    }];
+    let matcher = configured_matcher(|c| c.trim_candidates = true);
+    let trimmed = matcher.best_matches("fn", "", 1, &padded).unwrap();
+    assert_eq!(
+        trimmed[0].query_score,
+        matcher.best_matches("fn", "", 1, &bare).unwrap()[0].query_score
+    );
+    let untrimmed = Matcher::new()
+        .unwrap()
+        .best_matches("fn", "", 1, &padded)
+        .unwrap();
+    assert_ne!(untrimmed[0].query_score, trimmed[0].query_score);
+
+    assert_eq!(
+        matcher.matched_ranges("score", &padded[0].line),
+        Some(vec![(5, 10)])
+    );
+    assert_eq!(
+        matcher.matched_text("fs", &padded[0].line),
+        Some("fs".to_string())
+    );
+}
+
+#[test]
+fn frequent_paths_most_recent_first() {
+    let mut matcher = Matcher::new().unwrap();
+    assert!(matcher.frequent_paths().is_empty());
+    for path in &["src/a.rs", "src/b.rs", "src/c.rs", "src/a.rs"] {
+        matcher.update(path);
+    }
+    let paths = matcher.frequent_paths();
+    let names = paths.iter().map(|(p, _)| p.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, vec!["src/a.rs", "src/c.rs", "src/b.rs"]);
+    assert_eq!(paths[0].1, 1.);
+    assert!(paths[0].1 > paths[1].1 && paths[1].1 > paths[2].1 && paths[2].1 > 0.);
+    // The scores are what matching uses, before weighting
+    let lines = file_lines(&["src/c.rs"]);
+    let weight = MatcherConfig::default().frequency_weight;
+    assert_eq!(
+        matcher.best_matches("c", "", 1, &lines).unwrap()[0].frequency_score,
+        paths[1].1 * weight
+    );
+}
+
+#[test]
+fn caret_anchors_to_component_starts() {
+    let lines = file_lines(&["src/matcher.rs", "src/main.rs", "src/format.rs"]);
+    let matcher = Matcher::new().unwrap();
+    let indices = |query: &str| {
+        let mut indices = matcher
+            .best_matches(query, "", 5, &lines)
+            .unwrap()
+            .iter()
+            .map(|m| m.index)
+            .collect::<Vec<_>>();
+        indices.sort_unstable();
+        indices
+    };
+    assert_eq!(indices("^m"), vec![0, 1]);
+    // Without the anchor the m in the middle of format matches too
+    assert_eq!(indices("m"), vec![0, 1, 2]);
+    assert_eq!(indices("^mat"), vec![0]);
+    assert_eq!(indices("^at"), Vec::<usize>::new());
+    assert_eq!(indices("^s"), vec![0, 1, 2]);
+    assert_eq!(indices("^fmt"), vec![2]);
+    assert_eq!(indices("^M"), Vec::<usize>::new());
+
+    let results = matcher.best_matches("^mat", "", 1, &lines).unwrap();
+    assert!(results[0].matched_basename);
+}
+
+#[test]
+fn match_serializes_and_hashes() {
+    let lines = file_lines(&["src/matcher.rs", "src/main.rs", "tests/unit.rs"]);
+    let matcher = Matcher::new().unwrap();
+    let results = matcher.best_matches("ma", "", 3, &lines).unwrap();
+
+    let json = serde_json::to_value(&results[0]).unwrap();
+    assert!(json["score"].is_f64());
+    assert!(json["index"].is_u64());
+    let round_trip: Vec<Match> =
+        serde_json::from_str(&serde_json::to_string(&results).unwrap()).unwrap();
+    assert_eq!(round_trip, results);
+
+    let mut set = std::collections::HashSet::new();
+    set.extend(results.iter().cloned());
+    // The same results again, as from a repeated query, don't add anything
+    set.extend(matcher.best_matches("ma", "", 3, &lines).unwrap());
+    assert_eq!(set.len(), results.len());
+    assert!(results.iter().all(|m| set.contains(m)));
+}